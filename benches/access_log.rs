@@ -0,0 +1,137 @@
+//! Allocations and time the access log adds to a request.
+//!
+//! `cargo bench --bench access_log` serves the same request through an app
+//! without the `Logger`, then with it for a few formats, and prints the
+//! allocations and time per request of each, the baseline included.
+//! `tests/access_log_allocations.rs` checks that the combined format adds
+//! none.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::hint::black_box;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+use actix_web::{test, web, App, HttpResponse};
+use log::{LevelFilter, Log, Metadata, Record};
+
+use rs_starter::middlewares::access_filter::Logger;
+
+const REQUESTS: usize = 20_000;
+
+const COMBINED: &str = r#"%a "%r" %s %b "%{Referer}i" "%{User-Agent}i" %T"#;
+const DETAILED: &str =
+    r#"%t %a %{r}a "%r" %s %b %I %D %R %L "%{User-Agent}i" %{Content-Type}o %{tenant}xi"#;
+
+/// Counts the allocations of the process.
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+/// Formats every record like an appender would, without keeping it.
+struct Sink;
+
+impl Log for Sink {
+    fn enabled(&self, _: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        write!(io::sink(), "{}", record.args()).unwrap();
+    }
+
+    fn flush(&self) {}
+}
+
+static SINK: Sink = Sink;
+
+fn main() {
+    log::set_logger(&SINK).unwrap();
+    log::set_max_level(LevelFilter::Info);
+
+    actix_web::rt::System::new().block_on(async {
+        println!("{:<20} {:>12} {:>12}", "", "allocs/req", "ns/req");
+        run("no logger", None).await;
+        run("combined", Some(Logger::new(COMBINED))).await;
+        run("detailed", Some(detailed())).await;
+        run("combined json", Some(Logger::new(COMBINED).json(true))).await;
+        run("detailed json", Some(detailed().json(true))).await;
+    });
+}
+
+fn detailed() -> Logger {
+    Logger::new(DETAILED).custom_request_replace("tenant", |req| {
+        req.headers()
+            .get("x-tenant-id")
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or("-")
+            .to_owned()
+    })
+}
+
+async fn run(name: &str, logger: Option<Logger>) {
+    let app = App::new().route(
+        "/user/{id}",
+        web::get().to(|| async { HttpResponse::Ok().body("hello") }),
+    );
+
+    macro_rules! measure {
+        ($app:expr) => {{
+            let app = test::init_service($app).await;
+            let serve = || async {
+                let res = test::call_service(&app, request().to_request()).await;
+                black_box(test::read_body(res).await);
+            };
+            for _ in 0..REQUESTS / 10 {
+                serve().await;
+            }
+
+            let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+            let start = Instant::now();
+            for _ in 0..REQUESTS {
+                serve().await;
+            }
+            let elapsed = start.elapsed();
+            let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+
+            println!(
+                "{:<20} {:>12.1} {:>12.0}",
+                name,
+                allocations as f64 / REQUESTS as f64,
+                elapsed.as_nanos() as f64 / REQUESTS as f64
+            );
+        }};
+    }
+
+    match logger {
+        Some(logger) => measure!(app.wrap(logger)),
+        None => measure!(app),
+    }
+}
+
+fn request() -> test::TestRequest {
+    test::TestRequest::get()
+        .uri("/user/42?verbose=1")
+        .insert_header(("user-agent", "bench/1.0"))
+        .insert_header(("referer", "http://localhost/"))
+        .insert_header(("x-tenant-id", "acme"))
+}
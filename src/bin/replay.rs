@@ -0,0 +1,391 @@
+//! Replays the traffic recorded by `middlewares::recorder` against a server
+//! and compares the latencies and statuses with the recorded ones.
+
+use std::collections::HashMap;
+use std::fs;
+use std::process;
+use std::time::{Duration, Instant};
+
+use actix_web::http::Method;
+use awc::{Client, Connector};
+use futures::stream::{self, StreamExt};
+use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
+
+use rs_starter::middlewares::recorder::Recording;
+
+const USAGE: &str = "Usage: replay <RECORDING> [OPTIONS]\n\
+     \n\
+     Replays a traffic recording (recorder.path, one JSON request per line).\n\
+     \n\
+     Options:\n\
+     \x20 -t, --target <URL>        server to replay against (default: http://127.0.0.1:8001)\n\
+     \x20 -s, --speed <X>           1 for the recorded pace, 2 for twice as fast, 0 for as fast as possible (default: 1)\n\
+     \x20 -c, --concurrency <N>     most requests in flight (default: 64)\n\
+     \x20     --timeout <SECONDS>   timeout of each request (default: 30)\n\
+     \x20 -k, --insecure            don't verify the certificate of an https target\n\
+     \x20 -h, --help                print this help\n\
+     \n\
+     Exits with 1 if a request failed or its status differs from the recorded one.";
+
+/// Divergences listed in the report, the most frequent first.
+const MAX_DIVERGENCES: usize = 20;
+
+struct Args {
+    recording: String,
+    target: String,
+    speed: f64,
+    concurrency: usize,
+    timeout: Duration,
+    insecure: bool,
+}
+
+impl Args {
+    fn parse<A: IntoIterator<Item = String>>(args: A) -> Result<Args, String> {
+        let mut recording = None;
+        let mut parsed = Args {
+            recording: String::new(),
+            target: String::from("http://127.0.0.1:8001"),
+            speed: 1.0,
+            concurrency: 64,
+            timeout: Duration::from_secs(30),
+            insecure: false,
+        };
+        let mut iter = args.into_iter();
+
+        while let Some(arg) = iter.next() {
+            let (flag, inline) = match arg.split_once('=') {
+                Some((flag, val)) if flag.starts_with("--") => {
+                    (flag.to_string(), Some(val.to_string()))
+                }
+                _ => (arg.clone(), None),
+            };
+            let mut value = |name: &str| {
+                inline
+                    .clone()
+                    .or_else(|| iter.next())
+                    .ok_or_else(|| format!("{} requires a value", name))
+            };
+            let number = |name: &str, value: String| {
+                value
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|n| *n >= 0.0)
+                    .ok_or_else(|| format!("{} expects a positive number, got {}", name, value))
+            };
+
+            match flag.as_str() {
+                "-t" | "--target" => {
+                    parsed.target = value(&flag)?.trim_end_matches('/').to_string()
+                }
+                "-s" | "--speed" => parsed.speed = number(&flag, value(&flag)?)?,
+                "-c" | "--concurrency" => {
+                    parsed.concurrency = number(&flag, value(&flag)?)?.max(1.0) as usize
+                }
+                "--timeout" => {
+                    parsed.timeout = Duration::from_secs_f64(number(&flag, value(&flag)?)?)
+                }
+                "-k" | "--insecure" => parsed.insecure = true,
+                "-h" | "--help" => {
+                    println!("{}", USAGE);
+                    process::exit(0);
+                }
+                _ if !arg.starts_with('-') && recording.is_none() => recording = Some(arg),
+                _ => return Err(format!("unknown argument {}", arg)),
+            }
+        }
+
+        parsed.recording = recording.ok_or("the recording file is required")?;
+        Ok(parsed)
+    }
+}
+
+/// The replay of a recorded request.
+struct Outcome {
+    recording: Recording,
+    result: Result<u16, String>,
+    latency: Duration,
+}
+
+#[actix_web::main]
+async fn main() {
+    let args = match Args::parse(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("🔥 {}\n\n{}", err, USAGE);
+            process::exit(2);
+        }
+    };
+
+    let recordings = match read(&args.recording) {
+        Ok(recordings) if !recordings.is_empty() => recordings,
+        Ok(_) => {
+            eprintln!("🔥 {} has no request", args.recording);
+            process::exit(2);
+        }
+        Err(err) => {
+            eprintln!("🔥 Couldn't read {}: {}", args.recording, err);
+            process::exit(2);
+        }
+    };
+
+    let client = client(&args);
+    let first = recordings[0].ts;
+    let start = Instant::now();
+
+    // waits for the time of each request in turn, the recordings being sorted,
+    // so that only the requests sent take a slot of the concurrency
+    let outcomes: Vec<Outcome> = stream::iter(recordings)
+        .then(|recording| async move {
+            if args.speed > 0.0 {
+                let due =
+                    Duration::from_millis(recording.ts.saturating_sub(first)).div_f64(args.speed);
+                actix_web::rt::time::sleep(due.saturating_sub(start.elapsed())).await;
+            }
+            recording
+        })
+        .map(|recording| replay(&client, &args.target, recording))
+        .buffer_unordered(args.concurrency)
+        .collect()
+        .await;
+
+    let failed = report(&args, &outcomes, start.elapsed());
+    if failed {
+        process::exit(1);
+    }
+}
+
+/// The recordings, in the order they were received.
+fn read(path: &str) -> Result<Vec<Recording>, String> {
+    let content = fs::read_to_string(path).map_err(|err| err.to_string())?;
+    let mut recordings = content
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            serde_json::from_str::<Recording>(line)
+                .map_err(|err| format!("line {}: {}", i + 1, err))
+        })
+        .collect::<Result<Vec<Recording>, String>>()?;
+    recordings.sort_by_key(|recording| recording.ts);
+    Ok(recordings)
+}
+
+fn client(args: &Args) -> Client {
+    let mut ssl = SslConnector::builder(SslMethod::tls()).unwrap();
+    if args.insecure {
+        ssl.set_verify(SslVerifyMode::NONE);
+    }
+
+    Client::builder()
+        .connector(
+            Connector::new()
+                .openssl(ssl.build())
+                .limit(args.concurrency),
+        )
+        .timeout(args.timeout)
+        .disable_redirects()
+        .finish()
+}
+
+async fn replay(client: &Client, target: &str, recording: Recording) -> Outcome {
+    let start = Instant::now();
+    let result = send(client, target, &recording).await;
+    Outcome {
+        recording,
+        result,
+        latency: start.elapsed(),
+    }
+}
+
+/// The status of the response, once its body is read.
+async fn send(client: &Client, target: &str, recording: &Recording) -> Result<u16, String> {
+    let method = Method::from_bytes(recording.method.as_bytes()).map_err(|err| err.to_string())?;
+    let body = recording.body_bytes().map_err(|err| err.to_string())?;
+
+    let mut request = client.request(method, format!("{}{}", target, recording.uri));
+    for (name, value) in &recording.headers {
+        request = request.append_header((name.as_str(), value.as_str()));
+    }
+
+    let mut response = request
+        .send_body(body)
+        .await
+        .map_err(|err| err.to_string())?;
+    response
+        .body()
+        .limit(usize::MAX)
+        .await
+        .map_err(|err| err.to_string())?;
+    Ok(response.status().as_u16())
+}
+
+/// Prints the summary, returns whether any request failed or diverged.
+fn report(args: &Args, outcomes: &[Outcome], elapsed: Duration) -> bool {
+    let total = outcomes.len();
+    println!(
+        "Replayed {} requests against {} in {:.2}s ({:.1} req/s)",
+        total,
+        args.target,
+        elapsed.as_secs_f64(),
+        total as f64 / elapsed.as_secs_f64().max(f64::EPSILON)
+    );
+
+    let mut replayed: Vec<f64> = outcomes
+        .iter()
+        .filter(|outcome| outcome.result.is_ok())
+        .map(|outcome| outcome.latency.as_secs_f64() * 1000.0)
+        .collect();
+    let mut recorded: Vec<f64> = outcomes
+        .iter()
+        .map(|outcome| outcome.recording.duration_ms)
+        .collect();
+    println!("\nLatency (ms)      p50       p90       p99       max");
+    println!("  replayed {}", percentiles(&mut replayed));
+    println!("  recorded {}", percentiles(&mut recorded));
+
+    let truncated = outcomes
+        .iter()
+        .filter(|outcome| outcome.recording.truncated)
+        .count();
+    if truncated > 0 {
+        println!(
+            "\n{} requests were replayed with a body truncated by the recorder",
+            truncated
+        );
+    }
+
+    let errors: Vec<&Outcome> = outcomes
+        .iter()
+        .filter(|outcome| outcome.result.is_err())
+        .collect();
+    if !errors.is_empty() {
+        println!("\nFailed: {}", errors.len());
+        for outcome in errors.iter().take(MAX_DIVERGENCES) {
+            if let Err(err) = &outcome.result {
+                println!(
+                    "  {} {}: {}",
+                    outcome.recording.method, outcome.recording.uri, err
+                );
+            }
+        }
+    }
+
+    let mut divergences: HashMap<(&str, &str, u16, u16), usize> = HashMap::new();
+    for outcome in outcomes {
+        if let Ok(status) = outcome.result {
+            if status != outcome.recording.status {
+                let path = outcome.recording.uri.split('?').next().unwrap_or_default();
+                *divergences
+                    .entry((
+                        outcome.recording.method.as_str(),
+                        path,
+                        outcome.recording.status,
+                        status,
+                    ))
+                    .or_default() += 1;
+            }
+        }
+    }
+    let diverged: usize = divergences.values().sum();
+    println!("\nStatus divergences: {}", diverged);
+    let mut divergences: Vec<_> = divergences.into_iter().collect();
+    divergences.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    for ((method, path, recorded, replayed), count) in divergences.iter().take(MAX_DIVERGENCES) {
+        println!(
+            "  {:>6}  {} {}  {} -> {}",
+            count, method, path, recorded, replayed
+        );
+    }
+
+    !errors.is_empty() || diverged > 0
+}
+
+fn percentiles(values: &mut [f64]) -> String {
+    if values.is_empty() {
+        return String::from("-");
+    }
+    values.sort_by(|a, b| a.total_cmp(b));
+    let at = |p: f64| {
+        let rank = ((p * values.len() as f64).ceil() as usize).clamp(1, values.len());
+        values[rank - 1]
+    };
+    format!(
+        "{:>9.2} {:>9.2} {:>9.2} {:>9.2}",
+        at(0.5),
+        at(0.9),
+        at(0.99),
+        values[values.len() - 1]
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Args, String> {
+        Args::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn flags_take_separate_or_inline_values() {
+        let args = parse(&[
+            "traffic.jsonl",
+            "--target=https://staging:8443/",
+            "-s",
+            "2",
+            "--concurrency=8",
+            "--timeout",
+            "0.5",
+            "-k",
+        ])
+        .unwrap();
+        assert_eq!(args.recording, "traffic.jsonl");
+        assert_eq!(args.target, "https://staging:8443");
+        assert_eq!(args.speed, 2.0);
+        assert_eq!(args.concurrency, 8);
+        assert_eq!(args.timeout, Duration::from_millis(500));
+        assert!(args.insecure);
+
+        let args = parse(&["traffic.jsonl"]).unwrap();
+        assert_eq!(args.target, "http://127.0.0.1:8001");
+        assert_eq!(args.speed, 1.0);
+        assert_eq!(args.concurrency, 64);
+    }
+
+    #[test]
+    fn bad_arguments_are_refused() {
+        let error = |args: &[&str]| parse(args).err().unwrap();
+        assert_eq!(error(&[]), "the recording file is required");
+        assert_eq!(error(&["-k"]), "the recording file is required");
+        assert_eq!(
+            error(&["traffic.jsonl", "--rate=2"]),
+            "unknown argument --rate=2"
+        );
+        assert_eq!(
+            error(&["traffic.jsonl", "other.jsonl"]),
+            "unknown argument other.jsonl"
+        );
+        assert_eq!(
+            error(&["traffic.jsonl", "--target"]),
+            "--target requires a value"
+        );
+        assert_eq!(
+            error(&["traffic.jsonl", "-s", "-1"]),
+            "-s expects a positive number, got -1"
+        );
+    }
+
+    #[test]
+    fn percentiles_are_nearest_ranks() {
+        let mut values: Vec<f64> = (1..=100).rev().map(f64::from).collect();
+        assert_eq!(
+            percentiles(&mut values),
+            format!("{:>9.2} {:>9.2} {:>9.2} {:>9.2}", 50.0, 90.0, 99.0, 100.0)
+        );
+        assert_eq!(
+            percentiles(&mut [7.0]),
+            format!("{:>9.2} {:>9.2} {:>9.2} {:>9.2}", 7.0, 7.0, 7.0, 7.0)
+        );
+        assert_eq!(percentiles(&mut []), "-");
+    }
+}
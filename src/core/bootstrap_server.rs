@@ -1,32 +1,40 @@
 extern crate log;
+use std::collections::HashSet;
+use std::sync::Arc;
 use std::time::Duration;
 
-// log
-use log4rs;
+use futures::Future;
+use serde::{Deserialize, Serialize};
 
-// tls
-use openssl::ssl::{SslAcceptor, SslAcceptorBuilder, SslFiletype, SslMethod};
+// log
 
 // actix
 use actix_cors::Cors;
 use actix_web;
+use actix_web::body::MessageBody;
+use actix_web::dev::Transform;
 use actix_web::http::Method;
-use actix_web::{dev, http, middleware, web, web::Data, App, HttpServer, Route};
-
-// actix extens
-use actix_extensible_rate_limit::{
-    backend::memory::InMemoryBackend, backend::SimpleInput, backend::SimpleInputFunctionBuilder,
-    backend::SimpleOutput, RateLimiter,
-};
+use actix_web::{dev, http, middleware, web, web::Data, App, HttpServer};
 
 // html template
 use tera::Tera;
 
 // middlewares
 use crate::middlewares::access_filter;
+use crate::middlewares::chain::{BoxedService, Chain, MiddlewareFactory};
+use crate::middlewares::https::Https;
+use crate::middlewares::rate_limit::{MemoryBackend, Store};
+use crate::middlewares::request_id::AssignRequestId;
 // use crate::websocket::lobby::Lobby; // as well as this
 
 use crate::core::builtin_handles;
+use crate::core::health::{DiskCheck, Health, HealthCheck, MongoCheck, TemplateCheck};
+use crate::core::lifecycle::{self, Hooks};
+use crate::core::listeners;
+use crate::core::logging;
+use crate::core::routes::{RouteRegistry, RouteTable};
+use crate::core::settings::{ClientAuth, RateLimitStore, Settings};
+use crate::core::tls::{self, CertStore};
 use crate::utils;
 
 use crate::repository::mongodb_repo::MongoRepo;
@@ -34,164 +42,248 @@ use crate::services::user_service::{
     create_user, delete_user, get_all_users, get_user, update_user,
 };
 
-pub struct Server {
-    // apps: Vec<Box<dyn Fn(&mut ServiceConfig) + Send + Sync + 'static>>,
+/// A group of built-in routes that can be enabled or disabled as a whole.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Module {
+    /// `/`, `/index`, `/home`, `/about`, `/info`, `/readme` and the favicons.
+    Home,
+    /// `/healthz` (liveness) and `/readyz` (readiness).
+    Health,
+    /// `/metrics` in the Prometheus text format.
+    Metrics,
+    /// Demo routes: `/hey`, `/stream`, `/errors`, `/throw-error/{id}`, `/graphiql`, `/speed`.
+    Examples,
+    /// `/mandelbrot`
+    Mandelbrot,
+    /// The `/user` CRUD backed by MongoDB.
+    User,
+    /// The `/developer` scope, rate limited and kept to the internal networks.
+    Developer,
+    /// `/ws`
+    WebSocket,
+    /// Files under `/static`.
+    Static,
+}
+
+impl Module {
+    pub const ALL: [Module; 9] = [
+        Module::Home,
+        Module::Health,
+        Module::Metrics,
+        Module::Examples,
+        Module::Mandelbrot,
+        Module::User,
+        Module::Developer,
+        Module::WebSocket,
+        Module::Static,
+    ];
+
+    /// The routes of the module.
+    pub fn routes(self) -> RouteTable {
+        match self {
+            Module::Home => home(),
+            Module::Health => health(),
+            Module::Metrics => metrics(),
+            Module::Examples => examples(),
+            Module::Mandelbrot => mandelbrot(),
+            Module::User => user(),
+            Module::Developer => developer(),
+            Module::WebSocket => websocket(),
+            Module::Static => static_files(),
+        }
+    }
+
+    pub fn configure(self, cfg: &mut web::ServiceConfig) {
+        self.routes().configure(cfg);
+    }
 }
 
-// fn c2(method: &str, path: &str, f: &dyn std::any::Any) -> (&[u8], &str, &(dyn std::any::Any + 'static)){
-//     (method.as_bytes(), path, f)
-// }
+type ConfigureFn = Arc<dyn Fn(&mut web::ServiceConfig) + Send + Sync + 'static>;
+
+pub struct Server {
+    apps: Vec<ConfigureFn>,
+    routes: Vec<RouteTable>,
+    checks: Vec<Arc<dyn HealthCheck>>,
+    app_data: Vec<ConfigureFn>,
+    middlewares: Vec<MiddlewareFactory>,
+    modules: HashSet<Module>,
+    hooks: Hooks,
+    settings: Option<Settings>,
+}
 
+/// Registers every built-in module.
 pub fn config(cfg: &mut web::ServiceConfig) {
-    let routes = vec![(b"GET", "/developer2", builtin_handles::developer)];
+    for module in Module::ALL {
+        module.configure(cfg);
+    }
+}
 
-    let r1 = (b"GET", "/stream", builtin_handles::stream);
-    let r2 = (b"GET", "/readme", builtin_handles::readme);
-    let r3 = (b"GET", "/info", builtin_handles::info);
+fn home() -> RouteTable {
+    RouteTable::new()
+        .route(
+            "favicon",
+            &[Method::GET],
+            &["/favicon.ico"],
+            builtin_handles::favicon,
+        )
+        .route(
+            "favicon-svg",
+            &[Method::GET],
+            &["/favicon.svg"],
+            builtin_handles::favicon_svg,
+        )
+        .route(
+            "index",
+            &[Method::GET],
+            &["/", "/index", "/home"],
+            builtin_handles::index,
+        )
+        .route(
+            "readme",
+            &[Method::GET],
+            &["/readme"],
+            builtin_handles::readme,
+        )
+        .route("info", &[Method::GET], &["/info"], builtin_handles::info)
+        .route("about", &[Method::GET], &["/about"], builtin_handles::about)
+}
 
-    cfg.service(builtin_handles::favicon)
-        .service(builtin_handles::favicon_svg);
+fn health() -> RouteTable {
+    RouteTable::new()
+        .route(
+            "healthz",
+            &[Method::GET],
+            &["/healthz"],
+            builtin_handles::healthz,
+        )
+        .route(
+            "readyz",
+            &[Method::GET],
+            &["/readyz"],
+            builtin_handles::readyz,
+        )
+}
 
-    cfg.route(
-        "/",
-        Route::new()
-            .method(Method::from_bytes(b"GET").unwrap())
-            .to(builtin_handles::index),
-    )
-    .route(
-        "/index",
-        Route::new()
-            .method(Method::from_bytes(b"GET").unwrap())
-            .to(builtin_handles::index),
-    )
-    .route(
-        "/home",
-        Route::new()
-            .method(Method::from_bytes(b"GET").unwrap())
-            .to(builtin_handles::index),
-    )
-    .route(
-        r1.1,
-        Route::new()
-            .method(Method::from_bytes(r1.0).unwrap())
-            .to(r1.2),
+fn metrics() -> RouteTable {
+    RouteTable::new().route(
+        "metrics",
+        &[Method::GET],
+        &["/metrics"],
+        builtin_handles::metrics,
     )
-    .route(
-        r2.1,
-        Route::new()
-            .method(Method::from_bytes(r2.0).unwrap())
-            .to(r2.2),
-    )
-    .route(
-        r3.1,
-        Route::new()
-            .method(Method::from_bytes(r3.0).unwrap())
-            .to(r3.2),
-    )
-    .route(
-        "/hey",
-        Route::new()
-            .method(Method::from_bytes(b"GET").unwrap())
-            .to(|| async { "Hey there! 啊啊送积分啦；送积分啦" }),
-    )
-    .route(
-        "/about",
-        Route::new()
-            .method(Method::from_bytes(b"GET").unwrap())
-            .to(builtin_handles::about),
-    )
-    .route(
-        "/throw-error/{id}",
-        Route::new()
-            .method(Method::from_bytes(b"GET").unwrap())
-            .to(builtin_handles::throw_error),
-    )
-    .route(
-        "/graphiql",
-        Route::new()
-            .method(Method::from_bytes(b"GET").unwrap())
-            .to(builtin_handles::graphiql),
+}
+
+fn examples() -> RouteTable {
+    RouteTable::new()
+        .route(
+            "stream",
+            &[Method::GET],
+            &["/stream"],
+            builtin_handles::stream,
+        )
+        .route("hey", &[Method::GET], &["/hey"], || async {
+            "Hey there! 啊啊送积分啦；送积分啦"
+        })
+        .route(
+            "throw-error",
+            &[Method::GET],
+            &["/throw-error/{id}"],
+            builtin_handles::throw_error,
+        )
+        .route(
+            "graphiql",
+            &[Method::GET],
+            &["/graphiql"],
+            builtin_handles::graphiql,
+        )
+        .route("speed", &[Method::GET], &["/speed"], builtin_handles::speed)
+        .route(
+            "developer2",
+            &[Method::GET],
+            &["/developer2"],
+            builtin_handles::developer,
+        )
+        // simple error handle
+        .route(
+            "errors",
+            &[Method::GET],
+            &["/errors"],
+            builtin_handles::errors,
+        )
+}
+
+fn mandelbrot() -> RouteTable {
+    // 曼德布洛特集合绘制的灰度图片
+    RouteTable::new().route(
+        "mandelbrot",
+        &[Method::GET],
+        &["/mandelbrot"],
+        builtin_handles::mandelbrot,
     )
-    .route(
-        "/mandelbrot",
-        Route::new()
-            .method(Method::from_bytes(b"GET").unwrap())
-            .to(builtin_handles::mandelbrot),
-    ) // 曼德布洛特集合绘制的灰度图片
-    .route(
-        "/speed",
-        Route::new()
-            .method(Method::from_bytes(b"GET").unwrap())
-            .to(builtin_handles::speed),
-    );
-
-    log::info!("Router Count {}", routes.len());
-
-    for r in routes {
-        log::info!(
-            "Added a router: {} {}",
-            std::str::from_utf8(r.0).unwrap(),
-            r.1
-        );
-        cfg.route(
-            r.1,
-            Route::new()
-                .method(Method::from_bytes(r.0).unwrap())
-                .to(r.2),
-        );
-    }
+}
 
-    // user
-    cfg.service(create_user)
-        .service(get_user)
-        .service(update_user)
-        .service(delete_user)
-        .service(get_all_users);
-
-    // developers
-    cfg.service(
-        web::scope("/developer")
-            .wrap(access_limiter())
-            .route(
-                "",
-                Route::new()
-                    .method(Method::from_bytes(b"GET").unwrap())
-                    .to(builtin_handles::developer),
-            )
-            .route(
-                "/index",
-                Route::new()
-                    .method(Method::from_bytes(b"GET").unwrap())
-                    .to(builtin_handles::developer),
-            )
-            .route(
-                "/home",
-                Route::new()
-                    .method(Method::from_bytes(b"GET").unwrap())
-                    .to(builtin_handles::developer),
-            )
-            .route(
-                "/maxium",
-                Route::new()
-                    .method(Method::from_bytes(b"GET").unwrap())
-                    .to(builtin_handles::maxium),
-            )
-            .route(
-                "/typeOf",
-                Route::new()
-                    .method(Method::from_bytes(b"GET").unwrap())
-                    .to(builtin_handles::type_of),
-            ),
-    );
+fn user() -> RouteTable {
+    RouteTable::new()
+        .route("create-user", &[Method::POST], &["/user"], create_user)
+        .route("get-user", &[Method::GET], &["/user/{id}"], get_user)
+        .route("update-user", &[Method::PUT], &["/user/{id}"], update_user)
+        .route(
+            "delete-user",
+            &[Method::DELETE],
+            &["/user/{id}"],
+            delete_user,
+        )
+        .route("users", &[Method::GET], &["/users"], get_all_users)
+}
 
-    // Add the WebSocket route
-    cfg.service(web::resource("/ws").route(web::get().to(builtin_handles::websocket)));
-    // cfg.service(web::resource("/ws").route(web::get().to(echo_ws)));
+fn developer() -> RouteTable {
+    RouteTable::scope("/developer")
+        .route(
+            "developer",
+            &[Method::GET],
+            &["", "/index", "/home"],
+            builtin_handles::developer,
+        )
+        .route(
+            "maxium",
+            &[Method::GET],
+            &["/maxium"],
+            builtin_handles::maxium,
+        )
+        .route(
+            "type-of",
+            &[Method::GET],
+            &["/typeOf"],
+            builtin_handles::type_of,
+        )
+        .route(
+            "routes",
+            &[Method::GET],
+            &["/routes"],
+            builtin_handles::routes,
+        )
+        .route("stats", &[Method::GET], &["/stats"], builtin_handles::stats)
+        .route(
+            "stats-page",
+            &[Method::GET],
+            &["/stats.html"],
+            builtin_handles::stats_page,
+        )
+        .route(
+            "tls-reload",
+            &[Method::POST],
+            &["/tls/reload"],
+            builtin_handles::tls_reload,
+        )
+}
 
-    // simple error handle
-    cfg.service(builtin_handles::errors);
+fn websocket() -> RouteTable {
+    RouteTable::new().route("ws", &[Method::GET], &["/ws"], builtin_handles::websocket)
+}
 
-    cfg.configure(builtin_handles::static_handler);
+fn static_files() -> RouteTable {
+    RouteTable::new().files("static", "/static", "static")
 }
 
 pub fn cors() -> Cors {
@@ -206,98 +298,496 @@ pub fn cors() -> Cors {
         .max_age(3600)
 }
 
-pub fn tls_builder() -> SslAcceptorBuilder {
-    let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
-    builder
-        .set_private_key_file("key.pem", SslFiletype::PEM)
-        .unwrap();
-    builder.set_certificate_chain_file("cert.pem").unwrap();
-    return builder;
-}
-
-pub fn access_limiter() -> RateLimiter<
-    InMemoryBackend,
-    SimpleOutput,
-    impl Fn(&dev::ServiceRequest) -> std::future::Ready<Result<SimpleInput, actix_web::Error>>,
-> {
-    return RateLimiter::builder(
-        InMemoryBackend::builder().build(),
-        SimpleInputFunctionBuilder::new(Duration::from_secs(1), 5)
-            .real_ip_key()
-            .build(),
-    )
-    .add_headers()
-    .build();
+impl Default for Server {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Server {
     // Creates a new Server struct to configure.
     pub fn new() -> Self {
         Self {
-            // apps: vec![],
+            apps: vec![],
+            routes: vec![],
+            checks: vec![],
+            app_data: vec![],
+            middlewares: vec![],
+            modules: Module::ALL.into_iter().collect(),
+            hooks: Hooks::default(),
+            settings: None,
         }
     }
 
+    /// Uses the given settings instead of loading them from the config file
+    /// and the environment when the server starts. The command line is only
+    /// read by the binary, see `Settings::load_with_args`.
+    pub fn settings(mut self, settings: Settings) -> Self {
+        self.settings = Some(settings);
+        self
+    }
+
+    /// Registers routes, scopes or services of the downstream application,
+    /// like `App::configure`. Prefer `routes` for routes that should be
+    /// listed by `/developer/routes`.
+    pub fn configure<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&mut web::ServiceConfig) + Send + Sync + 'static,
+    {
+        self.apps.push(Arc::new(f));
+        self
+    }
+
+    /// Registers a table of routes of the downstream application, which are
+    /// listed by `/developer/routes` along with the built-in ones.
+    pub fn routes(mut self, table: RouteTable) -> Self {
+        self.routes.push(table);
+        self
+    }
+
+    /// Adds a check run by `/readyz`, and by `/healthz` if it is a liveness check.
+    pub fn health_check<C>(mut self, check: C) -> Self
+    where
+        C: HealthCheck + 'static,
+    {
+        self.checks.push(Arc::new(check));
+        self
+    }
+
+    /// Adds application data shared by all workers, like `App::app_data`.
+    /// Wrap state in `web::Data` to extract it with `web::Data<T>` in handlers.
+    pub fn app_data<U>(mut self, ext: U) -> Self
+    where
+        U: Clone + Send + Sync + 'static,
+    {
+        self.app_data.push(Arc::new(move |cfg| {
+            cfg.app_data(ext.clone());
+        }));
+        self
+    }
+
+    /// Wraps a middleware around the whole application, like `App::wrap`.
+    ///
+    /// Middlewares usually aren't `Send`, so a factory is passed which is
+    /// called once per worker, e.g. `.wrap(|| Cors::permissive())`.
+    pub fn wrap<F, M, B>(mut self, factory: F) -> Self
+    where
+        F: Fn() -> M + Send + Sync + 'static,
+        M: Transform<
+                BoxedService,
+                dev::ServiceRequest,
+                Response = dev::ServiceResponse<B>,
+                Error = actix_web::Error,
+                InitError = (),
+            > + 'static,
+        M::Transform: 'static,
+        M::Future: 'static,
+        B: MessageBody + 'static,
+    {
+        self.middlewares.push(MiddlewareFactory::new(factory));
+        self
+    }
+
+    /// Enables a built-in module, all of them are enabled by default.
+    pub fn enable(mut self, module: Module) -> Self {
+        self.modules.insert(module);
+        self
+    }
+
+    /// Disables a built-in module.
+    pub fn disable(mut self, module: Module) -> Self {
+        self.modules.remove(&module);
+        self
+    }
+
+    /// Runs `f` once the listeners are bound, before serving the first request.
+    pub fn on_start<F, Fut>(mut self, f: F) -> Self
+    where
+        F: FnOnce() -> Fut + 'static,
+        Fut: Future<Output = ()> + 'static,
+    {
+        self.hooks.on_start(f);
+        self
+    }
+
+    /// Runs `f` on each worker thread when it starts.
+    pub fn on_worker_start<F>(mut self, f: F) -> Self
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        self.hooks.on_worker_start(f);
+        self
+    }
+
+    /// Runs `f` after the server stopped and the in-flight requests drained,
+    /// before the MongoDB client is closed and the log appenders are flushed.
+    pub fn on_shutdown<F, Fut>(mut self, f: F) -> Self
+    where
+        F: FnOnce() -> Fut + 'static,
+        Fut: Future<Output = ()> + 'static,
+    {
+        self.hooks.on_shutdown(f);
+        self
+    }
+
     pub async fn run(self) {
+        let settings = match self.settings {
+            Some(settings) => settings,
+            None => match Settings::load() {
+                Ok(settings) => settings,
+                Err(err) => {
+                    eprintln!("🔥 {}", err);
+                    std::process::exit(1);
+                }
+            },
+        };
 
-        let server_port = 8001;
-        let tls_enable = false;
-        let min_stack_size = 384;   // 384k, 默认是: 2m
+        let tls_enable = settings.tls.enable;
+        let http_addr = format!("{}:{}", settings.server.host, settings.server.port);
+        let tls_addr = format!("{}:{}", settings.server.host, settings.tls.port);
 
         std::env::set_var("RUST_LOG", "debug");
-        std::env::set_var("RUST_MIN_STACK", min_stack_size.to_string());
+        std::env::set_var(
+            "RUST_MIN_STACK",
+            (settings.server.min_stack_size * 1024).to_string(),
+        );
+
+        if let Err(err) = logging::init(&settings.log) {
+            eprintln!("🔥 Couldn't set up logging: {:#}", err);
+            std::process::exit(1);
+        }
+
+        let mut modules = self.modules;
+        for module in &settings.server.disabled_modules {
+            modules.remove(module);
+        }
+        // keep the registration order stable, routes are matched in order
+        let modules: Vec<Module> = Module::ALL
+            .into_iter()
+            .filter(|m| modules.contains(m))
+            .collect();
+        log::info!("Enabled modules: {:?}", modules);
+
+        // downstream routes first, so they can shadow the built-in ones
+        let mut tables = self.routes;
+        tables.extend(modules.iter().map(|module| module.routes()));
+        let registry = RouteRegistry::new(tables);
+        let routes = registry.routes();
+        log::info!("Router Count {}", routes.len());
+        for route in &routes {
+            log::debug!(
+                "Added a router: {} {} ({})",
+                route.methods.join(","),
+                route.paths.join(", "),
+                route.name
+            );
+        }
+        let registry = Data::new(registry);
+
+        let db_data = if modules.contains(&Module::User) {
+            Some(Data::new(MongoRepo::init(&settings.mongo).await))
+        } else {
+            None
+        };
+        let tmpl_data = Data::new(
+            match Tera::new(&[utils::file::ROOT_DIR, "/templates/**/*"].concat()[..]) {
+                Ok(tera) => tera,
+                Err(err) => {
+                    // reported by /readyz and /healthz
+                    log::error!("🔥 Couldn't load the templates: {}", err);
+                    Tera::default()
+                }
+            },
+        );
 
-        log4rs::init_file("resources/log4rs.yaml", Default::default()).unwrap();
+        let mut checks = self.checks;
+        checks.push(Arc::new(TemplateCheck {
+            tera: tmpl_data.clone(),
+            required: vec!["index.html", "graphiql.html", "speed.html", "stats.html"],
+        }));
+        checks.push(Arc::new(DiskCheck {
+            path: utils::file::temp_dir(),
+            min_free: settings.health.min_free_disk,
+        }));
+        if let Some(db_data) = &db_data {
+            checks.push(Arc::new(MongoCheck(db_data.clone())));
+        }
+        let health_data = Data::new(Health::new(
+            checks,
+            Duration::from_millis(settings.health.timeout),
+        ));
+
+        let cert_store = if tls_enable {
+            match CertStore::new(&settings.tls) {
+                Ok(store) => {
+                    if settings.tls.reload_interval > 0 {
+                        store.watch(Duration::from_secs(settings.tls.reload_interval));
+                    }
+                    Some(store)
+                }
+                Err(err) => {
+                    log::error!("🔥 Couldn't load the TLS certificates: {}", err);
+                    return;
+                }
+            }
+        } else {
+            None
+        };
+        let cert_data = cert_store.clone().map(Data::from);
+
+        let apps = self.apps;
+        let app_data = self.app_data;
+        let middlewares = self.middlewares;
+        let mut hooks = self.hooks;
+        let worker_hooks = hooks.worker_start();
+        let db_client = db_data.clone();
+
+        let access = settings.log.access.clone();
+        let access_format =
+            if settings.tls.client_auth.mode == ClientAuth::None || access.format.contains("}c") {
+                access.format.clone()
+            } else {
+                format!("{} \"%{{subject}}c\"", access.format)
+            };
+        let access_json = access.json;
+        let access_rule = access.rule();
+        let access_path_rules = access.path_rules();
+
+        let recorder = match settings.recorder.recorder() {
+            Ok(recorder) => recorder,
+            Err(err) => {
+                log::error!(
+                    "🔥 Couldn't open the traffic recording {}: {}",
+                    settings.recorder.path,
+                    err
+                );
+                return;
+            }
+        };
+        if settings.recorder.enable {
+            log::info!("Recording traffic to {}", settings.recorder.path);
+        }
 
-        let db_data = Data::new(MongoRepo::init().await);
-        let tmpl_data =
-            Data::new(Tera::new(&[utils::file::ROOT_DIR, "/templates/**/*"].concat()[..]).unwrap());
+        let tls_settings = settings.tls.clone();
+        let rate_limit = settings.rate_limit.clone();
+        let trusted_proxies = settings.server.trusted_proxies();
+        let ip_filter = settings.ip_filter.ip_filter(trusted_proxies.clone());
+        let access_capture = access.capture.capture(trusted_proxies.clone());
+        // one backend for every worker, so that they share the counters
+        let rate_limit_backend = match rate_limit.store {
+            RateLimitStore::Memory => Store::Memory(MemoryBackend::new()),
+            RateLimitStore::Mongo => {
+                let db = match &db_data {
+                    Some(db_data) => Ok(db_data.database().clone()),
+                    None => MongoRepo::connect(&settings.mongo).await,
+                };
+                match db {
+                    Ok(db) => {
+                        let backend = rate_limit.mongo.backend(&db);
+                        // connects before the first request times out on it
+                        let init = backend.clone();
+                        actix_web::rt::spawn(async move {
+                            if let Err(err) = init.init().await {
+                                log::warn!("Couldn't set up the rate limit store: {}", err);
+                            }
+                        });
+                        log::info!(
+                            "Rate limit counters shared in {}.{}",
+                            settings.mongo.database,
+                            rate_limit.mongo.collection
+                        );
+                        Store::Mongo(backend)
+                    }
+                    Err(err) => {
+                        log::error!(
+                            "🔥 Couldn't connect to MongoDB, rate limit counters kept in memory: {}",
+                            err
+                        );
+                        Store::Memory(MemoryBackend::new())
+                    }
+                }
+            }
+        };
+        let metrics_enable = modules.contains(&Module::Metrics);
+        let stats_enable = modules.contains(&Module::Developer);
 
         let new_app = move || {
-            let logger = access_filter::Logger::new("%{r}a \"%r\" %s %b %D")
+            for hook in &worker_hooks {
+                hook();
+            }
+
+            let mut logger = access_filter::Logger::new(&access_format)
+                .metrics(metrics_enable)
+                .stats(stats_enable)
+                .json(access_json)
+                .rule(access_rule)
                 .exclude("/favicon.ico")
                 .exclude("/favicon.svg")
-                .exclude_regex("^/static");
+                .exclude_regex("^/static")
+                .trusted_proxies(trusted_proxies.clone());
+            for (pattern, rule) in &access_path_rules {
+                logger = logger.path_rule(pattern, *rule);
+            }
+            logger = logger.log_target(logging::ACCESS_TARGET);
+            if let Some(capture) = &access_capture {
+                logger = logger.capture(capture.clone());
+            }
 
-            App::new()
+            let mut app = App::new()
                 .app_data(tmpl_data.clone())
-                .app_data(db_data.clone())
+                .app_data(registry.clone())
+                .app_data(health_data.clone());
+            if let Some(db_data) = &db_data {
+                app = app.app_data(db_data.clone());
+            }
+            if let Some(cert_data) = &cert_data {
+                app = app.app_data(cert_data.clone());
+            }
+            for data in &app_data {
+                app = app.configure(|cfg| data(cfg));
+            }
+
+            app.wrap(Chain::new(&middlewares))
                 // .wrap(cors())
+                .wrap(rate_limit.rate_limit(rate_limit_backend.clone(), trusted_proxies.clone()))
+                // before the rate limit, which doesn't count the denied requests
+                .wrap(ip_filter.clone())
+                .wrap(Https::new(&tls_settings).trusted_proxies(trusted_proxies.clone()))
                 .wrap(logger)
+                .wrap(AssignRequestId::new())
                 .wrap(middleware::NormalizePath::new(
                     middleware::TrailingSlash::Trim,
                 ))
-                .configure(|wc| config(wc))
+                .wrap(recorder.clone())
+                .configure(|cfg| {
+                    for configure in &apps {
+                        configure(cfg);
+                    }
+                    registry.configure(cfg);
+                })
                 .default_service(web::route().to(builtin_handles::not_found))
         };
 
         let server = HttpServer::new(new_app)
-            .backlog(8192)
-            .workers(1)
-            .keep_alive(Duration::from_secs(75));
-
-        let bind_result = if !tls_enable {
-            server.bind(format!("0.0.0.0:{}", server_port))
+            .backlog(settings.server.backlog)
+            .workers(settings.server.workers)
+            .keep_alive(settings.server.keep_alive())
+            .shutdown_timeout(settings.server.shutdown_timeout)
+            .on_connect(tls::on_connect)
+            .disable_signals();
+
+        let activated = if settings.server.systemd {
+            match listeners::systemd_listeners() {
+                Ok(activated) => activated,
+                Err(err) => {
+                    log::error!("🔥 Couldn't adopt the systemd sockets: {}", err);
+                    return;
+                }
+            }
         } else {
-            server.bind_openssl(format!("0.0.0.0:{}", server_port), tls_builder())
+            vec![]
         };
 
+        let mut listeners = vec![];
+        let mut bind_result = Ok(server);
+        if settings.server.tcp && settings.tls.http_enabled() {
+            bind_result = bind_result.and_then(|server| server.bind(&http_addr));
+            listeners.push(format!("http://{}", http_addr));
+        }
+        if let Some(store) = cert_store.as_ref().filter(|_| settings.server.tcp) {
+            bind_result = bind_result.and_then(|server| {
+                let acceptor = store.acceptor().map_err(tls_error)?;
+                server.bind_openssl(&tls_addr, acceptor)
+            });
+            listeners.push(format!("https://{}", tls_addr));
+        }
+        if !settings.server.unix.path.is_empty() {
+            let unix = &settings.server.unix;
+            bind_result = bind_result.and_then(|server| {
+                let server = server.bind_uds(&unix.path)?;
+                listeners::secure_unix_socket(unix)?;
+                Ok(server)
+            });
+            listeners.push(format!("unix:{}", unix.path));
+        }
+        for socket in activated {
+            let name = socket.name().to_string();
+            match socket {
+                listeners::Activated::Tcp(lst, _) => {
+                    let addr = lst.local_addr().map(|a| a.to_string()).unwrap_or_default();
+                    // sockets named https or tls in the socket unit get the TLS acceptor
+                    match cert_store
+                        .as_ref()
+                        .filter(|_| name == "https" || name == "tls")
+                    {
+                        Some(store) => {
+                            bind_result = bind_result.and_then(|server| {
+                                let acceptor = store.acceptor().map_err(tls_error)?;
+                                server.listen_openssl(lst, acceptor)
+                            });
+                            listeners.push(format!("https://{} (systemd {})", addr, name));
+                        }
+                        None => {
+                            bind_result = bind_result.and_then(|server| server.listen(lst));
+                            listeners.push(format!("http://{} (systemd {})", addr, name));
+                        }
+                    }
+                }
+                listeners::Activated::Unix(lst, _) => {
+                    bind_result = bind_result.and_then(|server| server.listen_uds(lst));
+                    listeners.push(format!("unix socket (systemd {})", name));
+                }
+            }
+        }
+        if listeners.is_empty() {
+            log::error!(
+                "🔥 No listener to serve on, check server.tcp, server.unix and server.systemd"
+            );
+            return;
+        }
+
         match bind_result {
             Ok(svr) => {
-                if !tls_enable {
-                    log::info!(
-                        "Congratulations! Your server will be running at http://0.0.0.0:{}",
-                        server_port
-                    );
-                } else {
-                    log::info!(
-                        "Congratulations! Your server will be running at https://0.0.0.0:{}",
-                        server_port
-                    );
+                log::info!(
+                    "Congratulations! Your server will be running at {}",
+                    listeners.join(" and ")
+                );
+
+                let drain_timeout = Duration::from_secs(settings.server.shutdown_timeout);
+                hooks
+                    .serve(|| {
+                        let svr = svr.run();
+                        actix_web::rt::spawn(lifecycle::graceful_shutdown(
+                            svr.handle(),
+                            drain_timeout,
+                        ));
+                        svr
+                    })
+                    .await
+                    .expect("Failed to run server");
+
+                if let Some(db_client) = db_client {
+                    // the workers and their app data are gone with the server, the
+                    // client closes once the rate limit store lets go of it too
+                    match Arc::try_unwrap(db_client.into_inner()) {
+                        Ok(repo) => {
+                            repo.close();
+                            log::info!("MongoDB repository released");
+                        }
+                        Err(_) => log::warn!("MongoDB repository still in use"),
+                    }
                 }
-                svr.run().await.expect("Failed to run server")
+                log::logger().flush();
             }
-            _ => log::info!("🔥 Couldn't start the server at port {}", server_port),
+            Err(err) => log::info!(
+                "🔥 Couldn't start the server at {}: {}",
+                listeners.join(" and "),
+                err
+            ),
         }
     }
 }
+
+fn tls_error(err: openssl::error::ErrorStack) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, err)
+}
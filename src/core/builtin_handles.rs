@@ -2,10 +2,7 @@ use std::io;
 
 use actix_files::NamedFile;
 use actix_web;
-use actix_web::{
-    error, get, http, web, web::Data, web::ServiceConfig, Error, HttpRequest, HttpResponse,
-    Responder, Result,
-};
+use actix_web::{error, http, web, web::Data, Error, HttpRequest, HttpResponse, Responder, Result};
 use actix_web_actors::ws;
 
 use derive_more::{Display, Error};
@@ -14,6 +11,11 @@ use futures::{future::ok, stream::once};
 // html template
 use tera::{Context, Tera};
 
+use crate::core::health::Health;
+use crate::core::metrics;
+use crate::core::routes::RouteRegistry;
+use crate::core::stats;
+use crate::core::tls::CertStore;
 use crate::mandelbrot::mandelbrot_png;
 use crate::utils;
 use crate::websocket;
@@ -27,12 +29,10 @@ pub struct MyError {
 // Use default implementation for `error_response()` method
 impl error::ResponseError for MyError {}
 
-#[get("/favicon.ico")]
 pub async fn favicon(_req: HttpRequest) -> io::Result<NamedFile> {
     NamedFile::open("static/favicon.ico")
 }
 
-#[get("/favicon.svg")]
 pub async fn favicon_svg() -> impl Responder {
     NamedFile::open_async("./static/favicon.svg").await.unwrap()
 }
@@ -93,6 +93,23 @@ pub async fn info() -> impl Responder {
     HttpResponse::Ok().json("Hello, server is alive and kicking.")
 }
 
+/// Liveness: whether the process should be restarted.
+pub async fn healthz(health: Data<Health>) -> HttpResponse {
+    health.run(true).await.into_response()
+}
+
+/// Readiness: whether the dependencies are reachable and traffic can be routed here.
+pub async fn readyz(health: Data<Health>) -> HttpResponse {
+    health.run(false).await.into_response()
+}
+
+/// Request and WebSocket metrics in the Prometheus text format.
+pub async fn metrics() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4; charset=utf-8")
+        .body(metrics::render())
+}
+
 pub async fn readme(_req: HttpRequest) -> io::Result<NamedFile> {
     NamedFile::open("README.md")
 }
@@ -118,7 +135,6 @@ pub async fn stream() -> HttpResponse {
         .streaming(body)
 }
 
-#[get("/errors")]
 pub async fn errors() -> Result<&'static str, MyError> {
     Err(MyError {
         name: "MyError,粗欧文",
@@ -139,14 +155,6 @@ pub async fn not_found(_request: HttpRequest) -> Result<HttpResponse> {
         .body("<h1>404 - Page not found</h1>"))
 }
 
-pub fn static_handler(config: &mut ServiceConfig) {
-    // let static_path =
-    //     std::env::var("STATIC_ROOT").expect("Running in debug without STATIC_ROOT set!");
-    let static_path = "static";
-    let fs = actix_files::Files::new("/static", static_path);
-    config.service(fs);
-}
-
 pub async fn mandelbrot() -> io::Result<NamedFile> {
     let file_name = "mandel.png";
     let current_file = utils::file::temp_dir() + "/" + file_name;
@@ -165,6 +173,40 @@ pub async fn mandelbrot() -> io::Result<NamedFile> {
     NamedFile::open(utils::file::temp_dir() + "/" + file_name)
 }
 
+/// Reloads the TLS certificates from disk.
+pub async fn tls_reload(store: Option<Data<CertStore>>) -> HttpResponse {
+    match store {
+        None => HttpResponse::NotFound().json("TLS is not enabled"),
+        Some(store) => match store.reload() {
+            Ok(_) => HttpResponse::Ok().json("TLS certificates reloaded"),
+            Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+        },
+    }
+}
+
+/// Lists every route registered by the server.
+pub async fn routes(registry: Option<Data<RouteRegistry>>) -> HttpResponse {
+    match registry {
+        None => HttpResponse::NotFound().json("The route registry is not available"),
+        Some(registry) => HttpResponse::Ok().json(registry.routes()),
+    }
+}
+
+/// Latency, errors and bytes of each route over the last minutes.
+pub async fn stats() -> HttpResponse {
+    HttpResponse::Ok().json(stats::snapshot())
+}
+
+pub async fn stats_page(tmpl: Data<Tera>) -> impl Responder {
+    let mut ctx = Context::new();
+    ctx.insert("stats", &stats::snapshot());
+
+    match tmpl.render("stats.html", &ctx) {
+        Ok(rendered) => HttpResponse::Ok().body(rendered),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
 // 测试网速
 /// Speed tests are an excellent way to check your network connection speed.
 /// Fast network connections are key for enjoying a seamless experience on the internet.
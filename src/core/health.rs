@@ -0,0 +1,321 @@
+use std::ffi::CString;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use actix_web::{web::Data, HttpResponse};
+use futures::future::{join_all, LocalBoxFuture};
+use serde::Serialize;
+use tera::Tera;
+
+use crate::repository::mongodb_repo::MongoRepo;
+
+/// A dependency check run by `/healthz` and `/readyz`.
+///
+/// On success a check returns a short detail (e.g. the free space), on
+/// failure the reason it is down.
+pub trait HealthCheck: Send + Sync {
+    fn name(&self) -> &str;
+
+    /// Whether `/healthz` runs the check too. A failing liveness check gets
+    /// the process restarted, so only checks a restart can fix should opt in.
+    fn liveness(&self) -> bool {
+        false
+    }
+
+    fn check(&self) -> LocalBoxFuture<'_, Result<String, String>>;
+}
+
+/// The registered checks, shared with the handlers as `Data<Health>`.
+#[derive(Clone)]
+pub struct Health {
+    checks: Vec<Arc<dyn HealthCheck>>,
+    timeout: Duration,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub status: Status,
+    pub checks: Vec<CheckReport>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CheckReport {
+    pub name: String,
+    pub status: Status,
+    pub latency_ms: f64,
+    pub detail: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Up,
+    Down,
+}
+
+impl Health {
+    pub fn new(checks: Vec<Arc<dyn HealthCheck>>, timeout: Duration) -> Self {
+        Self { checks, timeout }
+    }
+
+    /// Runs the checks concurrently, each bounded by the timeout.
+    pub async fn run(&self, liveness: bool) -> Report {
+        let checks = self
+            .checks
+            .iter()
+            .filter(|check| !liveness || check.liveness())
+            .map(|check| self.run_one(check.as_ref()));
+        let checks = join_all(checks).await;
+
+        let status = if checks.iter().all(|check| check.status == Status::Up) {
+            Status::Up
+        } else {
+            Status::Down
+        };
+        Report { status, checks }
+    }
+
+    async fn run_one(&self, check: &dyn HealthCheck) -> CheckReport {
+        let start = Instant::now();
+        let result = match actix_web::rt::time::timeout(self.timeout, check.check()).await {
+            Ok(result) => result,
+            Err(_) => Err(format!("timed out after {:?}", self.timeout)),
+        };
+        let latency_ms = start.elapsed().as_secs_f64() * 1000.0;
+
+        let (status, detail) = match result {
+            Ok(detail) => (Status::Up, detail),
+            Err(reason) => {
+                log::warn!("Health check {} is down: {}", check.name(), reason);
+                (Status::Down, reason)
+            }
+        };
+        CheckReport {
+            name: check.name().to_string(),
+            status,
+            latency_ms,
+            detail,
+        }
+    }
+}
+
+impl Report {
+    pub fn into_response(self) -> HttpResponse {
+        match self.status {
+            Status::Up => HttpResponse::Ok().json(self),
+            Status::Down => HttpResponse::ServiceUnavailable().json(self),
+        }
+    }
+}
+
+/// Pings MongoDB through the repository.
+pub struct MongoCheck(pub Data<MongoRepo>);
+
+impl HealthCheck for MongoCheck {
+    fn name(&self) -> &str {
+        "mongo"
+    }
+
+    fn check(&self) -> LocalBoxFuture<'_, Result<String, String>> {
+        Box::pin(async move {
+            self.0
+                .ping()
+                .await
+                .map(|_| String::from("ping ok"))
+                .map_err(|err| err.to_string())
+        })
+    }
+}
+
+/// Checks that the templates rendered by the handlers were loaded.
+pub struct TemplateCheck {
+    pub tera: Data<Tera>,
+    pub required: Vec<&'static str>,
+}
+
+impl HealthCheck for TemplateCheck {
+    fn name(&self) -> &str {
+        "templates"
+    }
+
+    fn liveness(&self) -> bool {
+        true
+    }
+
+    fn check(&self) -> LocalBoxFuture<'_, Result<String, String>> {
+        let missing: Vec<&str> = self
+            .required
+            .iter()
+            .filter(|name| self.tera.get_template(name).is_err())
+            .copied()
+            .collect();
+
+        let result = if missing.is_empty() {
+            Ok(format!("{} templates loaded", self.required.len()))
+        } else {
+            Err(format!("missing templates: {}", missing.join(", ")))
+        };
+        Box::pin(async move { result })
+    }
+}
+
+/// Checks the free space of a directory, e.g. the temp dir `/mandelbrot`
+/// renders its images to.
+pub struct DiskCheck {
+    pub path: String,
+    /// Minimum free space in MiB.
+    pub min_free: u64,
+}
+
+impl HealthCheck for DiskCheck {
+    fn name(&self) -> &str {
+        "disk"
+    }
+
+    fn check(&self) -> LocalBoxFuture<'_, Result<String, String>> {
+        let result = free_space(&self.path)
+            .map_err(|err| format!("couldn't stat {}: {}", self.path, err))
+            .and_then(|free| {
+                let free = free / 1024 / 1024;
+                if free >= self.min_free {
+                    Ok(format!("{} MiB free in {}", free, self.path))
+                } else {
+                    Err(format!(
+                        "{} MiB free in {}, {} MiB required",
+                        free, self.path, self.min_free
+                    ))
+                }
+            });
+        Box::pin(async move { result })
+    }
+}
+
+/// Bytes available to unprivileged users on the file system of `path`.
+fn free_space(path: &str) -> std::io::Result<u64> {
+    let path = CString::new(path)?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    #[allow(clippy::unnecessary_cast)]
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::body::to_bytes;
+    use serde_json::Value;
+
+    use super::*;
+
+    /// A check answering `result` after `delay`.
+    struct Stub {
+        name: &'static str,
+        liveness: bool,
+        delay: Duration,
+        result: Result<&'static str, &'static str>,
+    }
+
+    impl Stub {
+        fn new(name: &'static str, result: Result<&'static str, &'static str>) -> Self {
+            Stub {
+                name,
+                liveness: false,
+                delay: Duration::ZERO,
+                result,
+            }
+        }
+    }
+
+    impl HealthCheck for Stub {
+        fn name(&self) -> &str {
+            self.name
+        }
+
+        fn liveness(&self) -> bool {
+            self.liveness
+        }
+
+        fn check(&self) -> LocalBoxFuture<'_, Result<String, String>> {
+            Box::pin(async move {
+                actix_web::rt::time::sleep(self.delay).await;
+                self.result.map(String::from).map_err(String::from)
+            })
+        }
+    }
+
+    fn health(checks: Vec<Stub>) -> Health {
+        let checks = checks
+            .into_iter()
+            .map(|check| Arc::new(check) as Arc<dyn HealthCheck>)
+            .collect();
+        Health::new(checks, Duration::from_millis(50))
+    }
+
+    async fn json(report: Report) -> (u16, Value) {
+        let res = report.into_response();
+        let status = res.status().as_u16();
+        let body = to_bytes(res.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[actix_web::test]
+    async fn readiness_is_down_when_a_check_fails() {
+        let health = health(vec![
+            Stub::new("mongo", Ok("ping ok")),
+            Stub::new("disk", Err("full")),
+        ]);
+        let (status, body) = json(health.run(false).await).await;
+
+        assert_eq!(status, 503);
+        assert_eq!(body["status"], "down");
+        assert_eq!(body["checks"][0]["status"], "up");
+        assert_eq!(body["checks"][1]["status"], "down");
+        assert_eq!(body["checks"][1]["detail"], "full");
+    }
+
+    #[actix_web::test]
+    async fn liveness_only_runs_the_liveness_checks() {
+        let health = health(vec![
+            Stub {
+                liveness: true,
+                ..Stub::new("templates", Ok("loaded"))
+            },
+            Stub::new("mongo", Err("unreachable")),
+        ]);
+
+        let report = health.run(true).await;
+        assert_eq!(report.status, Status::Up);
+        let names: Vec<&str> = report.checks.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, ["templates"]);
+
+        assert_eq!(health.run(false).await.status, Status::Down);
+    }
+
+    #[actix_web::test]
+    async fn slow_checks_are_down() {
+        let health = health(vec![Stub {
+            delay: Duration::from_secs(5),
+            ..Stub::new("mongo", Ok("ping ok"))
+        }]);
+        let report = health.run(false).await;
+
+        assert_eq!(report.status, Status::Down);
+        assert!(report.checks[0].detail.starts_with("timed out"));
+        assert!(report.checks[0].latency_ms < 1000.0);
+    }
+
+    #[actix_web::test]
+    async fn checks_report_their_status_and_latency() {
+        let health = health(vec![Stub::new("mongo", Ok("ping ok"))]);
+        let (status, body) = json(health.run(false).await).await;
+
+        assert_eq!(status, 200);
+        let check = body["checks"][0].as_object().unwrap();
+        let mut keys: Vec<&str> = check.keys().map(String::as_str).collect();
+        keys.sort_unstable();
+        assert_eq!(keys, ["detail", "latency_ms", "name", "status"]);
+        assert!(check["latency_ms"].is_f64());
+        assert_eq!(check["name"], "mongo");
+    }
+}
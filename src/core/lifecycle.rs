@@ -0,0 +1,270 @@
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use actix_web::dev::{Server, ServerHandle};
+use futures::future::LocalBoxFuture;
+use futures::Future;
+
+use crate::websocket::sessions;
+
+type Hook = Box<dyn FnOnce() -> LocalBoxFuture<'static, ()>>;
+type WorkerHook = Arc<dyn Fn() + Send + Sync + 'static>;
+
+/// Callbacks run at the different stages of the server lifecycle.
+#[derive(Default)]
+pub struct Hooks {
+    on_start: Vec<Hook>,
+    on_worker_start: Vec<WorkerHook>,
+    on_shutdown: Vec<Hook>,
+}
+
+impl Hooks {
+    pub fn on_start<F, Fut>(&mut self, f: F)
+    where
+        F: FnOnce() -> Fut + 'static,
+        Fut: Future<Output = ()> + 'static,
+    {
+        self.on_start.push(Box::new(move || Box::pin(f())));
+    }
+
+    pub fn on_worker_start<F>(&mut self, f: F)
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        self.on_worker_start.push(Arc::new(f));
+    }
+
+    pub fn on_shutdown<F, Fut>(&mut self, f: F)
+    where
+        F: FnOnce() -> Fut + 'static,
+        Fut: Future<Output = ()> + 'static,
+    {
+        self.on_shutdown.push(Box::new(move || Box::pin(f())));
+    }
+
+    /// Runs the server `run` starts between the hooks: `on_start` once the
+    /// listeners are bound and before the workers start, `on_shutdown` once
+    /// the server future completed and its listeners are closed.
+    pub async fn serve(&mut self, run: impl FnOnce() -> Server) -> io::Result<()> {
+        self.start().await;
+        run().await?;
+        log::info!("Server stopped");
+        self.shutdown().await;
+        Ok(())
+    }
+
+    /// Runs the `on_start` hooks.
+    async fn start(&mut self) {
+        for hook in self.on_start.drain(..) {
+            hook().await;
+        }
+    }
+
+    /// The `on_worker_start` hooks, to be run by each worker when it builds its `App`.
+    pub fn worker_start(&self) -> Vec<WorkerHook> {
+        self.on_worker_start.clone()
+    }
+
+    /// Runs the `on_shutdown` hooks in registration order.
+    async fn shutdown(&mut self) {
+        for hook in self.on_shutdown.drain(..) {
+            hook().await;
+        }
+    }
+}
+
+/// Completes when the process receives SIGTERM or SIGINT (ctrl-c).
+///
+/// A signal that can't be listened for is logged and never completes, the
+/// server then has to be killed.
+pub async fn shutdown_signal() -> &'static str {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, Signal, SignalKind};
+
+        fn listen(kind: SignalKind, name: &str) -> Option<Signal> {
+            signal(kind)
+                .map_err(|err| log::error!("🔥 Couldn't listen for {}: {}", name, err))
+                .ok()
+        }
+
+        async fn recv(signal: Option<Signal>) {
+            match signal {
+                Some(mut signal) => {
+                    signal.recv().await;
+                }
+                None => futures::future::pending().await,
+            }
+        }
+
+        let term = listen(SignalKind::terminate(), "SIGTERM");
+        let int = listen(SignalKind::interrupt(), "SIGINT");
+
+        tokio::select! {
+            _ = recv(term) => "SIGTERM",
+            _ = recv(int) => "SIGINT",
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        if let Err(err) = tokio::signal::ctrl_c().await {
+            log::error!("🔥 Couldn't listen for ctrl-c: {}", err);
+            futures::future::pending::<()>().await;
+        }
+        "ctrl-c"
+    }
+}
+
+/// Waits for a shutdown signal then stops the server gracefully.
+///
+/// Open WebSocket sessions are sent a close frame first, since they would
+/// otherwise keep their connection and the workers busy until `drain_timeout`
+/// runs out. The in-flight HTTP requests get up to `drain_timeout` to finish,
+/// unless a second signal comes first, which exits right away.
+pub async fn graceful_shutdown(handle: ServerHandle, drain_timeout: Duration) {
+    let signal = shutdown_signal().await;
+    log::info!(
+        "Received {}, shutting down gracefully (drain timeout {:?})",
+        signal,
+        drain_timeout
+    );
+
+    let drain = async {
+        let count = sessions::close_all();
+        if count > 0 {
+            log::info!("Closing {} websocket session(s)", count);
+
+            // give the sessions a moment to flush their close frame
+            let deadline = Instant::now() + drain_timeout.min(Duration::from_secs(5));
+            while sessions::count() > 0 && Instant::now() < deadline {
+                actix_web::rt::time::sleep(Duration::from_millis(50)).await;
+            }
+        }
+
+        handle.stop(true).await;
+    };
+
+    tokio::select! {
+        _ = drain => {}
+        signal = shutdown_signal() => {
+            log::warn!("Received {} again, exiting without draining the requests", signal);
+            log::logger().flush();
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::Mutex;
+
+    use actix_web::{web, App, HttpRequest, HttpServer};
+    use actix_web_actors::ws;
+    use futures::StreamExt;
+
+    use super::*;
+    use crate::websocket::server::MyWebSocket;
+
+    /// Polls `done` every 10ms, for at most a second.
+    async fn wait_until(done: impl Fn() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while !done() {
+            assert!(Instant::now() < deadline, "timed out");
+            actix_web::rt::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[actix_web::test]
+    async fn hooks_run_around_the_server() {
+        let events = Arc::new(Mutex::new(vec![]));
+        let log = |event: &'static str| {
+            let events = events.clone();
+            move || events.lock().unwrap().push(event)
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let mut hooks = Hooks::default();
+        let start = log("start");
+        hooks.on_start(move || async move { start() });
+        hooks.on_worker_start(log("worker"));
+        let shutdown = log("shutdown");
+        hooks.on_shutdown(move || async move {
+            // the listener is closed by the time the server future completes
+            assert!(TcpStream::connect(addr).is_err());
+            shutdown()
+        });
+
+        let worker_hooks = hooks.worker_start();
+        let server = HttpServer::new(move || {
+            for hook in &worker_hooks {
+                hook();
+            }
+            App::new()
+        })
+        .workers(2)
+        .disable_signals()
+        .listen(listener)
+        .unwrap();
+
+        let started = events.clone();
+        hooks
+            .serve(move || {
+                let server = server.run();
+                let handle = server.handle();
+                actix_web::rt::spawn(async move {
+                    wait_until(|| started.lock().unwrap().len() == 3).await;
+                    handle.stop(true).await;
+                });
+                server
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(
+            *events.lock().unwrap(),
+            ["start", "worker", "worker", "shutdown"]
+        );
+    }
+
+    #[actix_web::test]
+    async fn sessions_are_closed_as_going_away() {
+        let server = HttpServer::new(|| {
+            App::new().route(
+                "/ws",
+                web::get().to(|req: HttpRequest, stream: web::Payload| async move {
+                    ws::start(MyWebSocket::new(), &req, stream)
+                }),
+            )
+        })
+        .workers(1)
+        .disable_signals()
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let addr = server.addrs()[0];
+        let server = server.run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        let (_, mut frames) = awc::Client::new()
+            .ws(format!("ws://{}/ws", addr))
+            .connect()
+            .await
+            .unwrap();
+        wait_until(|| sessions::count() == 1).await;
+        assert_eq!(sessions::close_all(), 1);
+
+        let frame = frames.next().await.unwrap().unwrap();
+        match frame {
+            awc::ws::Frame::Close(Some(reason)) => {
+                assert_eq!(reason.code, ws::CloseCode::Away)
+            }
+            frame => panic!("expected a close frame, got {:?}", frame),
+        }
+        wait_until(|| sessions::count() == 0).await;
+        handle.stop(false).await;
+    }
+}
@@ -0,0 +1,244 @@
+//! Listening sockets besides the plain `host:port` binds: Unix domain sockets
+//! and sockets passed in by systemd (socket activation).
+
+use std::ffi::CString;
+use std::io;
+use std::net::TcpListener;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::net::UnixListener;
+use std::path::Path;
+
+use crate::core::settings::UnixSocketSettings;
+
+/// The first file descriptor passed by systemd, see sd_listen_fds(3).
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// A listening socket inherited from systemd.
+pub enum Activated {
+    Tcp(TcpListener, String),
+    Unix(UnixListener, String),
+}
+
+impl Activated {
+    /// The `FileDescriptorName=` of the socket unit, `unknown` if not set.
+    pub fn name(&self) -> &str {
+        match self {
+            Activated::Tcp(_, name) | Activated::Unix(_, name) => name,
+        }
+    }
+}
+
+/// Takes the listening sockets passed with `LISTEN_FDS`, if they are meant for
+/// this process.
+///
+/// The variables are removed from the environment afterwards so that child
+/// processes don't try to adopt the same sockets.
+pub fn systemd_listeners() -> io::Result<Vec<Activated>> {
+    let pid = std::env::var("LISTEN_PID").ok();
+    let fds = std::env::var("LISTEN_FDS").ok();
+    let names = std::env::var("LISTEN_FDNAMES").ok();
+    for var in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        std::env::remove_var(var);
+    }
+
+    let passed = passed_fds(
+        pid.as_deref(),
+        fds.as_deref(),
+        names.as_deref(),
+        std::process::id(),
+    );
+
+    let mut listeners = vec![];
+    for (fd, name) in passed {
+        // the descriptors are passed without close-on-exec
+        if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let listener = match socket_family(fd)? {
+            libc::AF_INET | libc::AF_INET6 => {
+                let listener = unsafe { TcpListener::from_raw_fd(fd) };
+                listener.set_nonblocking(true)?;
+                Activated::Tcp(listener, name)
+            }
+            libc::AF_UNIX => {
+                let listener = unsafe { UnixListener::from_raw_fd(fd) };
+                listener.set_nonblocking(true)?;
+                Activated::Unix(listener, name)
+            }
+            family => {
+                log::warn!(
+                    "Ignoring socket {} ({}) of unsupported family {}",
+                    fd,
+                    name,
+                    family
+                );
+                continue;
+            }
+        };
+        listeners.push(listener);
+    }
+
+    Ok(listeners)
+}
+
+/// The descriptors and names passed with `LISTEN_FDS` and `LISTEN_FDNAMES`,
+/// none unless `LISTEN_PID` is `own_pid`.
+fn passed_fds(
+    pid: Option<&str>,
+    fds: Option<&str>,
+    names: Option<&str>,
+    own_pid: u32,
+) -> Vec<(RawFd, String)> {
+    match pid.and_then(|pid| pid.parse::<u32>().ok()) {
+        Some(pid) if pid == own_pid => {}
+        _ => return vec![],
+    }
+    let count: RawFd = match fds.and_then(|fds| fds.parse().ok()) {
+        Some(count) => count,
+        None => return vec![],
+    };
+    let names: Vec<&str> = names
+        .map(|names| names.split(':').collect())
+        .unwrap_or_default();
+
+    (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count)
+        .enumerate()
+        .map(|(i, fd)| {
+            let name = names.get(i).copied().unwrap_or("unknown");
+            (fd, name.to_string())
+        })
+        .collect()
+}
+
+/// Applies the configured mode and owner to a bound Unix socket.
+pub fn secure_unix_socket(settings: &UnixSocketSettings) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = u32::from_str_radix(&settings.mode, 8)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid unix socket mode"))?;
+    std::fs::set_permissions(&settings.path, std::fs::Permissions::from_mode(mode))?;
+
+    let uid = match settings.owner.as_str() {
+        "" => None,
+        owner => Some(user_id(owner)?),
+    };
+    let gid = match settings.group.as_str() {
+        "" => None,
+        group => Some(group_id(group)?),
+    };
+    if uid.is_some() || gid.is_some() {
+        chown(
+            Path::new(&settings.path),
+            uid.unwrap_or(u32::MAX),
+            gid.unwrap_or(u32::MAX),
+        )?;
+    }
+
+    Ok(())
+}
+
+fn socket_family(fd: RawFd) -> io::Result<libc::c_int> {
+    let mut addr: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    let res =
+        unsafe { libc::getsockname(fd, &mut addr as *mut _ as *mut libc::sockaddr, &mut len) };
+    if res == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(addr.ss_family as libc::c_int)
+}
+
+/// A numeric uid or a user name.
+fn user_id(owner: &str) -> io::Result<libc::uid_t> {
+    if let Ok(uid) = owner.parse() {
+        return Ok(uid);
+    }
+    let name = CString::new(owner)?;
+    let passwd = unsafe { libc::getpwnam(name.as_ptr()) };
+    if passwd.is_null() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("unknown user {}", owner),
+        ));
+    }
+    Ok(unsafe { (*passwd).pw_uid })
+}
+
+/// A numeric gid or a group name.
+fn group_id(group: &str) -> io::Result<libc::gid_t> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+    let name = CString::new(group)?;
+    let entry = unsafe { libc::getgrnam(name.as_ptr()) };
+    if entry.is_null() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("unknown group {}", group),
+        ));
+    }
+    Ok(unsafe { (*entry).gr_gid })
+}
+
+/// `u32::MAX` (-1) leaves the owner or group unchanged.
+fn chown(path: &Path, uid: libc::uid_t, gid: libc::gid_t) -> io::Result<()> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    if unsafe { libc::chown(path.as_ptr(), uid, gid) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+
+    const PID: u32 = 4242;
+
+    #[test]
+    fn fds_are_passed_to_the_listen_pid() {
+        assert!(passed_fds(Some("4243"), Some("1"), None, PID).is_empty());
+        assert!(passed_fds(None, Some("1"), None, PID).is_empty());
+        assert!(passed_fds(Some("4242"), Some("0"), None, PID).is_empty());
+        assert!(passed_fds(Some("4242"), None, None, PID).is_empty());
+        assert_eq!(
+            passed_fds(Some("4242"), Some("1"), None, PID),
+            [(3, String::from("unknown"))]
+        );
+    }
+
+    #[test]
+    fn fds_are_named_in_order() {
+        assert_eq!(
+            passed_fds(Some("4242"), Some("3"), Some("http:admin"), PID),
+            [
+                (3, String::from("http")),
+                (4, String::from("admin")),
+                (5, String::from("unknown")),
+            ]
+        );
+    }
+
+    #[test]
+    fn unix_sockets_get_the_mode() {
+        let path = std::env::temp_dir().join(format!("rs-starter-{}.sock", std::process::id()));
+        std::fs::remove_file(&path).ok();
+        let _listener = UnixListener::bind(&path).unwrap();
+
+        let settings = UnixSocketSettings {
+            path: path.display().to_string(),
+            mode: String::from("640"),
+            ..UnixSocketSettings::default()
+        };
+        let secured = secure_unix_socket(&settings);
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        std::fs::remove_file(&path).ok();
+
+        secured.unwrap();
+        assert_eq!(mode & 0o777, 0o640);
+    }
+}
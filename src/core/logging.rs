@@ -0,0 +1,260 @@
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::LevelFilter;
+use log4rs::append::console::ConsoleAppender;
+use log4rs::append::file::FileAppender;
+use log4rs::append::rolling_file::policy::compound::roll::delete::DeleteRoller;
+use log4rs::append::rolling_file::policy::compound::roll::fixed_window::FixedWindowRoller;
+use log4rs::append::rolling_file::policy::compound::roll::Roll;
+use log4rs::append::rolling_file::policy::compound::trigger::Trigger;
+use log4rs::append::rolling_file::policy::compound::CompoundPolicy;
+use log4rs::append::rolling_file::{LogFile, RollingFileAppender};
+use log4rs::append::Append;
+use log4rs::config::{Appender, Config, Logger, Root};
+use log4rs::encode::pattern::PatternEncoder;
+
+use crate::core::settings::{LogFileSettings, LogSettings, RotationPeriod};
+
+/// Target of the access log records, see `access_filter::Logger::log_target`.
+pub const ACCESS_TARGET: &str = "access";
+
+const STDOUT_PATTERN: &str =
+    "{d(%Y-%m-%dT%H:%M:%S%Z)} {({l}):5.5} [{M}:{L}] [{X(request_id)(-)}] - {m}{n}";
+const APP_PATTERN: &str =
+    "{d(%Y-%m-%dT%H:%M:%S%Z)} {({l}):5.5} [{f}:{L}] [{X(request_id)(-)}] - {m}{n}";
+const ACCESS_PATTERN: &str = "{d(%Y-%m-%dT%H:%M:%S%Z)} {({l}):5.5} - {m}{n}";
+const ACCESS_JSON_PATTERN: &str = "{m}{n}";
+
+/// Stack of the thread rolling a file, the size of a main thread, see
+/// `OwnStackRoller`.
+const ROLL_STACK_SIZE: usize = 8 * 1024 * 1024;
+
+/// Sets up log4rs, from the `log.config` file if one is given, otherwise
+/// from the `log` settings.
+pub fn init(settings: &LogSettings) -> anyhow::Result<()> {
+    if !settings.config.is_empty() {
+        return log4rs::init_file(&settings.config, Default::default());
+    }
+    log4rs::init_config(config(settings)?)?;
+    Ok(())
+}
+
+/// Application records go to stdout and `log.file`, access records only to
+/// `log.access.file` and, if enabled, stdout.
+pub fn config(settings: &LogSettings) -> anyhow::Result<Config> {
+    let stdout = ConsoleAppender::builder()
+        .encoder(Box::new(PatternEncoder::new(STDOUT_PATTERN)))
+        .build();
+    let mut builder =
+        Config::builder().appender(Appender::builder().build("stdout", Box::new(stdout)));
+    let mut root = Root::builder().appender("stdout");
+
+    if !settings.file.path.is_empty() {
+        let file = file_appender(&settings.file, APP_PATTERN)?;
+        builder = builder.appender(Appender::builder().build("file", file));
+        root = root.appender("file");
+    }
+
+    let access = &settings.access;
+    let mut access_logger = Logger::builder().additive(false);
+    if !access.file.path.is_empty() {
+        let pattern = if access.json {
+            ACCESS_JSON_PATTERN
+        } else {
+            ACCESS_PATTERN
+        };
+        let file = file_appender(&access.file, pattern)?;
+        builder = builder.appender(Appender::builder().build("access", file));
+        access_logger = access_logger.appender("access");
+    }
+    if access.stdout {
+        access_logger = access_logger.appender("stdout");
+    }
+
+    let config = builder
+        .logger(access_logger.build(ACCESS_TARGET, LevelFilter::Info))
+        .build(root.build(settings.level))?;
+    Ok(config)
+}
+
+/// A plain file appender, or a rolling one if any rotation is set.
+fn file_appender(file: &LogFileSettings, default_pattern: &str) -> anyhow::Result<Box<dyn Append>> {
+    let pattern = if file.pattern.is_empty() {
+        default_pattern
+    } else {
+        file.pattern.as_str()
+    };
+    let encoder = Box::new(PatternEncoder::new(pattern));
+
+    let trigger = RotationTrigger::new(file);
+    if trigger.max_size.is_none() && trigger.period.is_none() {
+        return Ok(Box::new(
+            FileAppender::builder().encoder(encoder).build(&file.path)?,
+        ));
+    }
+
+    let roller: Box<dyn Roll> = if file.keep == 0 {
+        Box::new(DeleteRoller::new())
+    } else {
+        let suffix = if file.compress { ".gz" } else { "" };
+        let pattern = format!("{}.{{}}{}", file.path, suffix);
+        let roller = FixedWindowRoller::builder()
+            .base(1)
+            .build(&pattern, file.keep)?;
+        Box::new(OwnStackRoller(Box::new(roller)))
+    };
+    let policy = CompoundPolicy::new(Box::new(trigger), roller);
+    Ok(Box::new(
+        RollingFileAppender::builder()
+            .encoder(encoder)
+            .build(&file.path, Box::new(policy))?,
+    ))
+}
+
+/// Rolls the file once it grows past `max_size` bytes or when a new period
+/// starts, whichever comes first.
+///
+/// log4rs checks the trigger after writing a record, so the first record of
+/// a period ends up at the end of the rotated file.
+#[derive(Debug)]
+struct RotationTrigger {
+    max_size: Option<u64>,
+    /// Length of the period in seconds, periods start at multiples of it
+    /// since the epoch (midnight UTC for `daily`).
+    period: Option<u64>,
+    /// Period the active file was written in.
+    current: Mutex<u64>,
+}
+
+impl RotationTrigger {
+    fn new(file: &LogFileSettings) -> Self {
+        let period = match file.period {
+            RotationPeriod::Never => None,
+            RotationPeriod::Hourly => Some(3600),
+            RotationPeriod::Daily => Some(86400),
+        };
+        // a file left by a previous run belongs to the period it was last written in
+        let current = period.map(|period| {
+            let written = fs::metadata(&file.path)
+                .and_then(|meta| meta.modified())
+                .unwrap_or_else(|_| SystemTime::now());
+            seconds(written) / period
+        });
+
+        RotationTrigger {
+            max_size: match file.max_size {
+                0 => None,
+                mib => Some(mib * 1024 * 1024),
+            },
+            period,
+            current: Mutex::new(current.unwrap_or(0)),
+        }
+    }
+
+    /// Whether the file has grown past `max_size`.
+    fn is_full(&self, len: u64) -> bool {
+        matches!(self.max_size, Some(max) if len > max)
+    }
+
+    /// Whether `now` is in a later period than the last call, which moves
+    /// the current period to it.
+    fn is_new_period(&self, now: SystemTime) -> bool {
+        let period = match self.period {
+            Some(period) => period,
+            None => return false,
+        };
+        let now = seconds(now) / period;
+        let mut current = self.current.lock().unwrap();
+        let started = std::mem::replace(&mut *current, now);
+        started != now
+    }
+}
+
+impl Trigger for RotationTrigger {
+    fn trigger(&self, file: &LogFile) -> anyhow::Result<bool> {
+        Ok(self.is_full(file.len_estimate()) || self.is_new_period(SystemTime::now()))
+    }
+}
+
+fn seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+/// Rolls on a thread of its own, waiting for it.
+///
+/// log4rs rolls on the thread writing the record, usually an actix worker.
+/// Actix spawns them without a stack size, so they get `RUST_MIN_STACK`,
+/// which `server.min_stack_size` lowers to what serving a request needs.
+/// Compressing the rotated file with gzip on top of the frames of a handler
+/// can overflow such a stack, and an overflow aborts the process rather than
+/// failing the roll. A thread with the stack of a main thread doesn't depend
+/// on that setting.
+#[derive(Debug)]
+struct OwnStackRoller(Box<dyn Roll>);
+
+impl Roll for OwnStackRoller {
+    fn roll(&self, file: &Path) -> anyhow::Result<()> {
+        thread::scope(|scope| {
+            thread::Builder::new()
+                .name(String::from("log-roller"))
+                .stack_size(ROLL_STACK_SIZE)
+                .spawn_scoped(scope, || self.0.roll(file))?
+                .join()
+                .unwrap_or_else(|_| Err(anyhow::anyhow!("rolling {} panicked", file.display())))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    const HOUR: u64 = 3600;
+
+    fn trigger(max_size: Option<u64>, period: Option<u64>, current: u64) -> RotationTrigger {
+        RotationTrigger {
+            max_size,
+            period,
+            current: Mutex::new(current),
+        }
+    }
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn rolls_once_past_the_max_size() {
+        let capped = trigger(Some(1024), None, 0);
+        assert!(!capped.is_full(1023));
+        assert!(!capped.is_full(1024));
+        assert!(capped.is_full(1025));
+
+        assert!(!trigger(None, None, 0).is_full(u64::MAX));
+    }
+
+    #[test]
+    fn rolls_once_when_a_period_starts() {
+        let trigger = trigger(None, Some(HOUR), 10);
+        assert!(!trigger.is_new_period(at(10 * HOUR)));
+        assert!(!trigger.is_new_period(at(11 * HOUR - 1)));
+        assert!(trigger.is_new_period(at(11 * HOUR)));
+        assert!(!trigger.is_new_period(at(11 * HOUR + 1)));
+        // a period without records is skipped
+        assert!(trigger.is_new_period(at(13 * HOUR + 5)));
+    }
+
+    #[test]
+    fn never_rolls_without_a_period() {
+        let trigger = trigger(None, None, 0);
+        assert!(!trigger.is_new_period(at(0)));
+        assert!(!trigger.is_new_period(at(100 * HOUR)));
+    }
+}
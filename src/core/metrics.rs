@@ -0,0 +1,247 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Mutex;
+
+use actix_web::http::Method;
+use lazy_static::lazy_static;
+
+use crate::websocket::sessions;
+
+/// Upper bounds in seconds of the request duration histogram buckets.
+const BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Requests being served, from the access logger receiving them until their
+/// body is sent.
+pub static IN_FLIGHT: Gauge = Gauge::new();
+
+lazy_static! {
+    static ref REQUESTS: Mutex<Requests> = Mutex::new(BTreeMap::new());
+}
+
+/// The series by route pattern, then by method and status class.
+type Requests = BTreeMap<String, BTreeMap<(&'static str, &'static str), Series>>;
+
+pub struct Gauge(AtomicI64);
+
+impl Gauge {
+    pub const fn new() -> Self {
+        Gauge(AtomicI64::new(0))
+    }
+
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dec(&self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for Gauge {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, Default)]
+struct Series {
+    buckets: [u64; BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+/// Records a served request.
+///
+/// `route` is the matched pattern (e.g. `/user/{id}`) rather than the path so
+/// that the number of series stays bounded, `None` for unmatched requests.
+pub fn observe(route: Option<&str>, method: &Method, status: u16, seconds: f64) {
+    let mut requests = REQUESTS.lock().unwrap();
+    observe_into(&mut requests, route, method, status, seconds);
+}
+
+fn observe_into(
+    requests: &mut Requests,
+    route: Option<&str>,
+    method: &Method,
+    status: u16,
+    seconds: f64,
+) {
+    let route = route.unwrap_or("unmatched");
+    // the pattern is only copied by the first request of the route
+    if !requests.contains_key(route) {
+        requests.insert(route.to_string(), BTreeMap::new());
+    }
+    let series = requests
+        .get_mut(route)
+        .unwrap()
+        .entry((method_label(method), status_class(status)))
+        .or_default();
+    for (bucket, le) in series.buckets.iter_mut().zip(BUCKETS) {
+        if seconds <= le {
+            *bucket += 1;
+        }
+    }
+    series.sum += seconds;
+    series.count += 1;
+}
+
+/// Every metric in the Prometheus text exposition format (version 0.0.4).
+pub fn render() -> String {
+    let mut out = String::new();
+    render_requests(&REQUESTS.lock().unwrap(), &mut out);
+
+    out.push_str("# HELP http_requests_in_flight Requests being served.\n");
+    out.push_str("# TYPE http_requests_in_flight gauge\n");
+    let _ = writeln!(out, "http_requests_in_flight {}", IN_FLIGHT.get());
+
+    out.push_str("# HELP websocket_sessions Open WebSocket sessions.\n");
+    out.push_str("# TYPE websocket_sessions gauge\n");
+    let _ = writeln!(out, "websocket_sessions {}", sessions::count());
+
+    out
+}
+
+fn render_requests(requests: &Requests, out: &mut String) {
+    out.push_str(
+        "# HELP http_requests_total Requests served, by route pattern, method and status class.\n",
+    );
+    out.push_str("# TYPE http_requests_total counter\n");
+    for (labels, series) in series(requests) {
+        let _ = writeln!(out, "http_requests_total{{{}}} {}", labels, series.count);
+    }
+
+    out.push_str("# HELP http_request_duration_seconds Time from receiving a request until its body is sent.\n");
+    out.push_str("# TYPE http_request_duration_seconds histogram\n");
+    for (labels, series) in series(requests) {
+        for (count, le) in series.buckets.iter().zip(BUCKETS) {
+            let _ = writeln!(
+                out,
+                "http_request_duration_seconds_bucket{{{},le=\"{}\"}} {}",
+                labels, le, count
+            );
+        }
+        let _ = writeln!(
+            out,
+            "http_request_duration_seconds_bucket{{{},le=\"+Inf\"}} {}",
+            labels, series.count
+        );
+        let _ = writeln!(
+            out,
+            "http_request_duration_seconds_sum{{{}}} {}",
+            labels, series.sum
+        );
+        let _ = writeln!(
+            out,
+            "http_request_duration_seconds_count{{{}}} {}",
+            labels, series.count
+        );
+    }
+}
+
+/// Every series with its rendered labels, by route, method and status class.
+fn series(requests: &Requests) -> impl Iterator<Item = (String, &Series)> {
+    requests.iter().flat_map(|(route, by_method)| {
+        by_method.iter().map(move |((method, status), series)| {
+            let labels = format!(
+                "route=\"{}\",method=\"{}\",status=\"{}\"",
+                escape(route),
+                method,
+                status
+            );
+            (labels, series)
+        })
+    })
+}
+
+/// Standard methods only, anything else would let clients create series.
+pub(crate) fn method_label(method: &Method) -> &'static str {
+    match *method {
+        Method::GET => "GET",
+        Method::POST => "POST",
+        Method::PUT => "PUT",
+        Method::DELETE => "DELETE",
+        Method::PATCH => "PATCH",
+        Method::HEAD => "HEAD",
+        Method::OPTIONS => "OPTIONS",
+        Method::CONNECT => "CONNECT",
+        Method::TRACE => "TRACE",
+        _ => "OTHER",
+    }
+}
+
+fn status_class(status: u16) -> &'static str {
+    match status {
+        100..=199 => "1xx",
+        200..=299 => "2xx",
+        300..=399 => "3xx",
+        400..=499 => "4xx",
+        _ => "5xx",
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rendered(observations: &[(&str, f64)]) -> String {
+        let mut requests = Requests::new();
+        for (route, seconds) in observations {
+            observe_into(&mut requests, Some(route), &Method::GET, 200, *seconds);
+        }
+        let mut out = String::new();
+        render_requests(&requests, &mut out);
+        out
+    }
+
+    #[test]
+    fn buckets_are_cumulative() {
+        let out = rendered(&[("/", 0.003), ("/", 0.04), ("/", 0.3), ("/", 20.0)]);
+        let labels = r#"route="/",method="GET",status="2xx""#;
+
+        for (le, count) in [
+            ("0.005", 1),
+            ("0.01", 1),
+            ("0.05", 2),
+            ("0.25", 2),
+            ("0.5", 3),
+            ("10", 3),
+            ("+Inf", 4),
+        ] {
+            let line = format!(
+                "http_request_duration_seconds_bucket{{{},le=\"{}\"}} {}\n",
+                labels, le, count
+            );
+            assert!(out.contains(&line), "{} in\n{}", line, out);
+        }
+        let count = format!("http_request_duration_seconds_count{{{}}} 4\n", labels);
+        assert!(out.contains(&count), "{}", out);
+        let total = format!("http_requests_total{{{}}} 4\n", labels);
+        assert!(out.contains(&total), "{}", out);
+    }
+
+    #[test]
+    fn label_values_are_escaped() {
+        let out = rendered(&[("/a\\b/\"c\"\n", 0.1)]);
+        assert!(
+            out.contains(
+                r#"http_requests_total{route="/a\\b/\"c\"\n",method="GET",status="2xx"} 1"#
+            ),
+            "{}",
+            out
+        );
+    }
+}
@@ -1,2 +1,12 @@
 pub mod bootstrap_server;
 pub mod builtin_handles;
+pub mod health;
+pub mod lifecycle;
+pub mod listeners;
+pub mod logging;
+pub mod metrics;
+pub mod proxy;
+pub mod routes;
+pub mod settings;
+pub mod stats;
+pub mod tls;
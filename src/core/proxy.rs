@@ -0,0 +1,188 @@
+use std::{net::IpAddr, sync::Arc};
+
+use actix_web::{
+    http::header::{self, HeaderMap, HeaderName},
+    HttpRequest,
+};
+use ipnet::IpNet;
+
+use crate::utils::parse::{canonical_ip, parse_ip};
+
+pub const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+pub const X_FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-forwarded-proto");
+pub const X_FORWARDED_HOST: HeaderName = HeaderName::from_static("x-forwarded-host");
+
+/// The reverse proxies whose `Forwarded` and `X-Forwarded-*` headers are
+/// believed, from `server.trusted_proxies`.
+///
+/// Anybody can send these headers, so they are ignored unless the connection
+/// comes from a trusted proxy. Connections on the Unix socket have no address
+/// and are trusted like a proxy.
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies(Arc<Vec<IpNet>>);
+
+impl TrustedProxies {
+    pub fn new(networks: Vec<IpNet>) -> Self {
+        TrustedProxies(Arc::new(networks))
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        self.0.iter().any(|net| net.contains(&ip))
+    }
+
+    /// Whether the connection comes from a trusted proxy.
+    pub fn is_proxied(&self, req: &HttpRequest) -> bool {
+        match req.peer_addr() {
+            Some(peer) => self.contains(canonical_ip(peer.ip())),
+            None => true,
+        }
+    }
+
+    /// The address of the client: the peer of the connection, or the hops of
+    /// `Forwarded`, or `X-Forwarded-For`, walked back from the last one to the
+    /// first address that isn't a trusted proxy. `None` when a hop hides it.
+    pub fn client_addr(&self, req: &HttpRequest) -> Option<IpAddr> {
+        let peer = req.peer_addr().map(|addr| canonical_ip(addr.ip()));
+        if let Some(peer) = peer {
+            if !self.contains(peer) {
+                return Some(peer);
+            }
+        }
+
+        let mut client = peer;
+        for hop in forwarded_hops(req.headers()).into_iter().rev() {
+            client = hop;
+            match hop {
+                Some(ip) if self.contains(ip) => continue,
+                _ => break,
+            }
+        }
+        client
+    }
+
+    /// The scheme the client used, `proto` of `Forwarded` or
+    /// `X-Forwarded-Proto`, when the connection comes from a trusted proxy.
+    pub fn forwarded_proto<'a>(&self, req: &'a HttpRequest) -> Option<&'a str> {
+        if !self.is_proxied(req) {
+            return None;
+        }
+        forwarded_param(req.headers(), "proto")
+            .next()
+            .or_else(|| first_value(req.headers(), X_FORWARDED_PROTO))
+    }
+
+    /// The host the client asked for, `host` of `Forwarded` or
+    /// `X-Forwarded-Host`, when the connection comes from a trusted proxy.
+    pub fn forwarded_host<'a>(&self, req: &'a HttpRequest) -> Option<&'a str> {
+        if !self.is_proxied(req) {
+            return None;
+        }
+        forwarded_param(req.headers(), "host")
+            .next()
+            .or_else(|| first_value(req.headers(), X_FORWARDED_HOST))
+    }
+
+    /// The host the client asked for: the forwarded host behind a trusted
+    /// proxy, `Host` otherwise, or the host of the server without it.
+    pub fn host<'a>(&self, req: &'a HttpRequest) -> &'a str {
+        self.forwarded_host(req)
+            .or_else(|| {
+                req.headers()
+                    .get(header::HOST)
+                    .and_then(|host| host.to_str().ok())
+            })
+            .or_else(|| req.uri().host())
+            .unwrap_or_else(|| req.app_config().host())
+    }
+}
+
+/// The values of the comma separated header `name`.
+fn values(headers: &HeaderMap, name: HeaderName) -> impl Iterator<Item = &str> {
+    headers
+        .get_all(name)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+}
+
+/// The first value of the comma separated header `name`, unless empty.
+fn first_value(headers: &HeaderMap, name: HeaderName) -> Option<&str> {
+    values(headers, name)
+        .next()
+        .filter(|value| !value.is_empty())
+}
+
+/// The `key` parameter of each element of `Forwarded`, unquoted.
+fn forwarded_param<'a>(headers: &'a HeaderMap, key: &'a str) -> impl Iterator<Item = &'a str> {
+    values(headers, header::FORWARDED).filter_map(move |element| {
+        element.split(';').find_map(|pair| {
+            let (k, value) = pair.split_once('=')?;
+            k.trim()
+                .eq_ignore_ascii_case(key)
+                .then(|| value.trim().trim_matches('"'))
+        })
+    })
+}
+
+/// The client and proxy addresses of `Forwarded`, or of `X-Forwarded-For`
+/// without it, the client first. Hidden or malformed hops are `None`.
+fn forwarded_hops(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    let hops: Vec<Option<IpAddr>> = forwarded_param(headers, "for").map(parse_ip).collect();
+    if !hops.is_empty() {
+        return hops;
+    }
+    values(headers, X_FORWARDED_FOR).map(parse_ip).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+
+    use super::*;
+
+    fn proxies() -> TrustedProxies {
+        TrustedProxies::new(vec!["10.0.0.0/24".parse().unwrap()])
+    }
+
+    #[test]
+    fn forwarded_for_is_ignored_from_untrusted_peers() {
+        let req = TestRequest::default()
+            .peer_addr("203.0.113.7:4000".parse().unwrap())
+            .insert_header((X_FORWARDED_FOR, "10.0.0.9"))
+            .to_http_request();
+        assert_eq!(proxies().client_addr(&req), "203.0.113.7".parse().ok());
+    }
+
+    #[test]
+    fn forwarded_for_is_walked_back_through_trusted_proxies() {
+        let req = TestRequest::default()
+            .peer_addr("10.0.0.2:4000".parse().unwrap())
+            .insert_header((X_FORWARDED_FOR, "192.0.2.1, 198.51.100.4, 10.0.0.3"))
+            .to_http_request();
+        assert_eq!(proxies().client_addr(&req), "198.51.100.4".parse().ok());
+
+        let req = TestRequest::default()
+            .peer_addr("10.0.0.2:4000".parse().unwrap())
+            .insert_header((header::FORWARDED, r#"for="[2001:db8::1]:4711";proto=https"#))
+            .to_http_request();
+        assert_eq!(proxies().client_addr(&req), "2001:db8::1".parse().ok());
+    }
+
+    #[test]
+    fn forwarded_proto_and_host_need_a_trusted_proxy() {
+        let forwarded = |peer: &str| {
+            TestRequest::default()
+                .peer_addr(peer.parse().unwrap())
+                .insert_header((X_FORWARDED_PROTO, "https"))
+                .insert_header((X_FORWARDED_HOST, "example.com"))
+                .to_http_request()
+        };
+        let req = forwarded("203.0.113.7:4000");
+        assert_eq!(proxies().forwarded_proto(&req), None);
+        assert_eq!(proxies().forwarded_host(&req), None);
+
+        let req = forwarded("10.0.0.2:4000");
+        assert_eq!(proxies().forwarded_proto(&req), Some("https"));
+        assert_eq!(proxies().forwarded_host(&req), Some("example.com"));
+    }
+}
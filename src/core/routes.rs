@@ -0,0 +1,385 @@
+use std::sync::Arc;
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::{header, Method};
+use actix_web::{
+    guard, web, Error, FromRequest, Handler, HttpRequest, HttpResponse, Resource, Responder, Route,
+};
+use serde::Serialize;
+
+use crate::middlewares::chain::{BoxedService, Chain, MiddlewareFactory};
+
+type RouteFn = Arc<dyn Fn() -> Route + Send + Sync>;
+
+/// Routes sharing a path prefix and middlewares, each declared once with its
+/// methods, path aliases and name.
+///
+/// ```ignore
+/// RouteTable::scope("/developer")
+///     .wrap(AssignRequestId::new)
+///     .route("developer", &[Method::GET], &["", "/index", "/home"], developer)
+///     .route("tls-reload", &[Method::POST], &["/tls/reload"], tls_reload);
+/// ```
+#[derive(Clone, Default)]
+pub struct RouteTable {
+    scope: String,
+    middlewares: Vec<MiddlewareFactory>,
+    routes: Vec<RouteDef>,
+}
+
+#[derive(Clone)]
+struct RouteDef {
+    name: String,
+    methods: Vec<Method>,
+    paths: Vec<String>,
+    target: Target,
+}
+
+#[derive(Clone)]
+enum Target {
+    Handler(RouteFn),
+    Files(String),
+}
+
+/// A registered route, as listed by `/developer/routes`.
+#[derive(Debug, Clone, Serialize)]
+pub struct RouteInfo {
+    pub name: String,
+    /// Empty when the route accepts any method.
+    pub methods: Vec<String>,
+    /// Full paths, the first one is the canonical path used by `url_for`.
+    pub paths: Vec<String>,
+    pub scope: String,
+    /// Middlewares of the scope, the outermost last.
+    pub middlewares: Vec<String>,
+}
+
+impl RouteTable {
+    /// Routes at the root of the application.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Routes under `prefix`, registered as one `web::scope`.
+    pub fn scope(prefix: &str) -> Self {
+        Self {
+            scope: prefix.to_string(),
+            ..Self::default()
+        }
+    }
+
+    /// Wraps a middleware around the routes of this table, like `Scope::wrap`.
+    ///
+    /// As with `Server::wrap`, a factory is passed which is called once per worker.
+    pub fn wrap<F, M, B>(mut self, factory: F) -> Self
+    where
+        F: Fn() -> M + Send + Sync + 'static,
+        M: Transform<
+                BoxedService,
+                ServiceRequest,
+                Response = ServiceResponse<B>,
+                Error = Error,
+                InitError = (),
+            > + 'static,
+        M::Transform: 'static,
+        M::Future: 'static,
+        B: MessageBody + 'static,
+    {
+        self.middlewares.push(MiddlewareFactory::new(factory));
+        self
+    }
+
+    /// Adds a route served by `handler` on each of `paths`.
+    ///
+    /// An empty `methods` accepts any method.
+    pub fn route<F, Args>(
+        mut self,
+        name: &str,
+        methods: &[Method],
+        paths: &[&str],
+        handler: F,
+    ) -> Self
+    where
+        F: Handler<Args> + Send + Sync,
+        Args: FromRequest + 'static,
+        F::Output: Responder + 'static,
+    {
+        let route: RouteFn = Arc::new(move || web::route().to(handler.clone()));
+
+        self.routes.push(RouteDef {
+            name: name.to_string(),
+            methods: methods.to_vec(),
+            paths: paths.iter().map(|p| p.to_string()).collect(),
+            target: Target::Handler(route),
+        });
+        self
+    }
+
+    /// Serves the files under `dir` at `mount`.
+    pub fn files(mut self, name: &str, mount: &str, dir: &str) -> Self {
+        self.routes.push(RouteDef {
+            name: name.to_string(),
+            methods: vec![Method::GET, Method::HEAD],
+            paths: vec![mount.to_string()],
+            target: Target::Files(dir.to_string()),
+        });
+        self
+    }
+
+    pub fn configure(&self, cfg: &mut web::ServiceConfig) {
+        if self.scope.is_empty() {
+            for resource in self.resources() {
+                if self.middlewares.is_empty() {
+                    cfg.service(resource);
+                } else {
+                    cfg.service(resource.wrap(Chain::new(&self.middlewares)));
+                }
+            }
+            for route in &self.routes {
+                if let Target::Files(dir) = &route.target {
+                    cfg.service(actix_files::Files::new(&route.paths[0], dir));
+                }
+            }
+            return;
+        }
+
+        let mut scope = web::scope(&self.scope);
+        for resource in self.resources() {
+            scope = scope.service(resource);
+        }
+        for route in &self.routes {
+            if let Target::Files(dir) = &route.target {
+                scope = scope.service(actix_files::Files::new(&route.paths[0], dir));
+            }
+        }
+        if self.middlewares.is_empty() {
+            cfg.service(scope);
+        } else {
+            cfg.service(scope.wrap(Chain::new(&self.middlewares)));
+        }
+    }
+
+    /// One resource per path, holding the routes of every `RouteDef` served
+    /// on it, so that a request with another method gets a `405` listing the
+    /// methods of the path in `Allow`.
+    ///
+    /// A resource is named after the first route whose canonical path it is,
+    /// so that `url_for` resolves to the canonical path rather than an alias.
+    fn resources(&self) -> Vec<Resource> {
+        let mut resources: Vec<PathRoutes> = Vec::new();
+        for def in &self.routes {
+            let route = match &def.target {
+                Target::Handler(route) => route,
+                Target::Files(_) => continue,
+            };
+
+            for (i, path) in def.paths.iter().enumerate() {
+                let name = if i == 0 { Some(&def.name) } else { None };
+                let at = match resources.iter().position(|known| known.path == *path) {
+                    Some(at) => at,
+                    None => {
+                        resources.push(PathRoutes {
+                            path,
+                            name: None,
+                            methods: Some(vec![]),
+                            routes: vec![],
+                        });
+                        resources.len() - 1
+                    }
+                };
+                resources[at].add(name, &def.methods, def.route(route));
+            }
+        }
+        resources.into_iter().map(PathRoutes::resource).collect()
+    }
+
+    pub fn info(&self) -> Vec<RouteInfo> {
+        let middlewares: Vec<String> = self
+            .middlewares
+            .iter()
+            .map(|m| m.name().to_string())
+            .collect();
+
+        self.routes
+            .iter()
+            .map(|route| RouteInfo {
+                name: route.name.clone(),
+                methods: route.methods.iter().map(|m| m.to_string()).collect(),
+                paths: route
+                    .paths
+                    .iter()
+                    .map(|path| match (self.scope.as_str(), path.as_str()) {
+                        ("", "") => String::from("/"),
+                        (scope, path) => format!("{}{}", scope, path),
+                    })
+                    .collect(),
+                scope: self.scope.clone(),
+                middlewares: middlewares.clone(),
+            })
+            .collect()
+    }
+}
+
+impl RouteDef {
+    /// The route of the handler, guarded by the methods.
+    fn route(&self, route: &RouteFn) -> Route {
+        match self.methods.split_first() {
+            Some((first, rest)) => {
+                let any = rest
+                    .iter()
+                    .fold(guard::Any(guard::Method(first.clone())), |any, m| {
+                        any.or(guard::Method(m.clone()))
+                    });
+                route().guard(any)
+            }
+            None => route(),
+        }
+    }
+}
+
+/// The routes of a table served on one path.
+struct PathRoutes<'a> {
+    path: &'a str,
+    name: Option<&'a String>,
+    /// Methods of the routes, `None` once a route accepts any method.
+    methods: Option<Vec<Method>>,
+    routes: Vec<Route>,
+}
+
+impl<'a> PathRoutes<'a> {
+    fn add(&mut self, name: Option<&'a String>, methods: &[Method], route: Route) {
+        if self.name.is_none() {
+            self.name = name;
+        }
+        self.routes.push(route);
+        match &mut self.methods {
+            Some(_) if methods.is_empty() => self.methods = None,
+            Some(known) => {
+                for method in methods {
+                    if !known.contains(method) {
+                        known.push(method.clone());
+                    }
+                }
+            }
+            None => {}
+        }
+    }
+
+    fn resource(self) -> Resource {
+        let mut resource = web::resource(self.path);
+        if let Some(name) = self.name {
+            resource = resource.name(name);
+        }
+        if let Some(methods) = self.methods {
+            let allow = methods
+                .iter()
+                .map(Method::as_str)
+                .collect::<Vec<_>>()
+                .join(", ");
+            resource = resource.default_service(web::to(move || {
+                let allow = allow.clone();
+                async move {
+                    HttpResponse::MethodNotAllowed()
+                        .insert_header((header::ALLOW, allow))
+                        .finish()
+                }
+            }));
+        }
+        self.routes.into_iter().fold(resource, Resource::route)
+    }
+}
+
+/// The path as the router matches it, percent-decoded except for `%2F`, `%25`
+/// and `%2B`.
+///
+/// Rules matching the paths must use it rather than `req.path()`, or a
+/// request to `/%64eveloper` would be routed to `/developer` without matching
+/// the rules of `/developer`.
+pub fn routed_path(req: &HttpRequest) -> &str {
+    req.match_info().as_str()
+}
+
+/// Every route table registered by the server, shared with the handlers as
+/// `Data<RouteRegistry>`.
+///
+/// Services added with `Server::configure` bypass the registry and aren't listed.
+#[derive(Clone, Default)]
+pub struct RouteRegistry {
+    tables: Vec<RouteTable>,
+}
+
+impl RouteRegistry {
+    pub fn new(tables: Vec<RouteTable>) -> Self {
+        Self { tables }
+    }
+
+    pub fn configure(&self, cfg: &mut web::ServiceConfig) {
+        for table in &self.tables {
+            table.configure(cfg);
+        }
+    }
+
+    pub fn routes(&self) -> Vec<RouteInfo> {
+        self.tables.iter().flat_map(RouteTable::info).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::StatusCode, test, App, HttpResponse};
+
+    use super::*;
+
+    #[actix_web::test]
+    async fn methods_on_the_same_path() {
+        let table = RouteTable::new()
+            .route("get-user", &[Method::GET], &["/user/{id}"], || async {
+                HttpResponse::Ok().body("get")
+            })
+            .route("update-user", &[Method::PUT], &["/user/{id}"], || async {
+                HttpResponse::Ok().body("update")
+            });
+        let app = test::init_service(App::new().configure(|cfg| table.configure(cfg))).await;
+
+        for (method, body) in [(Method::GET, "get"), (Method::PUT, "update")] {
+            let req = test::TestRequest::default()
+                .method(method)
+                .uri("/user/42")
+                .to_request();
+            let res = test::call_service(&app, req).await;
+            assert!(res.status().is_success());
+            assert_eq!(test::read_body(res).await, body);
+        }
+
+        let req = test::TestRequest::delete().uri("/user/42").to_request();
+        let res = test::call_service(&app, req).await;
+        assert!(res.status().is_client_error());
+    }
+
+    #[actix_web::test]
+    async fn other_methods_are_not_allowed() {
+        let table = RouteTable::new()
+            .route("get-user", &[Method::GET], &["/user/{id}"], || async {
+                HttpResponse::Ok().body("get")
+            })
+            .route("update-user", &[Method::PUT], &["/user/{id}"], || async {
+                HttpResponse::Ok().body("update")
+            })
+            .route("users", &[Method::GET], &["/users"], || async {
+                HttpResponse::Ok().body("users")
+            });
+        let app = test::init_service(App::new().configure(|cfg| table.configure(cfg))).await;
+
+        for (uri, allow) in [("/user/42", "GET, PUT"), ("/users", "GET")] {
+            let req = test::TestRequest::delete().uri(uri).to_request();
+            let res = test::call_service(&app, req).await;
+            assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED);
+            assert_eq!(res.headers().get(header::ALLOW).unwrap(), allow);
+        }
+
+        let req = test::TestRequest::get().uri("/none").to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,1606 @@
+use std::{collections::BTreeMap, env, fmt, fs, time::Duration};
+
+use actix_web::http::{header::HeaderName, Method};
+use log::LevelFilter;
+use mongodb::Database;
+use serde::{Deserialize, Serialize};
+use serde_yaml::{Mapping, Value};
+
+use crate::core::bootstrap_server::Module;
+use crate::core::proxy::TrustedProxies;
+use crate::middlewares::access_filter::{Logger, Rule};
+use crate::middlewares::capture::{Capture, Redaction};
+use crate::middlewares::ip_filter::{IpFilter, IpRules};
+use crate::middlewares::rate_limit::{Algorithm, KeyStrategy, Policy, RateLimit, Store};
+use crate::middlewares::recorder::Recorder;
+use crate::repository::rate_limit_repo::MongoBackend;
+use crate::utils;
+
+/// Config file used when neither `--config` nor `APP_CONFIG` is given.
+pub const DEFAULT_CONFIG_FILE: &str = "resources/application.yaml";
+
+/// Prefix of the environment variables that override config keys, e.g.
+/// `APP__SERVER__PORT=8080` overrides `server.port`. It ends with the key
+/// separator so that unrelated variables like `APP_ENV` aren't taken for keys.
+const ENV_PREFIX: &str = "APP__";
+
+/// Runtime configuration of the server.
+///
+/// Values are layered, later sources overriding earlier ones:
+/// built-in defaults, the YAML config file, `APP__*` environment variables
+/// (a `.env` file is honoured) and finally command line flags.
+///
+/// Mappings are merged key by key, so the entries of the default maps, e.g.
+/// the `developer` policy of `rate_limit.policies`, are kept unless the
+/// config file sets them to `~`, which removes them. Any other key set to
+/// `~` gets its default value back.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    pub server: ServerSettings,
+    pub tls: TlsSettings,
+    pub log: LogSettings,
+    pub mongo: MongoSettings,
+    pub health: HealthSettings,
+    pub recorder: RecorderSettings,
+    pub rate_limit: RateLimitSettings,
+    pub ip_filter: IpFilterSettings,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ServerSettings {
+    pub host: String,
+    pub port: u16,
+    /// Bind `host:port` (and `host:tls.port`), turn off to only listen on the
+    /// Unix socket or the sockets passed by systemd.
+    pub tcp: bool,
+    pub unix: UnixSocketSettings,
+    /// Adopt the listening sockets passed by systemd socket activation (`LISTEN_FDS`).
+    pub systemd: bool,
+    pub workers: usize,
+    pub backlog: u32,
+    /// Keep-alive in seconds, `0` disables it.
+    pub keep_alive: u64,
+    /// Minimum stack size of spawned threads in KiB (the std default is 2m).
+    pub min_stack_size: usize,
+    /// Seconds given to in-flight requests to finish once a shutdown signal is received.
+    pub shutdown_timeout: u64,
+    /// Built-in modules that aren't registered, e.g. `[user, mandelbrot]`.
+    pub disabled_modules: Vec<Module>,
+    /// Reverse proxies whose `Forwarded` and `X-Forwarded-*` headers are
+    /// believed, in CIDR notation or addresses.
+    pub trusted_proxies: Vec<String>,
+}
+
+impl Default for ServerSettings {
+    fn default() -> Self {
+        Self {
+            host: String::from("0.0.0.0"),
+            port: 8001,
+            tcp: true,
+            unix: UnixSocketSettings::default(),
+            systemd: true,
+            workers: 1,
+            backlog: 8192,
+            keep_alive: 75,
+            min_stack_size: 384,
+            shutdown_timeout: 30,
+            disabled_modules: vec![],
+            trusted_proxies: vec![],
+        }
+    }
+}
+
+impl ServerSettings {
+    pub fn trusted_proxies(&self) -> TrustedProxies {
+        TrustedProxies::new(
+            self.trusted_proxies
+                .iter()
+                .filter_map(|n| utils::parse::parse_network(n))
+                .collect(),
+        )
+    }
+
+    pub fn keep_alive(&self) -> Option<Duration> {
+        match self.keep_alive {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UnixSocketSettings {
+    /// Path of the socket, empty to not bind one.
+    pub path: String,
+    /// Octal permissions of the socket file.
+    #[serde(deserialize_with = "string_or_number")]
+    pub mode: String,
+    /// User name or uid owning the socket file, empty to keep the current one.
+    pub owner: String,
+    /// Group name or gid owning the socket file, empty to keep the current one.
+    pub group: String,
+}
+
+impl Default for UnixSocketSettings {
+    fn default() -> Self {
+        Self {
+            path: String::new(),
+            mode: String::from("660"),
+            owner: String::new(),
+            group: String::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TlsSettings {
+    pub enable: bool,
+    /// Port of the HTTPS listener.
+    pub port: u16,
+    /// What the plaintext listener on `server.port` does while TLS is enabled.
+    pub http: PlainHttp,
+    /// Status of the redirects to HTTPS, 301 or 308.
+    pub redirect_status: u16,
+    pub hsts: HstsSettings,
+    /// Default private key, used when no SNI entry matches.
+    pub key: String,
+    /// Default certificate chain, used when no SNI entry matches.
+    pub cert: String,
+    /// Seconds between checks of the key, certificate and client CA files
+    /// for changes, `0` disables the watcher.
+    pub reload_interval: u64,
+    /// Certificates selected by the SNI hostname of the client.
+    pub sni: Vec<CertificateSettings>,
+    /// Verification of client certificates (mutual TLS).
+    pub client_auth: ClientAuthSettings,
+}
+
+impl Default for TlsSettings {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            port: 8443,
+            http: PlainHttp::Serve,
+            redirect_status: 308,
+            hsts: HstsSettings::default(),
+            key: String::from("key.pem"),
+            cert: String::from("cert.pem"),
+            reload_interval: 0,
+            sni: vec![],
+            client_auth: ClientAuthSettings::default(),
+        }
+    }
+}
+
+impl TlsSettings {
+    /// Whether a plaintext listener is bound on `server.port`.
+    pub fn http_enabled(&self) -> bool {
+        !self.enable || self.http != PlainHttp::Off
+    }
+
+    /// The default key/certificate pair.
+    pub fn certificate(&self) -> CertificateSettings {
+        CertificateSettings {
+            hostname: String::new(),
+            key: self.key.clone(),
+            cert: self.cert.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PlainHttp {
+    /// Serve the application over plaintext HTTP as well.
+    Serve,
+    /// Answer every plaintext request with a redirect to the HTTPS origin.
+    Redirect,
+    /// Don't bind a plaintext listener.
+    Off,
+}
+
+/// The `Strict-Transport-Security` header added to TLS responses.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HstsSettings {
+    pub enable: bool,
+    /// Seconds browsers should only use HTTPS for this host.
+    pub max_age: u64,
+    pub include_subdomains: bool,
+    pub preload: bool,
+}
+
+impl Default for HstsSettings {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            max_age: 31536000,
+            include_subdomains: false,
+            preload: false,
+        }
+    }
+}
+
+impl HstsSettings {
+    pub fn header_value(&self) -> String {
+        let mut value = format!("max-age={}", self.max_age);
+        if self.include_subdomains {
+            value.push_str("; includeSubDomains");
+        }
+        if self.preload {
+            value.push_str("; preload");
+        }
+        value
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientAuth {
+    /// Client certificates aren't requested.
+    #[default]
+    None,
+    /// A certificate is requested and verified if sent, but not required.
+    Optional,
+    /// The handshake fails unless the client sends a certificate signed by the CA.
+    Required,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ClientAuthSettings {
+    pub mode: ClientAuth,
+    /// PEM bundle of the CAs client certificates are verified against.
+    pub ca: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CertificateSettings {
+    /// `example.com`, or `*.example.com` to match any single label subdomain.
+    pub hostname: String,
+    pub key: String,
+    pub cert: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LogSettings {
+    /// Path of a log4rs config file used instead of the settings below.
+    pub config: String,
+    /// Level of the application log.
+    pub level: LevelFilter,
+    /// Application log file, written besides stdout.
+    pub file: LogFileSettings,
+    pub access: AccessLogSettings,
+}
+
+impl Default for LogSettings {
+    fn default() -> Self {
+        Self {
+            config: String::new(),
+            level: LevelFilter::Info,
+            file: LogFileSettings {
+                path: String::from("/tmp/log/app.log"),
+                ..LogFileSettings::default()
+            },
+            access: AccessLogSettings::default(),
+        }
+    }
+}
+
+/// A log file, rotated once it grows past `max_size` or when a new `period`
+/// starts, whichever comes first.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LogFileSettings {
+    /// Empty to disable the file.
+    pub path: String,
+    /// log4rs pattern of the records, empty for the log's default one.
+    pub pattern: String,
+    /// MiB, `0` disables the size based rotation.
+    pub max_size: u64,
+    pub period: RotationPeriod,
+    /// Rotated files kept, as `<path>.1` (the latest) to `<path>.<keep>`.
+    /// `0` deletes them.
+    pub keep: u32,
+    /// Gzip the rotated files.
+    pub compress: bool,
+}
+
+impl Default for LogFileSettings {
+    fn default() -> Self {
+        Self {
+            path: String::new(),
+            pattern: String::new(),
+            max_size: 100,
+            period: RotationPeriod::Daily,
+            keep: 7,
+            compress: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RotationPeriod {
+    Never,
+    Hourly,
+    Daily,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AccessLogSettings {
+    /// Fields of the access log, see `access_filter::Format`. The client
+    /// certificate subject is appended when `tls.client_auth` is enabled.
+    pub format: String,
+    /// Log one JSON object per request.
+    pub json: bool,
+    /// Access log file. Records are logged with the `access` target, and
+    /// only go to this file and, if `stdout` is set, to stdout.
+    pub file: LogFileSettings,
+    pub stdout: bool,
+    /// Fraction of the responses logged, from 0 to 1, errors and slow
+    /// requests aside.
+    pub sample: f64,
+    /// Milliseconds after which a request is always logged, at WARN. `0`
+    /// disables it.
+    pub slow: u64,
+    /// Always log 4xx and 5xx responses.
+    pub errors: bool,
+    /// Overrides for the paths matching a regex, the first match wins.
+    pub paths: Vec<AccessRuleSettings>,
+    pub capture: CaptureSettings,
+}
+
+impl Default for AccessLogSettings {
+    fn default() -> Self {
+        Self {
+            format: String::from("%{r}a \"%r\" %s %b %D %L"),
+            json: false,
+            file: LogFileSettings {
+                path: String::from("/tmp/log/access.log"),
+                ..LogFileSettings::default()
+            },
+            stdout: true,
+            sample: 1.0,
+            slow: 0,
+            errors: true,
+            paths: vec![],
+            capture: CaptureSettings::default(),
+        }
+    }
+}
+
+impl AccessLogSettings {
+    pub fn rule(&self) -> Rule {
+        Rule {
+            sample: self.sample,
+            slow: match self.slow {
+                0 => None,
+                ms => Some(Duration::from_millis(ms)),
+            },
+            errors: self.errors,
+        }
+    }
+
+    /// The path rules, the keys they don't set are inherited from `rule`.
+    pub fn path_rules(&self) -> Vec<(String, Rule)> {
+        let default = self.rule();
+        self.paths
+            .iter()
+            .map(|path| {
+                let rule = Rule {
+                    sample: path.sample.unwrap_or(default.sample),
+                    slow: match path.slow {
+                        Some(0) => None,
+                        Some(ms) => Some(Duration::from_millis(ms)),
+                        None => default.slow,
+                    },
+                    errors: path.errors.unwrap_or(default.errors),
+                };
+                (path.path.clone(), rule)
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AccessRuleSettings {
+    /// Regex matched against the request path.
+    pub path: String,
+    pub sample: Option<f64>,
+    pub slow: Option<u64>,
+    pub errors: Option<bool>,
+}
+
+/// Request and response bodies added to the access log, for debugging.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CaptureSettings {
+    pub enable: bool,
+    /// Regexes of the paths always captured.
+    pub paths: Vec<String>,
+    /// Requests carrying this header are captured too, empty to disable it.
+    pub header: String,
+    /// Networks the header is accepted from, the client address taken from
+    /// the forwarded headers of `server.trusted_proxies`.
+    pub header_networks: Vec<String>,
+    /// Bytes of each body kept.
+    pub max_body: usize,
+    /// Headers redacted besides `Authorization`, `Proxy-Authorization`,
+    /// `Cookie`, `Set-Cookie` and `X-Api-Key`.
+    pub redact_headers: Vec<String>,
+    /// JSON and form fields redacted, at any depth.
+    pub redact_fields: Vec<String>,
+}
+
+impl Default for CaptureSettings {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            paths: vec![],
+            header: String::from("x-debug-capture"),
+            header_networks: internal_networks(),
+            max_body: 4096,
+            redact_headers: vec![],
+            redact_fields: vec![
+                String::from("password"),
+                String::from("token"),
+                String::from("secret"),
+            ],
+        }
+    }
+}
+
+impl CaptureSettings {
+    /// `None` when disabled.
+    pub fn capture(&self, proxies: TrustedProxies) -> Option<Capture> {
+        if !self.enable {
+            return None;
+        }
+
+        let mut capture = Capture::new(self.max_body).trusted_proxies(proxies);
+        for path in &self.paths {
+            capture = capture.path(path.as_str());
+        }
+        if let Ok(header) = HeaderName::try_from(self.header.as_str()) {
+            capture = capture.header(header);
+        }
+        for network in self
+            .header_networks
+            .iter()
+            .filter_map(|n| utils::parse::parse_network(n))
+        {
+            capture = capture.header_network(network);
+        }
+        for header in &self.redact_headers {
+            if let Ok(header) = HeaderName::try_from(header.as_str()) {
+                capture = capture.redact_header(header);
+            }
+        }
+        for field in &self.redact_fields {
+            capture = capture.redact_field(field.as_str());
+        }
+        Some(capture)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MongoSettings {
+    pub uri: String,
+    pub database: String,
+}
+
+impl Default for MongoSettings {
+    fn default() -> Self {
+        Self {
+            uri: String::from("mongodb://127.0.0.1/golangDB?retryWrites=true&w=majority"),
+            database: String::from("rustDB"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HealthSettings {
+    /// Milliseconds after which a check is reported down.
+    pub timeout: u64,
+    /// Free space in MiB required in the temp dir (where `/mandelbrot` renders).
+    pub min_free_disk: u64,
+}
+
+impl Default for HealthSettings {
+    fn default() -> Self {
+        Self {
+            timeout: 2000,
+            min_free_disk: 100,
+        }
+    }
+}
+
+/// Traffic recorded for the `replay` binary.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RecorderSettings {
+    pub enable: bool,
+    /// JSONL file the requests are appended to.
+    pub path: String,
+    /// Regexes of the paths recorded, every path when empty.
+    pub paths: Vec<String>,
+    /// Regexes of the paths not recorded.
+    pub exclude: Vec<String>,
+    /// Bytes of each request body kept.
+    pub max_body: usize,
+    /// Headers whose values are redacted, the credentials by default.
+    pub redact_headers: Vec<String>,
+    /// JSON and form fields redacted, at any depth. JSON bodies are then
+    /// recorded without their whitespace.
+    pub redact_fields: Vec<String>,
+}
+
+impl Default for RecorderSettings {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            path: String::from("/tmp/log/traffic.jsonl"),
+            paths: vec![],
+            exclude: vec![
+                String::from("^/static"),
+                String::from("^/metrics$"),
+                String::from("^/(healthz|readyz)$"),
+            ],
+            max_body: 64 * 1024,
+            redact_headers: vec![
+                String::from("authorization"),
+                String::from("proxy-authorization"),
+                String::from("cookie"),
+                String::from("x-api-key"),
+            ],
+            redact_fields: vec![],
+        }
+    }
+}
+
+impl RecorderSettings {
+    /// A disabled recorder unless `enable` is set.
+    pub fn recorder(&self) -> std::io::Result<Recorder> {
+        if !self.enable {
+            return Ok(Recorder::disabled());
+        }
+
+        let mut recorder = Recorder::open(&self.path)?.max_body(self.max_body);
+        for path in &self.paths {
+            recorder = recorder.path(path.as_str());
+        }
+        for path in &self.exclude {
+            recorder = recorder.exclude(path.as_str());
+        }
+        let mut redaction = Redaction::default();
+        for header in &self.redact_headers {
+            if let Ok(header) = HeaderName::try_from(header.as_str()) {
+                redaction = redaction.header(header);
+            }
+        }
+        for field in &self.redact_fields {
+            redaction = redaction.field(field.as_str());
+        }
+        Ok(recorder.redaction(redaction))
+    }
+}
+
+/// The loopback and private networks, allowed by default where only the
+/// internal clients should get in.
+fn internal_networks() -> Vec<String> {
+    [
+        "127.0.0.0/8",
+        "10.0.0.0/8",
+        "172.16.0.0/12",
+        "192.168.0.0/16",
+        "::1",
+        "fc00::/7",
+    ]
+    .iter()
+    .map(|net| net.to_string())
+    .collect()
+}
+
+/// Named rate limit policies and the requests they apply to.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RateLimitSettings {
+    pub enable: bool,
+    pub policies: BTreeMap<String, PolicySettings>,
+    /// Requests limited by a policy, the first matching rule wins.
+    pub rules: Vec<LimitRuleSettings>,
+    pub bypass: BypassSettings,
+    /// Don't count the requests answered with a 5xx.
+    pub rollback_server_errors: bool,
+    pub store: RateLimitStore,
+    pub mongo: RateLimitMongoSettings,
+}
+
+impl Default for RateLimitSettings {
+    fn default() -> Self {
+        let mut policies = BTreeMap::new();
+        policies.insert(
+            String::from("developer"),
+            PolicySettings {
+                window: 1,
+                limit: 5,
+                ..PolicySettings::default()
+            },
+        );
+        Self {
+            enable: true,
+            policies,
+            rules: vec![LimitRuleSettings {
+                policy: String::from("developer"),
+                methods: vec![],
+                path: String::from("^/developer(/|$)"),
+            }],
+            bypass: BypassSettings::default(),
+            rollback_server_errors: true,
+            store: RateLimitStore::Memory,
+            mongo: RateLimitMongoSettings::default(),
+        }
+    }
+}
+
+impl RateLimitSettings {
+    /// The rules with their policy, the counters being kept by `backend`.
+    pub fn rate_limit(&self, backend: Store, proxies: TrustedProxies) -> RateLimit<Store> {
+        let mut rate_limit = RateLimit::new(backend)
+            .trusted_proxies(proxies)
+            .rollback_server_errors(self.rollback_server_errors);
+        if !self.enable {
+            return rate_limit;
+        }
+        for rule in &self.rules {
+            let policy = match self.policies.get(&rule.policy) {
+                Some(policy) => policy,
+                None => continue,
+            };
+            let methods: Vec<Method> = rule
+                .methods
+                .iter()
+                .filter_map(|m| Method::from_bytes(m.to_uppercase().as_bytes()).ok())
+                .collect();
+            rate_limit = rate_limit.rule(policy.policy(&rule.policy), &methods, &rule.path);
+        }
+        for network in self
+            .bypass
+            .networks
+            .iter()
+            .filter_map(|n| utils::parse::parse_network(n))
+        {
+            rate_limit = rate_limit.bypass_network(network);
+        }
+        for key in &self.bypass.keys {
+            rate_limit = rate_limit.bypass_key(key);
+        }
+        rate_limit
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PolicySettings {
+    /// Seconds of the window.
+    pub window: u64,
+    /// Requests allowed per key within a window, or tokens added to the
+    /// bucket per window.
+    pub limit: u64,
+    pub algorithm: Algorithm,
+    /// Tokens of a full bucket for `token_bucket`, `limit` when `0`.
+    pub burst: u64,
+    pub key: KeyStrategy,
+    /// API keys or header values counted on their own for the `api_key` and
+    /// `header` keys, the others are counted by address.
+    pub keys: Vec<String>,
+    /// Add the `RateLimit-*` headers to the responses.
+    pub headers: bool,
+}
+
+impl Default for PolicySettings {
+    fn default() -> Self {
+        Self {
+            window: 60,
+            limit: 60,
+            algorithm: Algorithm::FixedWindow,
+            burst: 0,
+            key: KeyStrategy::Ip,
+            keys: vec![],
+            headers: true,
+        }
+    }
+}
+
+impl PolicySettings {
+    pub fn policy(&self, name: &str) -> Policy {
+        Policy {
+            name: name.to_string(),
+            window: Duration::from_secs(self.window),
+            limit: self.limit,
+            algorithm: self.algorithm,
+            burst: if self.burst == 0 {
+                self.limit
+            } else {
+                self.burst
+            },
+            key: self.key.clone(),
+            keys: self.keys.iter().cloned().collect(),
+            headers: self.headers,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LimitRuleSettings {
+    /// Name of the policy in `rate_limit.policies`.
+    pub policy: String,
+    /// Methods limited, any when empty.
+    pub methods: Vec<String>,
+    /// Regex matched against the request path.
+    pub path: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RateLimitStore {
+    /// Counters of each instance, the limits multiply with the replicas.
+    #[default]
+    Memory,
+    /// Counters shared by the instances in the `mongo` database, kept in
+    /// memory while it is unavailable.
+    Mongo,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RateLimitMongoSettings {
+    pub collection: String,
+    /// Milliseconds to wait for MongoDB before counting the request in memory.
+    pub timeout: u64,
+    /// Seconds the counters are kept in memory after MongoDB failed.
+    pub retry: u64,
+}
+
+impl Default for RateLimitMongoSettings {
+    fn default() -> Self {
+        Self {
+            collection: String::from("RateLimit"),
+            timeout: 200,
+            retry: 30,
+        }
+    }
+}
+
+impl RateLimitMongoSettings {
+    pub fn backend(&self, db: &Database) -> MongoBackend {
+        MongoBackend::new(
+            db,
+            &self.collection,
+            Duration::from_millis(self.timeout),
+            Duration::from_secs(self.retry),
+        )
+    }
+}
+
+/// Clients never rate limited.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BypassSettings {
+    /// Networks in CIDR notation, or addresses, e.g. `10.0.0.0/8`.
+    pub networks: Vec<String>,
+    /// API keys, principals, header values or path parameters, compared with
+    /// what the policy of the request counts by.
+    pub keys: Vec<String>,
+}
+
+/// Networks allowed or denied per path prefix, the client address taken
+/// behind `server.trusted_proxies`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct IpFilterSettings {
+    pub enable: bool,
+    /// Networks of each scope, by path prefix, e.g. `/developer`.
+    pub scopes: BTreeMap<String, IpRulesSettings>,
+}
+
+impl Default for IpFilterSettings {
+    fn default() -> Self {
+        let mut scopes = BTreeMap::new();
+        for scope in ["/developer", "/metrics"] {
+            scopes.insert(
+                String::from(scope),
+                IpRulesSettings {
+                    allow: internal_networks(),
+                    deny: vec![],
+                },
+            );
+        }
+        Self {
+            enable: true,
+            scopes,
+        }
+    }
+}
+
+impl IpFilterSettings {
+    /// The filter of the scopes, letting every request through when disabled.
+    pub fn ip_filter(&self, proxies: TrustedProxies) -> IpFilter {
+        let mut filter = IpFilter::new().trusted_proxies(proxies);
+        if !self.enable {
+            return filter;
+        }
+        for (scope, rules) in &self.scopes {
+            let mut ip_rules = IpRules::default();
+            for network in rules
+                .allow
+                .iter()
+                .filter_map(|n| utils::parse::parse_network(n))
+            {
+                ip_rules = ip_rules.allow(network);
+            }
+            for network in rules
+                .deny
+                .iter()
+                .filter_map(|n| utils::parse::parse_network(n))
+            {
+                ip_rules = ip_rules.deny(network);
+            }
+            filter = filter.scope(scope, ip_rules);
+        }
+        filter
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct IpRulesSettings {
+    /// Networks in CIDR notation, or addresses, any when empty.
+    pub allow: Vec<String>,
+    /// Networks denied, even when allowed.
+    pub deny: Vec<String>,
+}
+
+#[derive(Debug)]
+pub enum SettingsError {
+    /// The config file could not be read.
+    Io(String, std::io::Error),
+    /// The config file, an override or the merged result is not valid.
+    Parse(String, serde_yaml::Error),
+    /// Unknown or malformed command line argument.
+    Args(String),
+    /// `--help` was given, the caller prints `Settings::usage` and exits.
+    Help,
+    /// The merged settings failed validation.
+    Invalid(Vec<String>),
+}
+
+impl fmt::Display for SettingsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SettingsError::Io(path, err) => {
+                write!(f, "couldn't read config file {}: {}", path, err)
+            }
+            SettingsError::Parse(source, err) => write!(f, "couldn't parse {}: {}", source, err),
+            SettingsError::Args(msg) => write!(f, "invalid argument: {}", msg),
+            SettingsError::Help => f.write_str(Settings::usage()),
+            SettingsError::Invalid(problems) => {
+                write!(f, "invalid settings:")?;
+                for problem in problems {
+                    write!(f, "\n  - {}", problem)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for SettingsError {}
+
+impl Settings {
+    /// Loads the settings from the process environment, leaving the command
+    /// line to the application embedding the server.
+    pub fn load() -> Result<Settings, SettingsError> {
+        dotenv::dotenv().ok();
+        Settings::load_from(std::iter::empty(), env::vars())
+    }
+
+    /// Loads the settings from the process environment and arguments, for a
+    /// binary whose command line is the one of `Settings::usage`.
+    pub fn load_with_args() -> Result<Settings, SettingsError> {
+        dotenv::dotenv().ok();
+        Settings::load_from(env::args().skip(1), env::vars())
+    }
+
+    /// Loads the settings from the given arguments (without the program name)
+    /// and environment variables.
+    pub fn load_from<A, E>(args: A, vars: E) -> Result<Settings, SettingsError>
+    where
+        A: IntoIterator<Item = String>,
+        E: IntoIterator<Item = (String, String)>,
+    {
+        let vars: Vec<(String, String)> = vars.into_iter().collect();
+        let args = Args::parse(args)?;
+
+        let mut merged = serde_yaml::to_value(Settings::default())
+            .map_err(|e| SettingsError::Parse(String::from("defaults"), e))?;
+
+        // config file
+        let explicit = args.config.clone().or_else(|| {
+            vars.iter()
+                .find(|(k, _)| k == "APP_CONFIG")
+                .map(|(_, v)| v.clone())
+        });
+        let file = explicit
+            .clone()
+            .unwrap_or_else(|| String::from(DEFAULT_CONFIG_FILE));
+        if explicit.is_some() || utils::file::file_exists(&file) {
+            let content =
+                fs::read_to_string(&file).map_err(|e| SettingsError::Io(file.clone(), e))?;
+            let value: Value = serde_yaml::from_str(&content)
+                .map_err(|e| SettingsError::Parse(file.clone(), e))?;
+            merge(&mut merged, value);
+        }
+
+        // environment variables
+        for (key, val) in &vars {
+            if key == "APP_CONFIG" || !key.starts_with(ENV_PREFIX) {
+                continue;
+            }
+            let path = key[ENV_PREFIX.len()..].to_lowercase().replace("__", ".");
+            set_path(&mut merged, &path, val);
+        }
+
+        // command line flags
+        for (path, val) in &args.overrides {
+            set_path(&mut merged, path, val);
+        }
+
+        let settings: Settings = serde_yaml::from_value(merged.clone())
+            .map_err(|e| SettingsError::Parse(String::from("settings"), e))?;
+
+        // the keys dropped by the round trip are unknown, e.g. misspelled
+        let known = serde_yaml::to_value(&settings)
+            .map_err(|e| SettingsError::Parse(String::from("settings"), e))?;
+        let mut problems = Vec::new();
+        unknown_keys(&merged, &known, "", &mut problems);
+        match settings.validate() {
+            Ok(()) => {}
+            Err(SettingsError::Invalid(invalid)) => problems.extend(invalid),
+            Err(err) => return Err(err),
+        }
+        if problems.is_empty() {
+            Ok(settings)
+        } else {
+            Err(SettingsError::Invalid(problems))
+        }
+    }
+
+    /// Checks the merged settings, reporting every problem at once.
+    pub fn validate(&self) -> Result<(), SettingsError> {
+        let mut problems = Vec::new();
+
+        if self.server.host.is_empty() {
+            problems.push(String::from("server.host must not be empty"));
+        }
+        if self.server.port == 0 {
+            problems.push(String::from("server.port must be between 1 and 65535"));
+        }
+        if !self.server.tcp && self.server.unix.path.is_empty() && !self.server.systemd {
+            problems.push(String::from(
+                "no listener: enable server.tcp, server.systemd or set server.unix.path",
+            ));
+        }
+        if u32::from_str_radix(&self.server.unix.mode, 8).is_err() {
+            problems.push(format!(
+                "server.unix.mode must be octal, e.g. 660, got {}",
+                self.server.unix.mode
+            ));
+        }
+        if self.server.workers == 0 {
+            problems.push(String::from("server.workers must be at least 1"));
+        }
+        if self.server.backlog == 0 {
+            problems.push(String::from("server.backlog must be at least 1"));
+        }
+        if self.server.min_stack_size < 64 {
+            problems.push(String::from(
+                "server.min_stack_size must be at least 64 (KiB)",
+            ));
+        }
+        if self.tls.enable {
+            if self.tls.port == 0 {
+                problems.push(String::from("tls.port must be between 1 and 65535"));
+            }
+            if self.tls.http != PlainHttp::Off && self.tls.port == self.server.port {
+                problems.push(String::from(
+                    "tls.port must differ from server.port unless tls.http is off",
+                ));
+            }
+            if self.tls.redirect_status != 301 && self.tls.redirect_status != 308 {
+                problems.push(String::from("tls.redirect_status must be 301 or 308"));
+            }
+            if !utils::file::file_exists(&self.tls.key) {
+                problems.push(format!("tls.key file not found: {}", self.tls.key));
+            }
+            if !utils::file::file_exists(&self.tls.cert) {
+                problems.push(format!("tls.cert file not found: {}", self.tls.cert));
+            }
+            if self.tls.client_auth.mode != ClientAuth::None
+                && !utils::file::file_exists(&self.tls.client_auth.ca)
+            {
+                problems.push(format!(
+                    "tls.client_auth.ca file not found: {}",
+                    self.tls.client_auth.ca
+                ));
+            }
+            for (i, cert) in self.tls.sni.iter().enumerate() {
+                if cert.hostname.is_empty() {
+                    problems.push(format!("tls.sni[{}].hostname must not be empty", i));
+                }
+                if !utils::file::file_exists(&cert.key) {
+                    problems.push(format!("tls.sni[{}].key file not found: {}", i, cert.key));
+                }
+                if !utils::file::file_exists(&cert.cert) {
+                    problems.push(format!("tls.sni[{}].cert file not found: {}", i, cert.cert));
+                }
+            }
+        }
+        if !self.log.config.is_empty() && !utils::file::file_exists(&self.log.config) {
+            problems.push(format!("log.config file not found: {}", self.log.config));
+        }
+        if !self.log.file.path.is_empty() && self.log.file.path == self.log.access.file.path {
+            problems.push(String::from(
+                "log.file.path and log.access.file.path must be different files",
+            ));
+        }
+        if self.log.access.format.trim().is_empty() {
+            problems.push(String::from("log.access.format must not be empty"));
+        } else if let Err(err) = Logger::try_new(&self.log.access.format) {
+            problems.push(format!("log.access.format {}", err));
+        }
+        if !(0.0..=1.0).contains(&self.log.access.sample) {
+            problems.push(String::from("log.access.sample must be between 0 and 1"));
+        }
+        for (i, path) in self.log.access.paths.iter().enumerate() {
+            if let Err(err) = regex::Regex::new(&path.path) {
+                problems.push(format!(
+                    "log.access.paths[{}].path is not a regex: {}",
+                    i, err
+                ));
+            }
+            if let Some(sample) = path.sample.filter(|s| !(0.0..=1.0).contains(s)) {
+                problems.push(format!(
+                    "log.access.paths[{}].sample must be between 0 and 1, got {}",
+                    i, sample
+                ));
+            }
+        }
+        let capture = &self.log.access.capture;
+        for (i, path) in capture.paths.iter().enumerate() {
+            if let Err(err) = regex::Regex::new(path) {
+                problems.push(format!(
+                    "log.access.capture.paths[{}] is not a regex: {}",
+                    i, err
+                ));
+            }
+        }
+        if !capture.header.is_empty() && HeaderName::try_from(capture.header.as_str()).is_err() {
+            problems.push(format!(
+                "log.access.capture.header is not a header name: {}",
+                capture.header
+            ));
+        }
+        for (key, headers) in [
+            ("log.access.capture", &capture.redact_headers),
+            ("recorder", &self.recorder.redact_headers),
+        ] {
+            for header in headers {
+                if HeaderName::try_from(header.as_str()).is_err() {
+                    problems.push(format!(
+                        "{}.redact_headers has an invalid header name: {}",
+                        key, header
+                    ));
+                }
+            }
+        }
+        for (key, patterns) in [
+            ("paths", &self.recorder.paths),
+            ("exclude", &self.recorder.exclude),
+        ] {
+            for (i, pattern) in patterns.iter().enumerate() {
+                if let Err(err) = regex::Regex::new(pattern) {
+                    problems.push(format!("recorder.{}[{}] is not a regex: {}", key, i, err));
+                }
+            }
+        }
+        if self.recorder.enable && self.recorder.path.is_empty() {
+            problems.push(String::from("recorder.path must not be empty"));
+        }
+        for (name, policy) in &self.rate_limit.policies {
+            if policy.window == 0 {
+                problems.push(format!(
+                    "rate_limit.policies.{}.window must be greater than 0",
+                    name
+                ));
+            }
+            if policy.algorithm == Algorithm::TokenBucket && policy.limit == 0 {
+                problems.push(format!(
+                    "rate_limit.policies.{}.limit must be greater than 0 to refill the bucket",
+                    name
+                ));
+            }
+            if let KeyStrategy::Header(header) = &policy.key {
+                if HeaderName::try_from(header.as_str()).is_err() {
+                    problems.push(format!(
+                        "rate_limit.policies.{}.key is not a header name: {}",
+                        name, header
+                    ));
+                }
+            }
+            if matches!(policy.key, KeyStrategy::ApiKey | KeyStrategy::Header(_))
+                && policy.keys.is_empty()
+            {
+                problems.push(format!(
+                    "rate_limit.policies.{}.keys must list the keys counted on their own",
+                    name
+                ));
+            }
+        }
+        for (i, rule) in self.rate_limit.rules.iter().enumerate() {
+            if !self.rate_limit.policies.contains_key(&rule.policy) {
+                problems.push(format!(
+                    "rate_limit.rules[{}].policy is not a policy: {}",
+                    i, rule.policy
+                ));
+            }
+            for method in &rule.methods {
+                if Method::from_bytes(method.to_uppercase().as_bytes()).is_err() {
+                    problems.push(format!(
+                        "rate_limit.rules[{}].methods has an invalid method: {}",
+                        i, method
+                    ));
+                }
+            }
+            match regex::Regex::new(&rule.path) {
+                Ok(path) => {
+                    let param = match self.rate_limit.policies.get(&rule.policy) {
+                        Some(PolicySettings {
+                            key: KeyStrategy::Param(param),
+                            ..
+                        }) => param,
+                        _ => continue,
+                    };
+                    if !path.capture_names().any(|name| name == Some(param)) {
+                        problems.push(format!(
+                            "rate_limit.rules[{}].path has no group named {} for its policy",
+                            i, param
+                        ));
+                    }
+                }
+                Err(err) => problems.push(format!(
+                    "rate_limit.rules[{}].path is not a regex: {}",
+                    i, err
+                )),
+            }
+        }
+        if self.rate_limit.mongo.collection.is_empty() {
+            problems.push(String::from(
+                "rate_limit.mongo.collection must not be empty",
+            ));
+        }
+        if self.rate_limit.mongo.timeout == 0 {
+            problems.push(String::from(
+                "rate_limit.mongo.timeout must be greater than 0",
+            ));
+        }
+        for network in &self.rate_limit.bypass.networks {
+            if utils::parse::parse_network(network).is_none() {
+                problems.push(format!(
+                    "rate_limit.bypass.networks has an invalid network: {}",
+                    network
+                ));
+            }
+        }
+        for network in &self.log.access.capture.header_networks {
+            if utils::parse::parse_network(network).is_none() {
+                problems.push(format!(
+                    "log.access.capture.header_networks has an invalid network: {}",
+                    network
+                ));
+            }
+        }
+        for network in &self.server.trusted_proxies {
+            if utils::parse::parse_network(network).is_none() {
+                problems.push(format!(
+                    "server.trusted_proxies has an invalid network: {}",
+                    network
+                ));
+            }
+        }
+        for (scope, rules) in &self.ip_filter.scopes {
+            if !scope.starts_with('/') {
+                problems.push(format!(
+                    "ip_filter.scopes.{} must be a path prefix starting with /",
+                    scope
+                ));
+            }
+            for (key, networks) in [("allow", &rules.allow), ("deny", &rules.deny)] {
+                for network in networks {
+                    if utils::parse::parse_network(network).is_none() {
+                        problems.push(format!(
+                            "ip_filter.scopes.{}.{} has an invalid network: {}",
+                            scope, key, network
+                        ));
+                    }
+                }
+            }
+        }
+        if !self.mongo.uri.starts_with("mongodb://")
+            && !self.mongo.uri.starts_with("mongodb+srv://")
+        {
+            problems.push(String::from(
+                "mongo.uri must start with mongodb:// or mongodb+srv://",
+            ));
+        }
+        if self.mongo.database.is_empty() {
+            problems.push(String::from("mongo.database must not be empty"));
+        }
+        if self.health.timeout == 0 {
+            problems.push(String::from("health.timeout must be greater than 0"));
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(SettingsError::Invalid(problems))
+        }
+    }
+
+    /// Usage text for the supported command line flags.
+    pub fn usage() -> &'static str {
+        "Usage: rs-starter [OPTIONS]\n\
+         \n\
+         Options:\n\
+         \x20 -c, --config <FILE>     config file (default: resources/application.yaml, env: APP_CONFIG)\n\
+         \x20     --host <HOST>       listen address (server.host)\n\
+         \x20 -p, --port <PORT>       listen port (server.port)\n\
+         \x20     --workers <N>       number of workers (server.workers)\n\
+         \x20     --tls               enable tls (tls.enable)\n\
+         \x20     --set <KEY=VALUE>   override any key, e.g. --set mongo.database=test\n\
+         \x20 -h, --help              print this help\n\
+         \n\
+         Every key can also be set with an environment variable, e.g. APP__SERVER__PORT=8080\n\
+         Unknown keys, from the config file, APP__* variables or --set, are refused."
+    }
+}
+
+/// Accepts `660` as well as `"660"`, YAML reads unquoted digits as a number.
+fn string_or_number<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    match Value::deserialize(deserializer)? {
+        Value::String(s) => Ok(s),
+        Value::Number(n) => Ok(n.to_string()),
+        other => Err(serde::de::Error::custom(format!(
+            "expected a string or a number, got {:?}",
+            other
+        ))),
+    }
+}
+
+#[derive(Debug, Default)]
+struct Args {
+    config: Option<String>,
+    overrides: Vec<(String, String)>,
+}
+
+impl Args {
+    fn parse<A: IntoIterator<Item = String>>(args: A) -> Result<Args, SettingsError> {
+        let mut parsed = Args::default();
+        let mut iter = args.into_iter();
+
+        while let Some(arg) = iter.next() {
+            let (flag, inline) = match arg.split_once('=') {
+                Some((flag, val)) if flag.starts_with("--") => {
+                    (flag.to_string(), Some(val.to_string()))
+                }
+                _ => (arg.clone(), None),
+            };
+            let mut value = |name: &str| {
+                inline
+                    .clone()
+                    .or_else(|| iter.next())
+                    .ok_or_else(|| SettingsError::Args(format!("{} requires a value", name)))
+            };
+
+            match flag.as_str() {
+                "-c" | "--config" => parsed.config = Some(value(&flag)?),
+                "--host" => parsed
+                    .overrides
+                    .push((String::from("server.host"), value(&flag)?)),
+                "-p" | "--port" => parsed
+                    .overrides
+                    .push((String::from("server.port"), value(&flag)?)),
+                "--workers" => parsed
+                    .overrides
+                    .push((String::from("server.workers"), value(&flag)?)),
+                "--tls" => parsed
+                    .overrides
+                    .push((String::from("tls.enable"), String::from("true"))),
+                "--set" => {
+                    let pair = value(&flag)?;
+                    match pair.split_once('=') {
+                        Some((key, val)) => {
+                            parsed.overrides.push((key.to_string(), val.to_string()))
+                        }
+                        None => {
+                            return Err(SettingsError::Args(format!(
+                                "--set expects KEY=VALUE, got {}",
+                                pair
+                            )))
+                        }
+                    }
+                }
+                "-h" | "--help" => return Err(SettingsError::Help),
+                _ => return Err(SettingsError::Args(format!("unknown argument {}", arg))),
+            }
+        }
+
+        Ok(parsed)
+    }
+}
+
+/// Deep merges `overlay` into `base`, mappings are merged key by key, the
+/// keys set to null are removed and any other value replaces the one in
+/// `base`.
+fn merge(base: &mut Value, overlay: Value) {
+    match (base, overlay) {
+        (Value::Mapping(base), Value::Mapping(overlay)) => {
+            for (key, val) in overlay {
+                if val.is_null() {
+                    base.remove(&key);
+                    continue;
+                }
+                match base.get_mut(&key) {
+                    Some(existing) => merge(existing, val),
+                    None => {
+                        base.insert(key, val);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+/// Reports the keys of `given` missing from `known`, by dotted path.
+fn unknown_keys(given: &Value, known: &Value, path: &str, problems: &mut Vec<String>) {
+    match (given, known) {
+        (Value::Mapping(given), Value::Mapping(known)) => {
+            for (key, val) in given {
+                let key_path = match key.as_str() {
+                    Some(key) if path.is_empty() => key.to_string(),
+                    Some(key) => format!("{}.{}", path, key),
+                    None => continue,
+                };
+                match known.get(key) {
+                    Some(known) => unknown_keys(val, known, &key_path, problems),
+                    None => problems.push(format!("unknown setting {}", key_path)),
+                }
+            }
+        }
+        (Value::Sequence(given), Value::Sequence(known)) => {
+            for (idx, (val, known)) in given.iter().zip(known).enumerate() {
+                unknown_keys(val, known, &format!("{}.{}", path, idx), problems);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Sets the dotted `path` (e.g. `server.port`) to `raw`, parsed as a YAML scalar
+/// so numbers and booleans keep their type.
+fn set_path(root: &mut Value, path: &str, raw: &str) {
+    // anything that isn't a plain scalar or a list is taken verbatim
+    let value = match serde_yaml::from_str::<Value>(raw) {
+        Ok(Value::Mapping(_)) | Ok(Value::Null) | Err(_) => Value::String(raw.to_string()),
+        Ok(value) => value,
+    };
+
+    let mut node = root;
+    for key in path.split('.') {
+        if !node.is_mapping() {
+            *node = Value::Mapping(Mapping::new());
+        }
+        let map = node.as_mapping_mut().unwrap();
+        let key = Value::String(key.to_string());
+        if !map.contains_key(&key) {
+            map.insert(key.clone(), Value::Null);
+        }
+        node = map.get_mut(&key).unwrap();
+    }
+    *node = value;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    /// A config file in the temp dir, removed when dropped.
+    struct ConfigFile(PathBuf);
+
+    impl ConfigFile {
+        fn new(name: &str, content: &str) -> Self {
+            let path =
+                env::temp_dir().join(format!("rs-starter-{}-{}.yaml", std::process::id(), name));
+            fs::write(&path, content).unwrap();
+            ConfigFile(path)
+        }
+
+        fn path(&self) -> String {
+            self.0.display().to_string()
+        }
+    }
+
+    impl Drop for ConfigFile {
+        fn drop(&mut self) {
+            fs::remove_file(&self.0).ok();
+        }
+    }
+
+    fn load(
+        file: &ConfigFile,
+        args: &[&str],
+        vars: &[(&str, &str)],
+    ) -> Result<Settings, SettingsError> {
+        let args = ["--config", &file.path()]
+            .iter()
+            .chain(args)
+            .map(|arg| arg.to_string())
+            .collect::<Vec<String>>();
+        let vars = vars.iter().map(|(k, v)| (k.to_string(), v.to_string()));
+        Settings::load_from(args, vars)
+    }
+
+    fn problems(result: Result<Settings, SettingsError>) -> Vec<String> {
+        match result {
+            Err(SettingsError::Invalid(problems)) => problems,
+            other => panic!("expected invalid settings, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn later_sources_override_earlier_ones() {
+        let file = ConfigFile::new(
+            "layers",
+            "server: { host: 127.0.0.1, port: 9000, workers: 3, backlog: 16 }",
+        );
+        let settings = load(
+            &file,
+            &["--port", "9002"],
+            &[("APP__SERVER__PORT", "9001"), ("APP__SERVER__WORKERS", "4")],
+        )
+        .unwrap();
+
+        assert_eq!(settings.server.port, 9002);
+        assert_eq!(settings.server.workers, 4);
+        assert_eq!(settings.server.backlog, 16);
+        assert_eq!(settings.server.host, "127.0.0.1");
+        assert_eq!(
+            settings.server.keep_alive,
+            ServerSettings::default().keep_alive
+        );
+    }
+
+    #[test]
+    fn mappings_are_merged_key_by_key() {
+        let file = ConfigFile::new(
+            "merge",
+            "server: { unix: { path: /tmp/rs-starter.sock } }\n\
+             rate_limit: { policies: { strict: { window: 60, limit: 1 } } }",
+        );
+        let settings = load(&file, &[], &[]).unwrap();
+
+        assert_eq!(settings.server.unix.path, "/tmp/rs-starter.sock");
+        assert_eq!(settings.server.unix.mode, "660");
+        assert_eq!(settings.server.port, 8001);
+        let policies: Vec<&str> = settings
+            .rate_limit
+            .policies
+            .keys()
+            .map(String::as_str)
+            .collect();
+        assert_eq!(policies, ["developer", "strict"]);
+    }
+
+    #[test]
+    fn null_removes_default_entries() {
+        let file = ConfigFile::new(
+            "null",
+            "server: { port: ~ }\n\
+             rate_limit: { policies: { developer: ~ }, rules: [] }\n\
+             ip_filter: { scopes: { /developer: ~, /metrics: ~ } }",
+        );
+        let settings = load(&file, &[], &[]).unwrap();
+
+        assert_eq!(settings.server.port, 8001);
+        assert!(settings.rate_limit.policies.is_empty());
+        assert!(settings.ip_filter.scopes.is_empty());
+    }
+
+    #[test]
+    fn unknown_keys_are_refused() {
+        let file = ConfigFile::new("unknown", "server: { prot: 8080 }");
+        let problems = problems(load(
+            &file,
+            &["--set", "mongo.databse=test"],
+            &[("APP__TLS__ENABEL", "true")],
+        ));
+
+        assert!(problems.contains(&String::from("unknown setting server.prot")));
+        assert!(problems.contains(&String::from("unknown setting mongo.databse")));
+        assert!(problems.contains(&String::from("unknown setting tls.enabel")));
+        assert_eq!(problems.len(), 3, "{:?}", problems);
+    }
+
+    #[test]
+    fn unknown_format_keys_are_refused() {
+        let file = ConfigFile::new("format", r#"log: { access: { format: '%{x}a "%r"' } }"#);
+        assert_eq!(
+            problems(load(&file, &[], &[])),
+            ["log.access.format has an unknown address: %{x}a"]
+        );
+
+        let file = ConfigFile::new("cert", r#"log: { access: { format: '%{name}c' } }"#);
+        assert_eq!(
+            problems(load(&file, &[], &[])),
+            ["log.access.format has an unknown client certificate field: %{name}c"]
+        );
+    }
+
+    #[test]
+    fn unrelated_variables_are_ignored() {
+        let file = ConfigFile::new("unrelated", "{}");
+        let settings = load(
+            &file,
+            &[],
+            &[
+                ("APP_ENV", "production"),
+                ("APP_NAME", "demo"),
+                ("APP_SERVER__PORT", "1"),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(settings.server.port, 8001);
+    }
+
+    #[test]
+    fn unknown_arguments_are_refused() {
+        let file = ConfigFile::new("args", "{}");
+        assert!(matches!(
+            load(&file, &["--verbose"], &[]),
+            Err(SettingsError::Args(_))
+        ));
+        assert!(matches!(
+            load(&file, &["--help"], &[]),
+            Err(SettingsError::Help)
+        ));
+    }
+}
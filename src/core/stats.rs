@@ -0,0 +1,325 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use actix_web::http::Method;
+use lazy_static::lazy_static;
+use serde::Serialize;
+
+use crate::core::metrics;
+
+/// Seconds of traffic the statistics cover.
+pub const WINDOW_SECS: u64 = 300;
+
+/// The window moves a slice at a time, the oldest slice being dropped when a
+/// new one starts.
+const SLICE_SECS: u64 = 30;
+const SLICE_COUNT: usize = (WINDOW_SECS / SLICE_SECS) as usize;
+
+/// Latency buckets grow by 10% from 0.01 ms (the last one ends past 30
+/// minutes), so percentiles are at most 10% above the actual latency.
+const BUCKET_MIN_MS: f64 = 0.01;
+const BUCKET_GROWTH: f64 = 1.1;
+const BUCKETS: usize = 200;
+
+lazy_static! {
+    static ref WINDOW: Mutex<VecDeque<Slice>> = Mutex::new(VecDeque::new());
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Key {
+    route: String,
+    method: &'static str,
+}
+
+struct Slice {
+    /// Index of the slice since the epoch.
+    index: u64,
+    series: HashMap<Key, Series>,
+}
+
+#[derive(Clone)]
+struct Series {
+    count: u64,
+    client_errors: u64,
+    server_errors: u64,
+    bytes: u64,
+    max_ms: f64,
+    buckets: Vec<u32>,
+}
+
+/// The statistics of the routes over the last `window_secs` seconds.
+#[derive(Debug, Serialize)]
+pub struct Snapshot {
+    pub window_secs: u64,
+    /// The busiest routes first.
+    pub routes: Vec<RouteStats>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RouteStats {
+    /// Matched pattern, e.g. `/user/{id}`, or `unmatched`.
+    pub route: String,
+    pub method: &'static str,
+    pub count: u64,
+    pub rps: f64,
+    /// 4xx responses.
+    pub client_errors: u64,
+    /// 5xx responses.
+    pub server_errors: u64,
+    /// Fraction of 5xx responses, the 4xx ones being the client's errors.
+    pub server_error_rate: f64,
+    pub p50_ms: f64,
+    pub p95_ms: f64,
+    pub p99_ms: f64,
+    pub max_ms: f64,
+    /// Bytes of the response bodies.
+    pub bytes: u64,
+    pub avg_bytes: u64,
+}
+
+/// Records a served request, `route` being the matched pattern like in
+/// `metrics::observe`.
+pub fn record(route: Option<&str>, method: &Method, status: u16, seconds: f64, bytes: usize) {
+    let key = Key {
+        route: route.unwrap_or("unmatched").to_string(),
+        method: metrics::method_label(method),
+    };
+    let mut slices = WINDOW.lock().unwrap();
+    record_at(&mut slices, now_secs(), key, status, seconds, bytes);
+}
+
+fn record_at(
+    slices: &mut VecDeque<Slice>,
+    now_secs: u64,
+    key: Key,
+    status: u16,
+    seconds: f64,
+    bytes: usize,
+) {
+    let now = slice_index(now_secs);
+    if slices.back().map(|slice| slice.index) != Some(now) {
+        slices.push_back(Slice {
+            index: now,
+            series: HashMap::new(),
+        });
+    }
+    expire(slices, now);
+
+    let series = slices
+        .back_mut()
+        .unwrap()
+        .series
+        .entry(key)
+        .or_insert_with(Series::new);
+    series.record(status, seconds * 1000.0, bytes);
+}
+
+pub fn snapshot() -> Snapshot {
+    let mut slices = WINDOW.lock().unwrap();
+    snapshot_at(&mut slices, now_secs())
+}
+
+fn snapshot_at(slices: &mut VecDeque<Slice>, now_secs: u64) -> Snapshot {
+    let now = slice_index(now_secs);
+    expire(slices, now);
+
+    let mut merged: HashMap<Key, Series> = HashMap::new();
+    for slice in slices.iter() {
+        for (key, series) in &slice.series {
+            merged
+                .entry(key.clone())
+                .or_insert_with(Series::new)
+                .merge(series);
+        }
+    }
+    // a window shorter than WINDOW_SECS until the server has been up that long
+    let window_secs = slices
+        .front()
+        .map_or(0, |slice| now_secs.saturating_sub(slice.index * SLICE_SECS))
+        .clamp(1, WINDOW_SECS);
+
+    let mut routes: Vec<RouteStats> = merged
+        .into_iter()
+        .map(|(key, series)| RouteStats {
+            route: key.route,
+            method: key.method,
+            count: series.count,
+            rps: series.count as f64 / window_secs as f64,
+            client_errors: series.client_errors,
+            server_errors: series.server_errors,
+            server_error_rate: series.server_errors as f64 / series.count as f64,
+            p50_ms: series.percentile(0.5),
+            p95_ms: series.percentile(0.95),
+            p99_ms: series.percentile(0.99),
+            max_ms: series.max_ms,
+            bytes: series.bytes,
+            avg_bytes: series.bytes / series.count,
+        })
+        .collect();
+    routes.sort_by(|a, b| {
+        b.count
+            .cmp(&a.count)
+            .then_with(|| a.route.cmp(&b.route))
+            .then_with(|| a.method.cmp(b.method))
+    });
+
+    Snapshot {
+        window_secs,
+        routes,
+    }
+}
+
+/// Drops the slices that left the window ending with slice `now`.
+fn expire(slices: &mut VecDeque<Slice>, now: u64) {
+    while matches!(slices.front(), Some(slice) if slice.index + SLICE_COUNT as u64 <= now) {
+        slices.pop_front();
+    }
+}
+
+impl Series {
+    fn new() -> Self {
+        Series {
+            count: 0,
+            client_errors: 0,
+            server_errors: 0,
+            bytes: 0,
+            max_ms: 0.0,
+            buckets: vec![0; BUCKETS],
+        }
+    }
+
+    fn record(&mut self, status: u16, ms: f64, bytes: usize) {
+        self.count += 1;
+        match status {
+            400..=499 => self.client_errors += 1,
+            500..=599 => self.server_errors += 1,
+            _ => {}
+        }
+        self.bytes += bytes as u64;
+        self.max_ms = self.max_ms.max(ms);
+        self.buckets[bucket(ms)] += 1;
+    }
+
+    fn merge(&mut self, other: &Series) {
+        self.count += other.count;
+        self.client_errors += other.client_errors;
+        self.server_errors += other.server_errors;
+        self.bytes += other.bytes;
+        self.max_ms = self.max_ms.max(other.max_ms);
+        for (bucket, count) in self.buckets.iter_mut().zip(&other.buckets) {
+            *bucket += count;
+        }
+    }
+
+    /// Upper bound of the bucket holding the `p` percentile, at most `max_ms`.
+    fn percentile(&self, p: f64) -> f64 {
+        let rank = ((p * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (i, count) in self.buckets.iter().enumerate() {
+            seen += *count as u64;
+            if seen >= rank {
+                return (BUCKET_MIN_MS * BUCKET_GROWTH.powi(i as i32)).min(self.max_ms);
+            }
+        }
+        self.max_ms
+    }
+}
+
+fn bucket(ms: f64) -> usize {
+    if ms <= BUCKET_MIN_MS {
+        return 0;
+    }
+    let i = ((ms / BUCKET_MIN_MS).ln() / BUCKET_GROWTH.ln()).ceil() as usize;
+    i.min(BUCKETS - 1)
+}
+
+fn slice_index(secs: u64) -> u64 {
+    secs / SLICE_SECS
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The start of a slice, in seconds since the epoch.
+    const T0: u64 = 1_000 * SLICE_SECS;
+
+    fn key(route: &str) -> Key {
+        Key {
+            route: route.to_string(),
+            method: "GET",
+        }
+    }
+
+    #[test]
+    fn percentiles_are_within_a_bucket_of_the_latencies() {
+        let mut slices = VecDeque::new();
+        // 1 ms to 100 ms, one request each
+        for ms in 1..=100 {
+            record_at(&mut slices, T0, key("/"), 200, ms as f64 / 1000.0, 0);
+        }
+        let route = &snapshot_at(&mut slices, T0).routes[0];
+
+        for (percentile, latency) in [
+            (route.p50_ms, 50.0),
+            (route.p95_ms, 95.0),
+            (route.p99_ms, 99.0),
+        ] {
+            assert!(
+                percentile >= latency && percentile <= latency * BUCKET_GROWTH,
+                "{} for {}",
+                percentile,
+                latency
+            );
+        }
+        assert_eq!(route.max_ms, 100.0);
+    }
+
+    #[test]
+    fn old_slices_leave_the_window() {
+        let mut slices = VecDeque::new();
+        record_at(&mut slices, T0, key("/old"), 200, 0.01, 0);
+        record_at(&mut slices, T0 + SLICE_SECS, key("/new"), 200, 0.01, 0);
+
+        let routes = |snapshot: Snapshot| -> Vec<String> {
+            snapshot
+                .routes
+                .into_iter()
+                .map(|route| route.route)
+                .collect()
+        };
+        let last = T0 + WINDOW_SECS - 1;
+        assert_eq!(routes(snapshot_at(&mut slices, last)), ["/new", "/old"]);
+        assert_eq!(
+            routes(snapshot_at(&mut slices, last + 1)),
+            ["/new"],
+            "{}s after the first request",
+            WINDOW_SECS
+        );
+        assert!(routes(snapshot_at(&mut slices, last + 1 + SLICE_SECS)).is_empty());
+    }
+
+    #[test]
+    fn errors_and_bytes_are_counted() {
+        let mut slices = VecDeque::new();
+        for (status, bytes) in [(200, 100), (201, 300), (404, 50), (500, 10), (503, 0)] {
+            record_at(&mut slices, T0, key("/"), status, 0.01, bytes);
+        }
+        record_at(&mut slices, T0 + SLICE_SECS, key("/"), 200, 0.01, 40);
+
+        let route = &snapshot_at(&mut slices, T0 + SLICE_SECS).routes[0];
+        assert_eq!(route.count, 6);
+        assert_eq!(route.client_errors, 1);
+        assert_eq!(route.server_errors, 2);
+        assert_eq!(route.server_error_rate, 2.0 / 6.0);
+        assert_eq!(route.bytes, 500);
+        assert_eq!(route.avg_bytes, 83);
+    }
+}
@@ -0,0 +1,524 @@
+use std::any::Any;
+use std::collections::HashMap;
+use std::fs;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use actix_tls::accept::openssl::TlsStream;
+use actix_utils::future::{ready, Ready};
+use actix_web::dev::{Extensions, Payload};
+use actix_web::{error, rt::net::TcpStream, FromRequest, HttpRequest};
+use openssl::error::ErrorStack;
+use openssl::nid::Nid;
+use openssl::ssl::{
+    NameType, SniError, SslAcceptor, SslAcceptorBuilder, SslContext, SslFiletype, SslMethod,
+    SslVerifyMode,
+};
+use openssl::x509::{X509NameRef, X509Ref, X509};
+use serde::Serialize;
+
+use crate::core::settings::{CertificateSettings, ClientAuth, ClientAuthSettings, TlsSettings};
+
+/// The certificates served by the TLS listener.
+///
+/// Contexts are picked per connection from the SNI hostname, falling back to
+/// the default certificate, and can be swapped at runtime with `reload`
+/// without restarting the server. Connections already established keep the
+/// certificate they were accepted with.
+pub struct CertStore {
+    settings: TlsSettings,
+    contexts: RwLock<Contexts>,
+}
+
+struct Contexts {
+    default: SslContext,
+    by_host: HashMap<String, SslContext>,
+    modified: Vec<Option<SystemTime>>,
+}
+
+impl CertStore {
+    pub fn new(settings: &TlsSettings) -> Result<Arc<CertStore>, ErrorStack> {
+        let contexts = Contexts::load(settings)?;
+        Ok(Arc::new(CertStore {
+            settings: settings.clone(),
+            contexts: RwLock::new(contexts),
+        }))
+    }
+
+    /// Re-reads every key and certificate from disk, and the CAs of the
+    /// client certificates.
+    ///
+    /// On failure the certificates in use are kept.
+    pub fn reload(&self) -> Result<(), ErrorStack> {
+        let contexts = Contexts::load(&self.settings)?;
+        *self.contexts.write().unwrap() = contexts;
+        log::info!(
+            "TLS certificates reloaded ({} SNI hostname(s))",
+            self.settings.sni.len()
+        );
+        Ok(())
+    }
+
+    /// Reloads the certificates if any of the files changed since the last load.
+    pub fn reload_if_changed(&self) -> Result<bool, ErrorStack> {
+        let modified = modified_times(&self.settings);
+        if modified == self.contexts.read().unwrap().modified {
+            return Ok(false);
+        }
+        self.reload().map(|_| true)
+    }
+
+    /// The context to use for a connection to `hostname`.
+    pub fn context_for(&self, hostname: Option<&str>) -> SslContext {
+        let contexts = self.contexts.read().unwrap();
+        hostname
+            .and_then(|name| lookup(&contexts.by_host, name))
+            .unwrap_or(&contexts.default)
+            .clone()
+    }
+
+    /// Builds the acceptor handed to `HttpServer::bind_openssl`, selecting the
+    /// certificate from this store on every handshake.
+    pub fn acceptor(self: &Arc<Self>) -> Result<SslAcceptorBuilder, ErrorStack> {
+        let mut builder =
+            context_builder(&self.settings.certificate(), &self.settings.client_auth)?;
+
+        let store = Arc::clone(self);
+        builder.set_servername_callback(move |ssl, _alert| {
+            let ctx = store.context_for(ssl.servername(NameType::HOST_NAME));
+            ssl.set_ssl_context(&ctx).map_err(|err| {
+                log::error!("Couldn't switch TLS context: {}", err);
+                SniError::ALERT_FATAL
+            })
+        });
+
+        Ok(builder)
+    }
+
+    /// Checks the certificate files every `interval` and reloads them when
+    /// they changed.
+    pub fn watch(self: &Arc<Self>, interval: Duration) {
+        let store = Arc::downgrade(self);
+        std::thread::Builder::new()
+            .name(String::from("tls-watch"))
+            .spawn(move || loop {
+                std::thread::sleep(interval);
+                let store = match store.upgrade() {
+                    Some(store) => store,
+                    None => return,
+                };
+                if let Err(err) = store.reload_if_changed() {
+                    log::error!(
+                        "Couldn't reload TLS certificates, keeping the current ones: {}",
+                        err
+                    );
+                }
+            })
+            .expect("failed to spawn the tls watcher");
+    }
+}
+
+impl Contexts {
+    fn load(settings: &TlsSettings) -> Result<Contexts, ErrorStack> {
+        // read the times first, a file replaced while loading is picked up next round
+        let modified = modified_times(settings);
+
+        let default = context_builder(&settings.certificate(), &settings.client_auth)?
+            .build()
+            .context()
+            .to_owned();
+
+        let mut by_host = HashMap::new();
+        for cert in &settings.sni {
+            let ctx = context_builder(cert, &settings.client_auth)?
+                .build()
+                .context()
+                .to_owned();
+            by_host.insert(cert.hostname.to_lowercase(), ctx);
+        }
+
+        Ok(Contexts {
+            default,
+            by_host,
+            modified,
+        })
+    }
+}
+
+/// A context builder for one key/certificate pair.
+///
+/// Client verification is set on every context, the SNI callback swaps the
+/// context before the client certificate is requested.
+fn context_builder(
+    cert: &CertificateSettings,
+    client_auth: &ClientAuthSettings,
+) -> Result<SslAcceptorBuilder, ErrorStack> {
+    let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
+    builder.set_private_key_file(&cert.key, SslFiletype::PEM)?;
+    builder.set_certificate_chain_file(&cert.cert)?;
+    builder.check_private_key()?;
+
+    let mode = match client_auth.mode {
+        ClientAuth::None => return Ok(builder),
+        ClientAuth::Optional => SslVerifyMode::PEER,
+        ClientAuth::Required => SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT,
+    };
+    builder.set_ca_file(&client_auth.ca)?;
+    builder.set_client_ca_list(openssl::x509::X509Name::load_client_ca_file(
+        &client_auth.ca,
+    )?);
+    builder.set_verify(mode);
+    // sessions can only be resumed within the same context when verifying peers
+    builder.set_session_id_context(b"rs-starter")?;
+    Ok(builder)
+}
+
+/// Exact hostname first, then a `*.example.com` wildcard for a single label.
+fn lookup<'a>(by_host: &'a HashMap<String, SslContext>, hostname: &str) -> Option<&'a SslContext> {
+    let hostname = hostname.to_lowercase();
+    by_host.get(&hostname).or_else(|| {
+        let (_, parent) = hostname.split_once('.')?;
+        by_host.get(&format!("*.{}", parent))
+    })
+}
+
+fn modified_times(settings: &TlsSettings) -> Vec<Option<SystemTime>> {
+    let mut files = vec![&settings.key, &settings.cert];
+    for cert in &settings.sni {
+        files.push(&cert.key);
+        files.push(&cert.cert);
+    }
+    if settings.client_auth.mode != ClientAuth::None {
+        files.push(&settings.client_auth.ca);
+    }
+    files
+        .into_iter()
+        .map(|file| fs::metadata(file).and_then(|m| m.modified()).ok())
+        .collect()
+}
+
+/// The verified certificate of a mutual TLS client.
+///
+/// Extract it in a handler to require a client certificate (the request is
+/// rejected with `401` otherwise), or as `Option<PeerIdentity>` when it is
+/// optional.
+#[derive(Debug, Clone, Serialize)]
+pub struct PeerIdentity {
+    /// Distinguished name of the subject, e.g. `CN=billing,O=example`.
+    pub subject: String,
+    /// Common name of the subject, if any.
+    pub common_name: Option<String>,
+    /// DNS names, IP addresses, emails and URIs of the subject alternative names.
+    pub sans: Vec<String>,
+    /// Distinguished name of the issuer.
+    pub issuer: String,
+    pub serial: String,
+}
+
+impl PeerIdentity {
+    pub fn from_cert(cert: &X509Ref) -> PeerIdentity {
+        let sans = cert
+            .subject_alt_names()
+            .map(|names| {
+                names
+                    .iter()
+                    .filter_map(|name| {
+                        name.dnsname()
+                            .map(str::to_string)
+                            .or_else(|| name.email().map(str::to_string))
+                            .or_else(|| name.uri().map(str::to_string))
+                            .or_else(|| name.ipaddress().and_then(ip_to_string))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        PeerIdentity {
+            subject: name_to_string(cert.subject_name()),
+            common_name: cert
+                .subject_name()
+                .entries_by_nid(Nid::COMMONNAME)
+                .next()
+                .and_then(|entry| entry.data().as_utf8().ok())
+                .map(|cn| cn.to_string()),
+            sans,
+            issuer: name_to_string(cert.issuer_name()),
+            serial: cert
+                .serial_number()
+                .to_bn()
+                .and_then(|bn| bn.to_hex_str().map(|hex| hex.to_string()))
+                .unwrap_or_default(),
+        }
+    }
+}
+
+impl FromRequest for PeerIdentity {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(
+            req.conn_data::<PeerIdentity>()
+                .cloned()
+                .ok_or_else(|| error::ErrorUnauthorized("client certificate required")),
+        )
+    }
+}
+
+/// `HttpServer::on_connect` callback storing the verified client certificate
+/// of TLS connections in the connection data.
+pub fn on_connect(conn: &dyn Any, data: &mut Extensions) {
+    if let Some(stream) = conn.downcast_ref::<TlsStream<TcpStream>>() {
+        let cert: Option<X509> = stream.ssl().peer_certificate();
+        if let Some(cert) = cert {
+            data.insert(PeerIdentity::from_cert(&cert));
+        }
+    }
+}
+
+fn name_to_string(name: &X509NameRef) -> String {
+    name.entries()
+        .map(|entry| {
+            let key = entry.object().nid().short_name().unwrap_or("?");
+            let val = entry
+                .data()
+                .as_utf8()
+                .map(|val| val.to_string())
+                .unwrap_or_default();
+            format!("{}={}", key, val)
+        })
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+fn ip_to_string(ip: &[u8]) -> Option<String> {
+    match ip.len() {
+        4 => Some(std::net::Ipv4Addr::from(<[u8; 4]>::try_from(ip).ok()?).to_string()),
+        16 => Some(std::net::Ipv6Addr::from(<[u8; 16]>::try_from(ip).ok()?).to_string()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use actix_web::{http::StatusCode, web, App, HttpResponse, HttpServer};
+    use openssl::asn1::Asn1Time;
+    use openssl::bn::BigNum;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::hash::MessageDigest;
+    use openssl::pkey::{PKey, Private};
+    use openssl::x509::extension::SubjectAlternativeName;
+    use openssl::x509::X509NameBuilder;
+
+    use super::*;
+
+    /// A directory of throwaway certificates, removed when dropped.
+    struct Certs(PathBuf);
+
+    impl Certs {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!(
+                "rs-starter-{}-tls-{}",
+                std::process::id(),
+                name
+            ));
+            fs::create_dir_all(&dir).unwrap();
+            Certs(dir)
+        }
+
+        /// Writes a self-signed pair for `cn` as `<file>.key` and `<file>.pem`.
+        fn write(&self, file: &str, cn: &str) -> CertificateSettings {
+            let (key, cert) = self_signed(cn, &[]);
+            let settings = self.settings(file, cn);
+            fs::write(&settings.key, key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+            fs::write(&settings.cert, cert.to_pem().unwrap()).unwrap();
+            settings
+        }
+
+        fn settings(&self, file: &str, hostname: &str) -> CertificateSettings {
+            let path = |ext: &str| {
+                self.0
+                    .join(format!("{}.{}", file, ext))
+                    .display()
+                    .to_string()
+            };
+            CertificateSettings {
+                hostname: hostname.to_string(),
+                key: path("key"),
+                cert: path("pem"),
+            }
+        }
+    }
+
+    /// A key and a certificate for `cn`, with the DNS names and IP addresses
+    /// of `sans`.
+    fn self_signed(cn: &str, sans: &[&str]) -> (PKey<Private>, X509) {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_nid(Nid::ORGANIZATIONNAME, "example")
+            .unwrap();
+        name.append_entry_by_nid(Nid::COMMONNAME, cn).unwrap();
+        let name = name.build();
+
+        let mut cert = X509::builder().unwrap();
+        cert.set_version(2).unwrap();
+        let serial = BigNum::from_u32(0x2a).unwrap().to_asn1_integer().unwrap();
+        cert.set_serial_number(&serial).unwrap();
+        cert.set_subject_name(&name).unwrap();
+        cert.set_issuer_name(&name).unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        if !sans.is_empty() {
+            let mut alt_names = SubjectAlternativeName::new();
+            for san in sans {
+                match san.parse::<std::net::IpAddr>() {
+                    Ok(_) => alt_names.ip(san),
+                    Err(_) => alt_names.dns(san),
+                };
+            }
+            let alt_names = alt_names.build(&cert.x509v3_context(None, None)).unwrap();
+            cert.append_extension(alt_names).unwrap();
+        }
+        cert.sign(&key, MessageDigest::sha256()).unwrap();
+        (key, cert.build())
+    }
+
+    impl Drop for Certs {
+        fn drop(&mut self) {
+            fs::remove_dir_all(&self.0).ok();
+        }
+    }
+
+    fn tls(default: CertificateSettings, sni: Vec<CertificateSettings>) -> TlsSettings {
+        TlsSettings {
+            enable: true,
+            key: default.key,
+            cert: default.cert,
+            sni,
+            ..TlsSettings::default()
+        }
+    }
+
+    /// Common name of the certificate served for `hostname`.
+    fn served(store: &CertStore, hostname: Option<&str>) -> Option<String> {
+        let ctx = store.context_for(hostname);
+        PeerIdentity::from_cert(ctx.certificate().unwrap()).common_name
+    }
+
+    #[test]
+    fn sni_hostnames_pick_their_certificate() {
+        let certs = Certs::new("sni");
+        let settings = tls(
+            certs.write("default", "default"),
+            vec![
+                certs.write("api", "api.example.com"),
+                certs.write("apps", "*.apps.example.com"),
+            ],
+        );
+        let store = CertStore::new(&settings).unwrap();
+
+        for (hostname, cn) in [
+            (Some("api.example.com"), "api.example.com"),
+            (Some("API.Example.com"), "api.example.com"),
+            (Some("billing.apps.example.com"), "*.apps.example.com"),
+            (Some("a.billing.apps.example.com"), "default"),
+            (Some("unknown.example.com"), "default"),
+            (None, "default"),
+        ] {
+            assert_eq!(
+                served(&store, hostname).as_deref(),
+                Some(cn),
+                "{:?}",
+                hostname
+            );
+        }
+    }
+
+    #[test]
+    fn changed_files_swap_the_certificate() {
+        let certs = Certs::new("reload");
+        let settings = tls(certs.write("default", "first"), vec![]);
+        let store = CertStore::new(&settings).unwrap();
+        assert!(!store.reload_if_changed().unwrap());
+
+        let renewed = certs.write("default", "renewed");
+        // don't rely on the resolution of the file times
+        let later = SystemTime::now() + Duration::from_secs(10);
+        fs::File::options()
+            .write(true)
+            .open(&renewed.cert)
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+        assert!(store.reload_if_changed().unwrap());
+        assert_eq!(served(&store, None).as_deref(), Some("renewed"));
+
+        fs::write(&renewed.cert, "not a certificate").unwrap();
+        assert!(store.reload().is_err());
+        assert_eq!(served(&store, None).as_deref(), Some("renewed"));
+    }
+
+    /// Status and body of a request to a handler extracting `PeerIdentity`,
+    /// served on a connection carrying `identity`.
+    async fn extracted(identity: Option<PeerIdentity>) -> (StatusCode, String) {
+        let server = HttpServer::new(|| {
+            App::new().route(
+                "/",
+                web::get().to(|peer: PeerIdentity| async move { HttpResponse::Ok().json(peer) }),
+            )
+        })
+        .on_connect(move |_, data| {
+            if let Some(identity) = &identity {
+                data.insert(identity.clone());
+            }
+        })
+        .workers(1)
+        .disable_signals()
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let addr = server.addrs()[0];
+        let server = server.run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        let mut res = awc::Client::new()
+            .get(format!("http://{}/", addr))
+            .send()
+            .await
+            .unwrap();
+        let body = res.body().await.unwrap();
+        handle.stop(false).await;
+        (res.status(), String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[actix_web::test]
+    async fn peer_identity_is_required() {
+        let (status, _) = extracted(None).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_web::test]
+    async fn peer_identity_is_read_from_the_connection() {
+        let (_, cert) = self_signed("billing", &["billing.example.com", "10.0.0.1"]);
+        let (status, body) = extracted(Some(PeerIdentity::from_cert(&cert))).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let peer: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(
+            peer,
+            serde_json::json!({
+                "subject": "O=example,CN=billing",
+                "common_name": "billing",
+                "sans": ["billing.example.com", "10.0.0.1"],
+                "issuer": "O=example,CN=billing",
+                "serial": "2A",
+            })
+        );
+    }
+}
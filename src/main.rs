@@ -8,8 +8,21 @@ pub mod utils;
 pub mod websocket;
 
 use crate::core::bootstrap_server::Server;
+use crate::core::settings::{Settings, SettingsError};
 
 #[actix_web::main]
 async fn main() {
-    Server::new().run().await
+    let settings = match Settings::load_with_args() {
+        Ok(settings) => settings,
+        Err(SettingsError::Help) => {
+            println!("{}", Settings::usage());
+            return;
+        }
+        Err(err) => {
+            eprintln!("🔥 {}", err);
+            std::process::exit(1);
+        }
+    };
+
+    Server::new().settings(settings).run().await
 }
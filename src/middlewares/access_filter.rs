@@ -1,10 +1,12 @@
 use std::{
     borrow::Cow,
+    cell::RefCell,
     collections::HashSet,
     convert::TryFrom,
     env,
     fmt::{self, Display as _},
     future::Future,
+    io,
     marker::PhantomData,
     pin::Pin,
     rc::Rc,
@@ -15,20 +17,32 @@ use actix_utils::future::{ready, Ready};
 use bytes::Bytes;
 use futures_core::ready;
 use human_repr::HumanCount;
-use log::{debug, warn};
+use log::{debug, warn, Level};
 use myhumantime::format_duration;
 use pin_project_lite::pin_project;
 use regex::{Regex, RegexSet};
+use serde::ser::{Serialize, SerializeMap, Serializer};
+use serde_json::{Map, Value};
 use std::time::Duration;
 use time::{format_description::well_known::Rfc3339, OffsetDateTime};
 
 use actix_web::body::{BodySize, MessageBody};
 use actix_web::{
-    dev::{Service, ServiceRequest, ServiceResponse, Transform},
-    http::header::HeaderName,
-    Error, Result,
+    dev::{Payload, Service, ServiceRequest, ServiceResponse, Transform},
+    http::{
+        header::{HeaderMap, HeaderName, HeaderValue},
+        Method,
+    },
+    Error, HttpMessage, HttpRequest, Result,
 };
 
+use crate::core::proxy::TrustedProxies;
+use crate::core::routes::routed_path;
+use crate::core::tls::PeerIdentity;
+use crate::core::{metrics, stats};
+use crate::middlewares::capture::{BodyTap, Capture, TapPayload};
+use crate::middlewares::request_id;
+
 #[derive(Debug)]
 pub struct Logger(Rc<Inner>);
 
@@ -38,16 +52,124 @@ struct Inner {
     exclude: HashSet<String>,
     exclude_regex: RegexSet,
     log_target: Cow<'static, str>,
+    metrics: bool,
+    stats: bool,
+    json: bool,
+    rule: Rule,
+    path_rules: Vec<(Regex, Rule)>,
+    capture: Option<Rc<Capture>>,
+    proxies: TrustedProxies,
+    /// Buffers of the requests logged, reused by the next ones.
+    slots: RefCell<Vec<Slots>>,
+}
+
+/// Buffers kept for reuse, more than the requests a worker usually serves at
+/// once.
+const MAX_SPARE_SLOTS: usize = 64;
+
+/// Which requests are logged, and at which level.
+///
+/// 5xx responses are logged at ERROR, requests slower than `slow` at WARN and
+/// the others at INFO.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rule {
+    /// Fraction of the responses logged, from 0 to 1, errors and slow
+    /// requests aside.
+    pub sample: f64,
+    /// Requests taking longer are always logged.
+    pub slow: Option<Duration>,
+    /// Always log 4xx and 5xx responses.
+    pub errors: bool,
+}
+
+impl Default for Rule {
+    fn default() -> Self {
+        Rule {
+            sample: 1.0,
+            slow: None,
+            errors: true,
+        }
+    }
+}
+
+impl Rule {
+    /// The level to log the request at, `None` to skip it.
+    ///
+    /// `roll` draws the number from 0 to 1 the request is sampled by, when it
+    /// is neither always nor never logged.
+    fn level(&self, status: u16, elapsed: Duration, roll: impl FnOnce() -> f64) -> Option<Level> {
+        let slow = matches!(self.slow, Some(slow) if elapsed >= slow);
+        let level = if status >= 500 {
+            Level::Error
+        } else if slow {
+            Level::Warn
+        } else {
+            Level::Info
+        };
+
+        let sampled = match self.sample {
+            sample if sample >= 1.0 => true,
+            sample if sample <= 0.0 => false,
+            sample => roll() < sample,
+        };
+        if slow || (self.errors && status >= 400) || sampled {
+            Some(level)
+        } else {
+            None
+        }
+    }
+}
+
+impl Inner {
+    fn excludes(&self, path: &str) -> bool {
+        self.exclude.contains(path) || self.exclude_regex.is_match(path)
+    }
+
+    /// The rule of the first path pattern matching `path`, the default one otherwise.
+    fn rule_for(&self, path: &str) -> Rule {
+        self.path_rules
+            .iter()
+            .find(|(regex, _)| regex.is_match(path))
+            .map_or(self.rule, |(_, rule)| *rule)
+    }
+
+    /// Empty buffers for a request, reused when there are some.
+    fn take_slots(&self) -> Slots {
+        self.slots.borrow_mut().pop().unwrap_or_default()
+    }
+
+    /// Gives back the buffers of a request once it is logged.
+    fn recycle(&self, mut slots: Slots) {
+        let mut spare = self.slots.borrow_mut();
+        if spare.len() < MAX_SPARE_SLOTS {
+            slots.clear();
+            spare.push(slots);
+        }
+    }
 }
 
 impl Logger {
+    /// Panics on an invalid format, see `try_new`.
     pub fn new(format: &str) -> Logger {
-        Logger(Rc::new(Inner {
-            format: Format::new(format),
+        Logger::try_new(format).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Fails on the tokens taking an unknown key, e.g. `%{x}a`.
+    pub fn try_new(format: &str) -> Result<Logger, String> {
+        Ok(Logger(Rc::new(Inner {
+            format: Format::new(format)?,
             exclude: HashSet::new(),
             exclude_regex: RegexSet::empty(),
             log_target: Cow::Borrowed(module_path!()),
-        }))
+            metrics: false,
+            stats: false,
+            json: false,
+            rule: Rule::default(),
+            path_rules: vec![],
+            capture: None,
+            proxies: TrustedProxies::default(),
+            slots: RefCell::default(),
+        })))
     }
 
     pub fn exclude<T: Into<String>>(mut self, path: T) -> Self {
@@ -67,11 +189,143 @@ impl Logger {
         self
     }
 
-    // pub fn log_target(mut self, target: impl Into<Cow<'static, str>>) -> Self {
-    //     let inner = Rc::get_mut(&mut self.0).unwrap();
-    //     inner.log_target = target.into();
-    //     self
-    // }
+    /// Registers the function computing the `%{label}xi` field from the request.
+    ///
+    /// ```ignore
+    /// Logger::new("%a %{tenant}xi \"%r\" %s")
+    ///     .custom_request_replace("tenant", |req| {
+    ///         req.headers()
+    ///             .get("x-tenant-id")
+    ///             .and_then(|v| v.to_str().ok())
+    ///             .unwrap_or("-")
+    ///             .to_owned()
+    ///     });
+    /// ```
+    pub fn custom_request_replace(
+        mut self,
+        label: &str,
+        f: impl Fn(&ServiceRequest) -> String + 'static,
+    ) -> Self {
+        let inner = Rc::get_mut(&mut self.0).unwrap();
+        let request_fn = CustomRequestFn {
+            inner_fn: Rc::new(f),
+        };
+
+        let mut found = false;
+        for unit in &mut inner.format.units {
+            if let FormatText::CustomRequest(unit_label, unit_fn, _) = unit {
+                if unit_label == label {
+                    *unit_fn = Some(request_fn.clone());
+                    found = true;
+                }
+            }
+        }
+        if !found {
+            debug!(
+                "Attempted to register custom request logging function for nonexistent label: {}",
+                label
+            );
+        }
+        self
+    }
+
+    /// Registers the function computing the `%{label}xo` field from the
+    /// response, e.g. the matched route name with `res.request().match_name()`.
+    pub fn custom_response_replace(
+        mut self,
+        label: &str,
+        f: impl Fn(&ServiceResponse) -> String + 'static,
+    ) -> Self {
+        let inner = Rc::get_mut(&mut self.0).unwrap();
+        let response_fn = CustomResponseFn {
+            inner_fn: Rc::new(f),
+        };
+
+        let mut found = false;
+        for unit in &mut inner.format.units {
+            if let FormatText::CustomResponse(unit_label, unit_fn, _) = unit {
+                if unit_label == label {
+                    *unit_fn = Some(response_fn.clone());
+                    found = true;
+                }
+            }
+        }
+        if !found {
+            debug!(
+                "Attempted to register custom response logging function for nonexistent label: {}",
+                label
+            );
+        }
+        self
+    }
+
+    /// Records every request, excluded paths included, in `core::metrics`.
+    pub fn metrics(mut self, enable: bool) -> Self {
+        Rc::get_mut(&mut self.0).unwrap().metrics = enable;
+        self
+    }
+
+    /// Records every request, excluded paths included, in `core::stats`.
+    pub fn stats(mut self, enable: bool) -> Self {
+        Rc::get_mut(&mut self.0).unwrap().stats = enable;
+        self
+    }
+
+    /// Samples, always logs errors or slow requests according to `rule`,
+    /// everything is logged by default.
+    pub fn rule(mut self, rule: Rule) -> Self {
+        Rc::get_mut(&mut self.0).unwrap().rule = rule;
+        self
+    }
+
+    /// Applies `rule` instead of the default one to the paths matching the
+    /// `pattern` regex. The first matching pattern wins.
+    pub fn path_rule<T: AsRef<str>>(mut self, pattern: T, rule: Rule) -> Self {
+        let regex = Regex::new(pattern.as_ref()).unwrap();
+        Rc::get_mut(&mut self.0)
+            .unwrap()
+            .path_rules
+            .push((regex, rule));
+        self
+    }
+
+    /// Logs one JSON object per request instead of a line of text.
+    ///
+    /// Each field of the format becomes a key: `%r` is split into `method`,
+    /// `path`, `query` and `protocol`, `%s` is `status`, `%b` is `bytes`, `%T`
+    /// and `%D` are `duration` (seconds) and `duration_ms`, headers are
+    /// nested under `request_headers` and `response_headers`, and custom
+    /// fields under `custom`, by label, so that they can't clash with the
+    /// others. Literal text of the format is left out, and missing values
+    /// are `null`.
+    pub fn json(mut self, enable: bool) -> Self {
+        Rc::get_mut(&mut self.0).unwrap().json = enable;
+        self
+    }
+
+    /// Adds the headers and bodies of the requests selected by `capture` to
+    /// their record, as a `capture` object in JSON or appended as
+    /// ` capture={...}` to the line of text. Captured requests are always
+    /// logged, whatever the rule.
+    pub fn capture(mut self, capture: Capture) -> Self {
+        Rc::get_mut(&mut self.0).unwrap().capture = Some(Rc::new(capture));
+        self
+    }
+
+    /// Takes the client address of `%{r}a` and the host of `%v` from the
+    /// forwarded headers of these proxies.
+    pub fn trusted_proxies(mut self, proxies: TrustedProxies) -> Self {
+        Rc::get_mut(&mut self.0).unwrap().proxies = proxies;
+        self
+    }
+
+    /// Target of the access log records, e.g. to send them to their own
+    /// log4rs appender.
+    pub fn log_target(mut self, target: impl Into<Cow<'static, str>>) -> Self {
+        let inner = Rc::get_mut(&mut self.0).unwrap();
+        inner.log_target = target.into();
+        self
+    }
 }
 
 impl Default for Logger {
@@ -81,6 +335,14 @@ impl Default for Logger {
             exclude: HashSet::new(),
             exclude_regex: RegexSet::empty(),
             log_target: Cow::Borrowed(module_path!()),
+            metrics: false,
+            stats: false,
+            json: false,
+            rule: Rule::default(),
+            path_rules: vec![],
+            capture: None,
+            proxies: TrustedProxies::default(),
+            slots: RefCell::default(),
         }))
     }
 }
@@ -97,15 +359,15 @@ where
     type Future = Ready<Result<Self::Transform, Self::InitError>>;
 
     fn new_transform(&self, service: S) -> Self::Future {
-        for unit in &self.0.format.0 {
-            if let FormatText::CustomRequest(label, None) = unit {
+        for unit in &self.0.format.units {
+            if let FormatText::CustomRequest(label, None, _) = unit {
                 warn!(
                     "No custom request replacement function was registered for label: {}",
                     label
                 );
             }
 
-            if let FormatText::CustomResponse(label, None) = unit {
+            if let FormatText::CustomResponse(label, None, _) = unit {
                 warn!(
                     "No custom response replacement function was registered for label: {}",
                     label
@@ -137,31 +399,70 @@ where
 
     actix_service::forward_ready!(service);
 
-    fn call(&self, req: ServiceRequest) -> Self::Future {
-        let excluded = self.inner.exclude.contains(req.path())
-            || self.inner.exclude_regex.is_match(req.path());
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let path = routed_path(req.request());
+        let excluded = self.inner.excludes(path);
+        let in_flight = if self.inner.metrics {
+            Some(InFlight::new())
+        } else {
+            None
+        };
 
         if excluded {
             LoggerResponse {
                 fut: self.service.call(req),
-                format: None,
+                inner: None,
                 time: OffsetDateTime::now_utc(),
-                log_target: Cow::Borrowed(""),
+                rule: Rule::default(),
+                request_body: None,
+                captured: None,
+                slots: Slots::default(),
+                in_flight,
+                stats: self.inner.stats,
                 _phantom: PhantomData,
             }
         } else {
             let now = OffsetDateTime::now_utc();
-            let mut format = self.inner.format.clone();
+            let format = &self.inner.format;
+            let rule = self.inner.rule_for(routed_path(req.request()));
 
-            for unit in &mut format.0 {
-                unit.render_request(now, &req);
-            }
+            let captured = self
+                .inner
+                .capture
+                .as_ref()
+                .filter(|capture| capture.wants(&req))
+                .map(|capture| Captured {
+                    capture: capture.clone(),
+                    request_headers: capture.headers(req.headers()),
+                    response_headers: Value::Null,
+                    response_body: BodyTap::new(capture.max_body()),
+                });
+
+            let request_body = if captured.is_some() || format.request_size {
+                let limit = captured.as_ref().map_or(0, |c| c.capture.max_body());
+                let tap = Rc::new(BodyTap::new(limit));
+                let payload = TapPayload::new(req.take_payload(), tap.clone());
+                req.set_payload(Payload::Stream {
+                    payload: Box::pin(payload),
+                });
+                Some(tap)
+            } else {
+                None
+            };
+
+            let mut slots = self.inner.take_slots();
+            format.custom_requests(&req, &mut slots.custom_requests);
 
             LoggerResponse {
                 fut: self.service.call(req),
-                format: Some(format),
+                inner: Some(self.inner.clone()),
                 time: now,
-                log_target: self.inner.log_target.clone(),
+                rule,
+                request_body,
+                captured,
+                slots,
+                in_flight,
+                stats: self.inner.stats,
                 _phantom: PhantomData,
             }
         }
@@ -177,8 +478,14 @@ pin_project! {
         #[pin]
         fut: S::Future,
         time: OffsetDateTime,
-        format: Option<Format>,
-        log_target: Cow<'static, str>,
+        // `None` for the excluded paths
+        inner: Option<Rc<Inner>>,
+        rule: Rule,
+        request_body: Option<Rc<BodyTap>>,
+        captured: Option<Captured>,
+        slots: Slots,
+        in_flight: Option<InFlight>,
+        stats: bool,
         _phantom: PhantomData<B>,
     }
 }
@@ -202,38 +509,76 @@ where
             debug!("Error in response: {:?}", error);
         }
 
-        let res = if let Some(ref mut format) = this.format {
-            // to avoid polluting all the Logger types with the body parameter we swap the body
-            // out temporarily since it's not usable in custom response functions anyway
+        let mut slots = std::mem::take(this.slots);
+        let res = match this.inner {
+            Some(inner) if inner.format.custom_responses > 0 => {
+                // to avoid polluting all the Logger types with the body parameter we swap the body
+                // out temporarily since it's not usable in custom response functions anyway
 
-            let (req, res) = res.into_parts();
-            let (res, body) = res.into_parts();
+                let (req, res) = res.into_parts();
+                let (res, body) = res.into_parts();
 
-            let temp_res = ServiceResponse::new(req, res.map_into_boxed_body());
+                let temp_res = ServiceResponse::new(req, res.map_into_boxed_body());
+                inner
+                    .format
+                    .custom_responses(&temp_res, &mut slots.custom_responses);
 
-            for unit in &mut format.0 {
-                unit.render_response(&temp_res);
+                // re-construct original service response
+                let (req, res) = temp_res.into_parts();
+                ServiceResponse::new(req, res.set_body(body))
             }
-
-            // re-construct original service response
-            let (req, res) = temp_res.into_parts();
-            ServiceResponse::new(req, res.set_body(body))
-        } else {
-            res
+            _ => res,
         };
 
-        let time = *this.time;
-        let format = this.format.take();
-        let log_target = this.log_target.clone();
+        if let Some(captured) = this.captured {
+            captured.response_headers = captured.capture.headers(res.headers());
+        }
+
         let status_code = res.status().as_u16();
+        let entry = this.inner.as_ref().map(|inner| {
+            inner
+                .format
+                .response_headers(res.headers(), &mut slots.response_headers);
+            Entry {
+                req: res.request().clone(),
+                proxies: inner.proxies.clone(),
+                time: *this.time,
+                status: status_code,
+                slots,
+                size: 0,
+                request_size: 0,
+                elapsed: Duration::ZERO,
+            }
+        });
+
+        let time = *this.time;
+        let inner = this.inner.take();
+        let rule = *this.rule;
+        let request_body = this.request_body.take();
+        let captured = this.captured.take();
+        let in_flight = this.in_flight.take();
+        let observation = if in_flight.is_some() || *this.stats {
+            Some(Observation {
+                route: res.request().match_pattern(),
+                method: res.request().method().clone(),
+                in_flight,
+                stats: *this.stats,
+            })
+        } else {
+            None
+        };
 
         Poll::Ready(Ok(res.map_body(move |_, body| StreamLog {
             body,
             time,
-            format,
+            inner,
+            entry,
             size: 0,
-            log_target,
+            rule,
+            request_body,
+            captured,
             status_code,
+            observation,
         })))
     }
 }
@@ -243,39 +588,164 @@ pin_project! {
     pub struct StreamLog<B> {
         #[pin]
         body: B,
-        format: Option<Format>,
+        inner: Option<Rc<Inner>>,
+        entry: Option<Entry>,
         size: usize,
         time: OffsetDateTime,
-        log_target: Cow<'static, str>,
+        rule: Rule,
+        request_body: Option<Rc<BodyTap>>,
+        captured: Option<Captured>,
         status_code: u16,
+        observation: Option<Observation>,
     }
 
     impl<B> PinnedDrop for StreamLog<B> {
-        fn drop(this: Pin<&mut Self>) {
-            if let Some(ref format) = this.format {
-                let render = |fmt: &mut fmt::Formatter<'_>| {
-                    for unit in &format.0 {
-                        unit.render(fmt, this.size, this.time)?;
-                    }
-                    Ok(())
-                };
+        fn drop(mut this: Pin<&mut Self>) {
+            let elapsed = (OffsetDateTime::now_utc() - this.time).as_seconds_f64();
 
-                if this.status_code >= 500 {
-                    log::error!(
-                        target: this.log_target.as_ref(),
-                        "Access {}", FormatDisplay(&render)
+            if let Some(observation) = this.as_mut().project().observation.take() {
+                if observation.in_flight.is_some() {
+                    metrics::observe(
+                        observation.route.as_deref(),
+                        &observation.method,
+                        this.status_code,
+                        elapsed,
                     );
-                } else {
-                    log::info!(
-                        target: this.log_target.as_ref(),
-                        "Access {}", FormatDisplay(&render)
+                }
+                if observation.stats {
+                    stats::record(
+                        observation.route.as_deref(),
+                        &observation.method,
+                        this.status_code,
+                        elapsed,
+                        this.size,
                     );
                 }
             }
+
+            let elapsed = Duration::from_secs_f64(elapsed.max(0.0));
+            let level = match this.inner {
+                Some(_) => this.rule.level(this.status_code, elapsed, rand::random),
+                None => None,
+            };
+            let level = match this.captured {
+                Some(_) => level.or(Some(Level::Info)),
+                None => level,
+            };
+            let inner = match this.inner.clone() {
+                Some(inner) => inner,
+                None => return,
+            };
+            let mut entry = match this.as_mut().project().entry.take() {
+                Some(entry) => entry,
+                None => return,
+            };
+            if let Some(level) = level {
+                entry.size = this.size;
+                entry.request_size = this.request_body.as_ref().map_or(0, |tap| tap.size());
+                entry.elapsed = elapsed;
+                let captured = this
+                    .captured
+                    .as_ref()
+                    .map(|captured| captured.render(this.request_body.as_deref()));
+                log_entry(&inner, level, &entry, captured.as_ref());
+            }
+            inner.recycle(entry.slots);
         }
     }
 }
 
+/// Writes the access log record of `entry`.
+fn log_entry(inner: &Inner, level: Level, entry: &Entry, captured: Option<&Value>) {
+    if inner.json {
+        let record = JsonRecord {
+            format: &inner.format,
+            entry,
+            capture: captured,
+        };
+        log::log!(target: inner.log_target.as_ref(), level, "{}", record);
+    } else {
+        let render = |fmt: &mut fmt::Formatter<'_>| {
+            for unit in &inner.format.units {
+                unit.render(fmt, entry)?;
+            }
+            if let Some(captured) = captured {
+                write!(fmt, " capture={}", captured)?;
+            }
+            Ok(())
+        };
+
+        log::log!(
+            target: inner.log_target.as_ref(),
+            level,
+            "Access {}", FormatDisplay(&render)
+        );
+    }
+}
+
+/// Counts a request in `metrics::IN_FLIGHT` until dropped.
+struct InFlight;
+
+impl InFlight {
+    fn new() -> Self {
+        metrics::IN_FLIGHT.inc();
+        InFlight
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        metrics::IN_FLIGHT.dec();
+    }
+}
+
+/// What the metrics and stats are labelled with, recorded once the body is sent.
+struct Observation {
+    route: Option<String>,
+    method: Method,
+    /// Set when recording the metrics.
+    in_flight: Option<InFlight>,
+    stats: bool,
+}
+
+/// The headers and bodies of a request selected by `Capture`.
+struct Captured {
+    capture: Rc<Capture>,
+    request_headers: Value,
+    response_headers: Value,
+    response_body: BodyTap,
+}
+
+impl Captured {
+    fn render(&self, request_body: Option<&BodyTap>) -> Value {
+        let mut out = Map::new();
+        out.insert(
+            String::from("request_headers"),
+            self.request_headers.clone(),
+        );
+        if let Some(body) = request_body {
+            out.insert(String::from("request_body"), self.capture.body(body));
+            out.insert(
+                String::from("request_body_truncated"),
+                Value::from(body.truncated()),
+            );
+        }
+        out.insert(
+            String::from("response_headers"),
+            self.response_headers.clone(),
+        );
+        out.insert(
+            String::from("response_body"),
+            self.capture.body(&self.response_body),
+        );
+        out.insert(
+            String::from("response_body_truncated"),
+            Value::from(self.response_body.truncated()),
+        );
+        Value::Object(out)
+    }
+}
+
 impl<B: MessageBody> MessageBody for StreamLog<B> {
     type Error = B::Error;
 
@@ -293,6 +763,9 @@ impl<B: MessageBody> MessageBody for StreamLog<B> {
         match ready!(this.body.poll_next(cx)) {
             Some(Ok(chunk)) => {
                 *this.size += chunk.len();
+                if let Some(captured) = this.captured {
+                    captured.response_body.record(&chunk);
+                }
                 Poll::Ready(Some(Ok(chunk)))
             }
             Some(Err(err)) => Poll::Ready(Some(Err(err))),
@@ -301,24 +774,85 @@ impl<B: MessageBody> MessageBody for StreamLog<B> {
     }
 }
 
-/// A formatting style for the `Logger` consisting of multiple concatenated `FormatText` items.
+/// What a logged request is rendered from, gathered while it is served.
+///
+/// Most fields are read from the request once the body is sent, only the
+/// values that don't outlive the response are copied: its status, the
+/// response headers of the format and the output of the custom functions.
+struct Entry {
+    req: HttpRequest,
+    proxies: TrustedProxies,
+    time: OffsetDateTime,
+    status: u16,
+    slots: Slots,
+    size: usize,
+    request_size: usize,
+    elapsed: Duration,
+}
+
+/// The values of the fields that don't outlive the response, by slot. The
+/// buffers go back to the `Logger` once the request is logged.
+#[derive(Debug, Clone, Default)]
+struct Slots {
+    /// Values of the `%{..}o` fields.
+    response_headers: Vec<Option<HeaderValue>>,
+    /// Output of the `%{..}xi` functions.
+    custom_requests: Vec<Option<String>>,
+    /// Output of the `%{..}xo` functions.
+    custom_responses: Vec<Option<String>>,
+}
+
+impl Slots {
+    fn clear(&mut self) {
+        self.response_headers.clear();
+        self.custom_requests.clear();
+        self.custom_responses.clear();
+    }
+}
+
+/// A formatting style for the `Logger`, compiled once from the format string.
+///
+/// Rendering a request writes straight to the log record: the text units in
+/// order, or the precomputed `json` layout.
 #[derive(Debug, Clone)]
-struct Format(Vec<FormatText>);
+struct Format {
+    units: Vec<FormatText>,
+    /// Keys of the JSON output, in order, and the units they are rendered from.
+    json: Vec<(String, JsonField)>,
+    /// Whether `%I` is used, the request body being counted then.
+    request_size: bool,
+    response_headers: usize,
+    custom_requests: usize,
+    custom_responses: usize,
+}
+
+/// The fields of `%{..}c`.
+const CLIENT_CERT_FIELDS: [&str; 5] = ["subject", "cn", "san", "issuer", "serial"];
 
 impl Default for Format {
     /// Return the default formatting style for the `Logger`:
     fn default() -> Format {
-        Format::new(r#"%a "%r" %s %b "%{Referer}i" "%{User-Agent}i" %T"#)
+        Format::new(r#"%a "%r" %s %b "%{Referer}i" "%{User-Agent}i" %T"#).unwrap()
     }
 }
 
 impl Format {
     /// Create a `Format` from a format string.
     ///
-    /// Returns `None` if the format string syntax is incorrect.
-    pub fn new(s: &str) -> Format {
+    /// Returns an error for the tokens taking an unknown key: `%{..}a` only
+    /// takes `r` and `%{..}c` a field of the client certificate.
+    pub fn new(s: &str) -> Result<Format, String> {
         log::trace!("Access log format: {}", s);
-        let fmt = Regex::new(r"%(\{([A-Za-z0-9\-_]+)\}([aioe]|x[io])|[%atPrUsbTD]?)").unwrap();
+        let fmt =
+            Regex::new(r"%(\{([A-Za-z0-9\-_]+)\}([aioec]|x[io])|[%atPHrUqvsbIRLTD]?)").unwrap();
+
+        let mut response_headers = 0;
+        let mut custom_requests = 0;
+        let mut custom_responses = 0;
+        let next = |slots: &mut usize| {
+            *slots += 1;
+            *slots - 1
+        };
 
         let mut idx = 0;
         let mut results = Vec::new();
@@ -332,18 +866,36 @@ impl Format {
 
             if let Some(key) = cap.get(2) {
                 results.push(match cap.get(3).unwrap().as_str() {
-                    "a" => {
-                        if key.as_str() == "r" {
-                            FormatText::RealIpRemoteAddr
-                        } else {
-                            unreachable!("regex and code mismatch")
-                        }
-                    }
+                    "a" if key.as_str() == "r" => FormatText::RealIpRemoteAddr,
+                    "a" => return Err(format!("has an unknown address: %{{{}}}a", key.as_str())),
                     "i" => FormatText::RequestHeader(HeaderName::try_from(key.as_str()).unwrap()),
-                    "o" => FormatText::ResponseHeader(HeaderName::try_from(key.as_str()).unwrap()),
-                    "e" => FormatText::EnvironHeader(key.as_str().to_owned()),
-                    "xi" => FormatText::CustomRequest(key.as_str().to_owned(), None),
-                    "xo" => FormatText::CustomResponse(key.as_str().to_owned(), None),
+                    "o" => FormatText::ResponseHeader(
+                        HeaderName::try_from(key.as_str()).unwrap(),
+                        next(&mut response_headers),
+                    ),
+                    "e" => FormatText::EnvironHeader(
+                        key.as_str().to_owned(),
+                        env::var(key.as_str()).ok(),
+                    ),
+                    "c" if CLIENT_CERT_FIELDS.contains(&key.as_str()) => {
+                        FormatText::ClientCert(key.as_str().to_owned())
+                    }
+                    "c" => {
+                        return Err(format!(
+                            "has an unknown client certificate field: %{{{}}}c",
+                            key.as_str()
+                        ))
+                    }
+                    "xi" => FormatText::CustomRequest(
+                        key.as_str().to_owned(),
+                        None,
+                        next(&mut custom_requests),
+                    ),
+                    "xo" => FormatText::CustomResponse(
+                        key.as_str().to_owned(),
+                        None,
+                        next(&mut custom_responses),
+                    ),
                     _ => unreachable!(),
                 })
             } else {
@@ -358,6 +910,13 @@ impl Format {
                     "U" => FormatText::UrlPath,
                     "T" => FormatText::Time,
                     "D" => FormatText::TimeMillis,
+                    "P" => FormatText::Pid,
+                    "H" => FormatText::Protocol,
+                    "q" => FormatText::QueryString,
+                    "v" => FormatText::Host,
+                    "I" => FormatText::RequestSize,
+                    "R" => FormatText::MatchPattern,
+                    "L" => FormatText::RequestId,
                     _ => FormatText::Str(m.as_str().to_owned()),
                 });
             }
@@ -366,13 +925,103 @@ impl Format {
             results.push(FormatText::Str(s[idx..].to_owned()));
         }
 
-        Format(results)
+        Ok(Format {
+            json: Format::json_layout(&results),
+            request_size: results
+                .iter()
+                .any(|unit| matches!(unit, FormatText::RequestSize)),
+            units: results,
+            response_headers,
+            custom_requests,
+            custom_responses,
+        })
+    }
+
+    /// The keys of the JSON output, see `Logger::json`. A field repeated in
+    /// the format keeps the place of its first occurrence.
+    fn json_layout(units: &[FormatText]) -> Vec<(String, JsonField)> {
+        let mut layout: Vec<(String, JsonField)> = Vec::new();
+        let mut put =
+            |key: &str, field: JsonField| match layout.iter_mut().find(|(name, _)| name == key) {
+                Some((_, JsonField::Group(fields))) if matches!(field, JsonField::Group(_)) => {
+                    if let JsonField::Group(more) = field {
+                        for (name, unit) in more {
+                            match fields.iter_mut().find(|(known, _)| *known == name) {
+                                Some(known) => known.1 = unit,
+                                None => fields.push((name, unit)),
+                            }
+                        }
+                    }
+                }
+                Some(known) => known.1 = field,
+                None => layout.push((key.to_string(), field)),
+            };
+
+        for (i, unit) in units.iter().enumerate() {
+            if let FormatText::RequestLine = unit {
+                put("method", JsonField::Part(FormatText::Method));
+                put("path", JsonField::Part(FormatText::UrlPath));
+                put("query", JsonField::Part(FormatText::QueryString));
+                put("protocol", JsonField::Part(FormatText::Protocol));
+                continue;
+            }
+            match unit.json_key() {
+                Some((Some(group), name)) => put(group, JsonField::Group(vec![(name, i)])),
+                Some((None, key)) => put(&key, JsonField::Unit(i)),
+                None => {}
+            }
+        }
+        layout
+    }
+
+    /// Runs the `%{..}xi` functions into `out`, slot by slot.
+    fn custom_requests(&self, req: &ServiceRequest, out: &mut Vec<Option<String>>) {
+        out.reserve(self.custom_requests);
+        for unit in &self.units {
+            if let FormatText::CustomRequest(_, request_fn, _) = unit {
+                out.push(request_fn.as_ref().map(|f| f.call(req)));
+            }
+        }
+    }
+
+    /// Runs the `%{..}xo` functions into `out`, slot by slot.
+    fn custom_responses(&self, res: &ServiceResponse, out: &mut Vec<Option<String>>) {
+        out.reserve(self.custom_responses);
+        for unit in &self.units {
+            if let FormatText::CustomResponse(_, response_fn, _) = unit {
+                out.push(response_fn.as_ref().map(|f| f.call(res)));
+            }
+        }
+    }
+
+    /// The values of the `%{..}o` fields into `out`, slot by slot.
+    fn response_headers(&self, headers: &HeaderMap, out: &mut Vec<Option<HeaderValue>>) {
+        out.reserve(self.response_headers);
+        for unit in &self.units {
+            if let FormatText::ResponseHeader(name, _) = unit {
+                out.push(headers.get(name).cloned());
+            }
+        }
     }
 }
 
+/// Where the value of a key of the JSON output comes from.
+#[derive(Debug, Clone)]
+enum JsonField {
+    /// The unit at this index of the format.
+    Unit(usize),
+    /// One of the fields `%r` is split into.
+    Part(FormatText),
+    /// Fields nested in an object, e.g. the request headers, with the index
+    /// of their unit.
+    Group(Vec<(String, usize)>),
+}
+
 /// A string of text to be logged.
 ///
 /// This is either one of the data fields supported by the `Logger`, or a custom `String`.
+/// The fields whose value doesn't outlive the response hold their slot in
+/// the `Entry`.
 #[non_exhaustive]
 #[derive(Debug, Clone)]
 enum FormatText {
@@ -387,11 +1036,30 @@ enum FormatText {
     RemoteAddr,
     RealIpRemoteAddr,
     UrlPath,
+    /// Id of the server process.
+    Pid,
+    /// HTTP version of the request.
+    Protocol,
+    QueryString,
+    /// Host of the request, from the `Host` header or the URI, or forwarded
+    /// by a trusted proxy.
+    Host,
+    /// Bytes of the request body read by the handler.
+    RequestSize,
+    /// Route pattern the request matched, e.g. `/user/{id}`.
+    MatchPattern,
+    /// Id assigned by the `request_id::AssignRequestId` middleware.
+    RequestId,
+    /// Method of the request, only in the JSON output where `%r` is split.
+    Method,
     RequestHeader(HeaderName),
-    ResponseHeader(HeaderName),
-    EnvironHeader(String),
-    CustomRequest(String, Option<CustomRequestFn>),
-    CustomResponse(String, Option<CustomResponseFn>),
+    ResponseHeader(HeaderName, usize),
+    /// Name and value of the environment variable, read when the format is
+    /// compiled.
+    EnvironHeader(String, Option<String>),
+    ClientCert(String),
+    CustomRequest(String, Option<CustomRequestFn>, usize),
+    CustomResponse(String, Option<CustomResponseFn>, usize),
 }
 
 #[derive(Clone)]
@@ -428,136 +1096,262 @@ impl fmt::Debug for CustomResponseFn {
     }
 }
 
+/// The value of a unit, borrowed from the `Entry` for as long as it is
+/// written.
+enum Field<'a> {
+    /// `-` in text, `null` in JSON.
+    Missing,
+    Text(&'a str),
+    Number(u64),
+    Display(&'a dyn fmt::Display),
+}
+
+impl<'a> Field<'a> {
+    fn text(value: Option<&'a str>) -> Self {
+        value.map_or(Field::Missing, Field::Text)
+    }
+}
+
 impl FormatText {
-    fn render(
-        &self,
-        fmt: &mut fmt::Formatter<'_>,
-        size: usize,
-        entry_time: OffsetDateTime,
-    ) -> Result<(), fmt::Error> {
+    fn render(&self, fmt: &mut fmt::Formatter<'_>, entry: &Entry) -> Result<(), fmt::Error> {
         match self {
             FormatText::Str(ref string) => fmt.write_str(string),
             FormatText::Percent => "%".fmt(fmt),
+            FormatText::RequestLine => {
+                let req = &entry.req;
+                write!(fmt, "{} {}", req.method(), req.path())?;
+                if !req.query_string().is_empty() {
+                    write!(fmt, "?{}", req.query_string())?;
+                }
+                write!(fmt, " {:?}", req.version())
+            }
             FormatText::ResponseSize => {
-                size.human_count_bytes().fmt(fmt)
+                entry.size.human_count_bytes().fmt(fmt)
                 // size.fmt(fmt)
             }
-            FormatText::Time => {
-                let rt = OffsetDateTime::now_utc() - entry_time;
-                let rt = rt.as_seconds_f64();
-                fmt.write_fmt(format_args!("{:.6}s", rt))
-            }
+            FormatText::RequestSize => entry.request_size.human_count_bytes().fmt(fmt),
+            FormatText::Time => fmt.write_fmt(format_args!("{:.6}s", entry.elapsed.as_secs_f64())),
             FormatText::TimeMillis => {
-                let rt = OffsetDateTime::now_utc() - entry_time;
                 // 1秒=1000毫秒(ms),          1毫秒=1／1000秒
                 // 1秒=1000000微秒(μs OR us), 1微秒=1／1000000秒
                 // 1秒=1000000000纳秒(ns),    1纳秒=1／1000000000秒
                 // 1秒=1000000000000皮秒,     1皮秒=1／1000000000000秒
-                format_duration(Duration::from_nanos(rt.whole_nanoseconds() as u64)).fmt(fmt)
+                format_duration(entry.elapsed).fmt(fmt)
             }
-            FormatText::EnvironHeader(ref name) => {
-                if let Ok(val) = env::var(name) {
-                    fmt.write_fmt(format_args!("{}", val))
-                } else {
-                    "-".fmt(fmt)
+            _ => self.with_field(entry, |field| match field {
+                Field::Missing => "-".fmt(fmt),
+                Field::Text(text) => fmt.write_str(text),
+                Field::Number(number) => number.fmt(fmt),
+                Field::Display(value) => value.fmt(fmt),
+            }),
+        }
+    }
+
+    /// Hands the value of the unit to `f`, the text and JSON outputs only
+    /// differing in how they write it.
+    fn with_field<R>(&self, entry: &Entry, f: impl FnOnce(Field<'_>) -> R) -> R {
+        let req = &entry.req;
+
+        match self {
+            FormatText::Method => f(Field::Text(req.method().as_str())),
+            FormatText::UrlPath => f(Field::Text(req.path())),
+            FormatText::QueryString => match req.query_string() {
+                "" => f(Field::Missing),
+                query => f(Field::Text(query)),
+            },
+            FormatText::Protocol => f(Field::Display(&format_args!("{:?}", req.version()))),
+            FormatText::Pid => f(Field::Number(u64::from(std::process::id()))),
+            FormatText::ResponseStatus => f(Field::Number(u64::from(entry.status))),
+            FormatText::RequestTime => {
+                // RFC 3339 timestamps are at most 35 bytes long
+                let mut buf = [0u8; 64];
+                let mut out = &mut buf[..];
+                match entry.time.format_into(&mut out, &Rfc3339) {
+                    Ok(len) => f(Field::text(std::str::from_utf8(&buf[..len]).ok())),
+                    Err(_) => f(Field::Missing),
                 }
             }
-            _ => Ok(()),
+            FormatText::Host => f(Field::Text(entry.proxies.host(req))),
+            FormatText::RemoteAddr => f(Field::text(req.connection_info().peer_addr())),
+            FormatText::RealIpRemoteAddr => match entry.proxies.client_addr(req) {
+                Some(addr) => f(Field::Display(&addr)),
+                None => f(Field::Missing),
+            },
+            FormatText::MatchPattern => f(Field::text(req.match_pattern().as_deref())),
+            FormatText::RequestId => match req.extensions().get::<request_id::RequestId>() {
+                Some(id) => f(Field::Display(id)),
+                None => f(Field::Missing),
+            },
+            FormatText::RequestHeader(name) => f(Field::text(
+                req.headers().get(name).and_then(|v| v.to_str().ok()),
+            )),
+            FormatText::ResponseHeader(_, slot) => f(Field::text(
+                entry.slots.response_headers[*slot]
+                    .as_ref()
+                    .and_then(|v| v.to_str().ok()),
+            )),
+            FormatText::EnvironHeader(_, value) => f(Field::text(value.as_deref())),
+            FormatText::ClientCert(field) => match req.conn_data::<PeerIdentity>() {
+                Some(peer) => match field.as_str() {
+                    "subject" => f(Field::Text(&peer.subject)),
+                    "cn" => f(Field::text(peer.common_name.as_deref())),
+                    "san" if !peer.sans.is_empty() => f(Field::Display(&Joined(&peer.sans))),
+                    "issuer" => f(Field::Text(&peer.issuer)),
+                    "serial" => f(Field::Text(&peer.serial)),
+                    _ => f(Field::Missing),
+                },
+                None => f(Field::Missing),
+            },
+            FormatText::CustomRequest(_, _, slot) => {
+                f(Field::text(entry.slots.custom_requests[*slot].as_deref()))
+            }
+            FormatText::CustomResponse(_, _, slot) => {
+                f(Field::text(entry.slots.custom_responses[*slot].as_deref()))
+            }
+            _ => f(Field::Missing),
         }
     }
 
-    fn render_response(&mut self, res: &ServiceResponse) {
-        match self {
-            FormatText::ResponseStatus => {
-                *self = FormatText::Str(format!("{}", res.status().as_u16()))
+    /// Key of the field in the JSON output, with the object it is nested in.
+    fn json_key(&self) -> Option<(Option<&'static str>, String)> {
+        let key = match self {
+            FormatText::RequestTime => (None, String::from("time")),
+            FormatText::ResponseStatus => (None, String::from("status")),
+            FormatText::ResponseSize => (None, String::from("bytes")),
+            FormatText::RequestSize => (None, String::from("request_bytes")),
+            FormatText::Time => (None, String::from("duration")),
+            FormatText::TimeMillis => (None, String::from("duration_ms")),
+            FormatText::RemoteAddr => (None, String::from("remote_addr")),
+            FormatText::RealIpRemoteAddr => (None, String::from("real_ip")),
+            FormatText::UrlPath => (None, String::from("path")),
+            FormatText::Pid => (None, String::from("pid")),
+            FormatText::Protocol => (None, String::from("protocol")),
+            FormatText::QueryString => (None, String::from("query")),
+            FormatText::Host => (None, String::from("host")),
+            FormatText::MatchPattern => (None, String::from("route")),
+            FormatText::RequestId => (None, String::from("request_id")),
+            FormatText::RequestHeader(name) => (Some("request_headers"), name.to_string()),
+            FormatText::ResponseHeader(name, _) => (Some("response_headers"), name.to_string()),
+            FormatText::EnvironHeader(name, _) => (Some("env"), name.clone()),
+            FormatText::ClientCert(field) => (Some("client_cert"), field.clone()),
+            FormatText::CustomRequest(label, _, _) | FormatText::CustomResponse(label, _, _) => {
+                (Some("custom"), label.clone())
             }
+            _ => return None,
+        };
+        Some(key)
+    }
+}
 
-            FormatText::ResponseHeader(ref name) => {
-                let s = if let Some(val) = res.headers().get(name) {
-                    if let Ok(s) = val.to_str() {
-                        s
-                    } else {
-                        "-"
-                    }
-                } else {
-                    "-"
-                };
-                *self = FormatText::Str(s.to_string())
+/// A request as one JSON object, serialized straight to the log record.
+struct JsonRecord<'a> {
+    format: &'a Format,
+    entry: &'a Entry,
+    capture: Option<&'a Value>,
+}
+
+impl Serialize for JsonRecord<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        for (key, field) in &self.format.json {
+            match field {
+                JsonField::Unit(i) => {
+                    map.serialize_entry(key, &JsonValue(&self.format.units[*i], self.entry))?
+                }
+                JsonField::Part(unit) => map.serialize_entry(key, &JsonValue(unit, self.entry))?,
+                JsonField::Group(fields) => map.serialize_entry(
+                    key,
+                    &JsonGroup {
+                        units: &self.format.units,
+                        fields,
+                        entry: self.entry,
+                    },
+                )?,
             }
+        }
+        if let Some(capture) = self.capture {
+            map.serialize_entry("capture", capture)?;
+        }
+        map.end()
+    }
+}
 
-            FormatText::CustomResponse(_, res_fn) => {
-                let text = match res_fn {
-                    Some(res_fn) => FormatText::Str(res_fn.call(res)),
-                    None => FormatText::Str("-".to_owned()),
-                };
+impl fmt::Display for JsonRecord<'_> {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        serde_json::to_writer(FmtWriter(fmt), self).map_err(|_| fmt::Error)
+    }
+}
+
+struct JsonValue<'a>(&'a FormatText, &'a Entry);
 
-                *self = text;
+impl Serialize for JsonValue<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let JsonValue(unit, entry) = *self;
+        match unit {
+            FormatText::ResponseSize => serializer.serialize_u64(entry.size as u64),
+            FormatText::RequestSize => serializer.serialize_u64(entry.request_size as u64),
+            FormatText::Time => serializer.serialize_f64(entry.elapsed.as_secs_f64()),
+            FormatText::TimeMillis => {
+                serializer.serialize_f64(entry.elapsed.as_secs_f64() * 1000.0)
             }
+            _ => unit.with_field(entry, |field| match field {
+                // custom functions return `-` for an absent value
+                Field::Missing | Field::Text("-") => serializer.serialize_none(),
+                Field::Text(text) => serializer.serialize_str(text),
+                Field::Number(number) => serializer.serialize_u64(number),
+                Field::Display(value) => serializer.collect_str(value),
+            }),
+        }
+    }
+}
+
+struct JsonGroup<'a> {
+    units: &'a [FormatText],
+    fields: &'a [(String, usize)],
+    entry: &'a Entry,
+}
 
-            _ => {}
+impl Serialize for JsonGroup<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.fields.len()))?;
+        for (name, i) in self.fields {
+            map.serialize_entry(name, &JsonValue(&self.units[*i], self.entry))?;
         }
+        map.end()
     }
+}
 
-    fn render_request(&mut self, now: OffsetDateTime, req: &ServiceRequest) {
-        match self {
-            FormatText::RequestLine => {
-                *self = if req.query_string().is_empty() {
-                    FormatText::Str(format!(
-                        "{} {} {:?}",
-                        req.method(),
-                        req.path(),
-                        req.version()
-                    ))
-                } else {
-                    FormatText::Str(format!(
-                        "{} {}?{} {:?}",
-                        req.method(),
-                        req.path(),
-                        req.query_string(),
-                        req.version()
-                    ))
-                };
-            }
-            FormatText::UrlPath => *self = FormatText::Str(req.path().to_string()),
-            FormatText::RequestTime => *self = FormatText::Str(now.format(&Rfc3339).unwrap()),
-            FormatText::RequestHeader(ref name) => {
-                let s = if let Some(val) = req.headers().get(name) {
-                    if let Ok(s) = val.to_str() {
-                        s
-                    } else {
-                        "-"
-                    }
-                } else {
-                    "-"
-                };
-                *self = FormatText::Str(s.to_string());
-            }
-            FormatText::RemoteAddr => {
-                let s = if let Some(peer) = req.connection_info().peer_addr() {
-                    FormatText::Str((*peer).to_string())
-                } else {
-                    FormatText::Str("-".to_string())
-                };
-                *self = s;
-            }
-            FormatText::RealIpRemoteAddr => {
-                let s = if let Some(remote) = req.connection_info().realip_remote_addr() {
-                    FormatText::Str(remote.to_string())
-                } else {
-                    FormatText::Str("-".to_string())
-                };
-                *self = s;
-            }
-            FormatText::CustomRequest(_, request_fn) => {
-                let s = match request_fn {
-                    Some(f) => FormatText::Str(f.call(req)),
-                    None => FormatText::Str("-".to_owned()),
-                };
+/// Lets serde_json write to a `fmt::Formatter`, which it only does with
+/// valid UTF-8 split on character boundaries.
+struct FmtWriter<'a, 'b>(&'a mut fmt::Formatter<'b>);
 
-                *self = s;
+impl io::Write for FmtWriter<'_, '_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let text = std::str::from_utf8(buf)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        self.0
+            .write_str(text)
+            .map_err(|_| io::Error::from(io::ErrorKind::Other))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Strings separated by commas, written one after the other.
+struct Joined<'a>(&'a [String]);
+
+impl fmt::Display for Joined<'_> {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, item) in self.0.iter().enumerate() {
+            if i > 0 {
+                fmt.write_str(",")?;
             }
-            _ => {}
+            fmt.write_str(item)?;
         }
+        Ok(())
     }
 }
 
@@ -569,3 +1363,226 @@ impl<'a> fmt::Display for FormatDisplay<'a> {
         (self.0)(fmt)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Mutex, Once};
+
+    use actix_web::{
+        error::PayloadError,
+        test::{call_service, init_service, read_body, TestRequest},
+        web, App, HttpResponse,
+    };
+    use futures::StreamExt;
+    use log::{LevelFilter, Log, Metadata, Record};
+
+    use super::*;
+
+    const FAST: Duration = Duration::from_millis(10);
+
+    /// Keeps the records of the loggers under test, by target.
+    struct Records(Mutex<Vec<(String, String)>>);
+
+    impl Log for Records {
+        fn enabled(&self, metadata: &Metadata) -> bool {
+            metadata.target().starts_with("test::")
+        }
+
+        fn log(&self, record: &Record) {
+            if self.enabled(record.metadata()) {
+                let mut records = self.0.lock().unwrap();
+                records.push((record.target().to_owned(), record.args().to_string()));
+            }
+        }
+
+        fn flush(&self) {}
+    }
+
+    static RECORDS: Records = Records(Mutex::new(Vec::new()));
+
+    /// The records `logger` writes for `req`, served by a `/user/{id}` route
+    /// reading the body of the request, streamed in `chunks` if any.
+    async fn access_log(logger: Logger, req: TestRequest, chunks: &[&'static str]) -> Vec<String> {
+        static INSTALL: Once = Once::new();
+        static TARGETS: AtomicUsize = AtomicUsize::new(0);
+        INSTALL.call_once(|| {
+            log::set_logger(&RECORDS).unwrap();
+            log::set_max_level(LevelFilter::Info);
+        });
+
+        let target = format!("test::{}", TARGETS.fetch_add(1, Ordering::Relaxed));
+        let app = init_service(
+            App::new()
+                .wrap(logger.log_target(target.clone()))
+                .wrap(request_id::AssignRequestId::new())
+                .route(
+                    "/user/{id}",
+                    web::to(|body: web::Bytes| async move {
+                        HttpResponse::Ok()
+                            .insert_header(("x-cache", "miss"))
+                            .body(format!("{} bytes", body.len()))
+                    }),
+                ),
+        )
+        .await;
+        let mut req = req.to_request();
+        if !chunks.is_empty() {
+            let chunks: Vec<_> = chunks
+                .iter()
+                .map(|chunk| Ok::<_, PayloadError>(Bytes::from(*chunk)))
+                .collect();
+            let payload = Payload::Stream {
+                payload: futures::stream::iter(chunks).boxed_local(),
+            };
+            req = req.replace_payload(payload).0;
+        }
+        let res = call_service(&app, req).await;
+        read_body(res).await;
+
+        let records = RECORDS.0.lock().unwrap();
+        records
+            .iter()
+            .filter(|(of, _)| *of == target)
+            .map(|(_, record)| record.clone())
+            .collect()
+    }
+
+    fn sampled(sample: f64) -> Rule {
+        Rule {
+            sample,
+            slow: Some(Duration::from_secs(1)),
+            errors: true,
+        }
+    }
+
+    #[test]
+    fn requests_are_sampled_by_the_roll() {
+        let rule = sampled(0.25);
+        assert_eq!(rule.level(200, FAST, || 0.1), Some(Level::Info));
+        assert_eq!(rule.level(200, FAST, || 0.25), None);
+        assert_eq!(rule.level(200, FAST, || 0.9), None);
+
+        assert_eq!(sampled(0.0).level(200, FAST, || 0.0), None);
+        assert_eq!(sampled(1.0).level(200, FAST, || 0.999), Some(Level::Info));
+    }
+
+    #[test]
+    fn errors_and_slow_requests_are_always_logged() {
+        let rule = sampled(0.0);
+        assert_eq!(rule.level(404, FAST, || 1.0), Some(Level::Info));
+        assert_eq!(rule.level(503, FAST, || 1.0), Some(Level::Error));
+        assert_eq!(
+            rule.level(200, Duration::from_secs(2), || 1.0),
+            Some(Level::Warn)
+        );
+
+        let rule = Rule {
+            errors: false,
+            ..rule
+        };
+        assert_eq!(rule.level(404, FAST, || 1.0), None);
+        assert_eq!(rule.level(503, FAST, || 1.0), None);
+    }
+
+    #[test]
+    fn the_first_matching_path_rule_wins() {
+        let logger = Logger::new("%s")
+            .rule(sampled(0.5))
+            .path_rule("^/metrics$", sampled(0.0))
+            .path_rule("^/", sampled(1.0));
+
+        assert_eq!(logger.0.rule_for("/metrics"), sampled(0.0));
+        assert_eq!(logger.0.rule_for("/metrics/more"), sampled(1.0));
+        assert_eq!(logger.0.rule_for("metrics"), sampled(0.5));
+    }
+
+    #[test]
+    fn paths_are_excluded() {
+        let logger = Logger::new("%s")
+            .exclude("/favicon.ico")
+            .exclude_regex("^/static");
+
+        assert!(logger.0.excludes("/favicon.ico"));
+        assert!(logger.0.excludes("/static/app.css"));
+        assert!(!logger.0.excludes("/favicon.ico/more"));
+        assert!(!logger.0.excludes("/user"));
+    }
+
+    #[actix_web::test]
+    async fn json_records_are_one_object_per_request() {
+        let logger = Logger::new(
+            r#"%r %s %b %T "%{Referer}i" "%{User-Agent}i" %{X-Cache}o %{Age}o %{status}xo"#,
+        )
+        .json(true);
+        let req = TestRequest::get()
+            .uri("/user/42?verbose=1")
+            .insert_header(("user-agent", "test/1.0"));
+        let records = access_log(logger, req, &[]).await;
+        assert_eq!(records.len(), 1);
+        assert!(!records[0].contains('\n'), "{}", records[0]);
+
+        let mut record: Value = serde_json::from_str(&records[0]).unwrap();
+        assert!(record["duration"].as_f64().unwrap() >= 0.0);
+        record["duration"] = Value::from(0.0);
+        assert_eq!(
+            record,
+            serde_json::json!({
+                "method": "GET",
+                "path": "/user/42",
+                "query": "verbose=1",
+                "protocol": "HTTP/1.1",
+                "status": 200,
+                "bytes": 7,
+                "duration": 0.0,
+                "request_headers": { "referer": null, "user-agent": "test/1.0" },
+                "response_headers": { "x-cache": "miss", "age": null },
+                "custom": { "status": null },
+            })
+        );
+    }
+
+    #[actix_web::test]
+    async fn fields_are_rendered() {
+        let logger = Logger::new("%P %H %q %v %R %I %L");
+        let req = TestRequest::post()
+            .uri("/user/42?verbose=1")
+            .insert_header(("host", "example.com"))
+            .insert_header(("x-request-id", "abc-123"));
+        let records = access_log(logger, req, &["hello", " ", "world"]).await;
+        assert_eq!(
+            records,
+            [format!(
+                "Access {} HTTP/1.1 verbose=1 example.com /user/{{id}} 11B abc-123",
+                std::process::id()
+            )]
+        );
+
+        let logger = Logger::new("%q %I %L");
+        let records = access_log(logger, TestRequest::get().uri("/user/42"), &[]).await;
+        assert_eq!(records.len(), 1);
+        let (fields, id) = records[0].rsplit_once(' ').unwrap();
+        assert_eq!(fields, "Access - 0B");
+        assert_eq!(id.len(), 36, "{}", id);
+    }
+
+    #[actix_web::test]
+    async fn custom_fields_are_rendered_by_their_function() {
+        let logger = Logger::new("%{tenant}xi %{route}xo %{cache}xo")
+            .custom_request_replace("tenant", |req| {
+                req.headers()
+                    .get("x-tenant")
+                    .and_then(|v| v.to_str().ok())
+                    .unwrap_or("-")
+                    .to_owned()
+            })
+            .custom_response_replace("route", |res| {
+                res.request().match_pattern().unwrap_or_default()
+            });
+        let req = TestRequest::get()
+            .uri("/user/42")
+            .insert_header(("x-tenant", "acme"));
+        let records = access_log(logger, req, &[]).await;
+        assert_eq!(records, ["Access acme /user/{id} -"]);
+    }
+}
@@ -0,0 +1,430 @@
+use std::{
+    borrow::Cow,
+    cell::{Cell, RefCell},
+    collections::HashSet,
+    pin::Pin,
+    rc::Rc,
+    task::{Context, Poll},
+};
+
+use actix_web::{
+    dev::{Payload, ServiceRequest},
+    error::PayloadError,
+    http::header::{self, HeaderMap, HeaderName},
+};
+use bytes::Bytes;
+use futures_core::ready;
+use ipnet::IpNet;
+use pin_project_lite::pin_project;
+use regex::{Captures, Regex, RegexSet};
+use serde_json::{Map, Value};
+
+use crate::core::{proxy::TrustedProxies, routes::routed_path};
+use crate::middlewares::rate_limit::X_API_KEY;
+
+/// Replaces the redacted header and field values.
+pub const REDACTED: &str = "[REDACTED]";
+
+/// The header values and the JSON or form fields replaced with
+/// `[REDACTED]`, in captures and recordings.
+#[derive(Debug, Clone, Default)]
+pub struct Redaction {
+    headers: HashSet<HeaderName>,
+    fields: HashSet<String>,
+    /// Finds the fields in bodies that aren't valid JSON, e.g. truncated ones
+    /// or forms.
+    text: Option<Regex>,
+}
+
+impl Redaction {
+    pub fn header(mut self, name: HeaderName) -> Self {
+        self.headers.insert(name);
+        self
+    }
+
+    /// Redacts the field `name` (case insensitive) at any depth of JSON
+    /// bodies, and in form bodies.
+    pub fn field<T: Into<String>>(mut self, name: T) -> Self {
+        self.fields.insert(name.into().to_lowercase());
+
+        let names = self
+            .fields
+            .iter()
+            .map(|name| regex::escape(name))
+            .collect::<Vec<String>>()
+            .join("|");
+        let pattern = format!(
+            r#"(?i)("(?:{names})"\s*:\s*)("(?:[^"\\]|\\.)*"?|[^,}}\]\s]*)|((?:^|&)(?:{names})=)[^&]*"#,
+            names = names
+        );
+        self.text = Some(Regex::new(&pattern).unwrap());
+        self
+    }
+
+    pub(crate) fn header_value<'a>(&self, name: &HeaderName, value: &'a str) -> &'a str {
+        if self.headers.contains(name) {
+            REDACTED
+        } else {
+            value
+        }
+    }
+
+    /// A body that is valid JSON, re-serialized with its fields redacted, or
+    /// the body with the fields it looks like it holds redacted.
+    pub(crate) fn body(&self, body: String, truncated: bool) -> String {
+        if self.fields.is_empty() {
+            return body;
+        }
+        if !truncated {
+            if let Ok(mut value) = serde_json::from_str::<Value>(&body) {
+                self.json(&mut value);
+                return value.to_string();
+            }
+        }
+        self.text(&body).into_owned()
+    }
+
+    fn json(&self, value: &mut Value) {
+        match value {
+            Value::Object(map) => {
+                for (key, value) in map.iter_mut() {
+                    if self.fields.contains(&key.to_lowercase()) {
+                        *value = Value::from(REDACTED);
+                    } else {
+                        self.json(value);
+                    }
+                }
+            }
+            Value::Array(items) => {
+                for item in items {
+                    self.json(item);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn text<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        match &self.text {
+            Some(regex) => {
+                regex.replace_all(text, |caps: &Captures| match (caps.get(1), caps.get(3)) {
+                    (Some(key), _) => format!("{}\"{}\"", key.as_str(), REDACTED),
+                    (_, Some(key)) => format!("{}{}", key.as_str(), REDACTED),
+                    _ => caps[0].to_string(),
+                })
+            }
+            None => Cow::Borrowed(text),
+        }
+    }
+}
+
+/// Records the headers and bodies of selected requests in the access log,
+/// to debug what clients send and receive.
+///
+/// A request is captured when its path matches one of `path` patterns or
+/// when it carries the debug `header` from one of the `header_network`s, so
+/// that outside clients can't have their bodies logged whatever the sampling
+/// of the access log. Bodies are truncated to `max_body`
+/// bytes, and the values of sensitive headers and of JSON or form fields are
+/// replaced with `[REDACTED]`.
+#[derive(Debug, Clone)]
+pub struct Capture {
+    max_body: usize,
+    paths: RegexSet,
+    header: Option<HeaderName>,
+    /// Clients the debug header is accepted from, none by default.
+    header_networks: Vec<IpNet>,
+    proxies: TrustedProxies,
+    redaction: Redaction,
+}
+
+impl Capture {
+    /// Redacts `Authorization`, `Proxy-Authorization`, `Cookie`, `Set-Cookie`
+    /// and `X-Api-Key` by default.
+    pub fn new(max_body: usize) -> Self {
+        Capture {
+            max_body,
+            paths: RegexSet::empty(),
+            header: None,
+            header_networks: vec![],
+            proxies: TrustedProxies::default(),
+            redaction: Redaction::default()
+                .header(header::AUTHORIZATION)
+                .header(header::PROXY_AUTHORIZATION)
+                .header(header::COOKIE)
+                .header(header::SET_COOKIE)
+                .header(X_API_KEY),
+        }
+    }
+
+    /// Captures the requests whose path matches the `pattern` regex.
+    pub fn path<T: Into<String>>(mut self, pattern: T) -> Self {
+        let mut patterns = self.paths.patterns().to_vec();
+        patterns.push(pattern.into());
+        self.paths = RegexSet::new(patterns).unwrap();
+        self
+    }
+
+    /// Captures the requests carrying `name` with any value but `0` or `false`,
+    /// from the clients of a `header_network`.
+    pub fn header(mut self, name: HeaderName) -> Self {
+        self.header = Some(name);
+        self
+    }
+
+    /// Accepts the debug header from the clients in `network`.
+    pub fn header_network(mut self, network: IpNet) -> Self {
+        self.header_networks.push(network);
+        self
+    }
+
+    /// Takes the client address from the forwarded headers of these proxies.
+    pub fn trusted_proxies(mut self, proxies: TrustedProxies) -> Self {
+        self.proxies = proxies;
+        self
+    }
+
+    pub fn redact_header(mut self, name: HeaderName) -> Self {
+        self.redaction = self.redaction.header(name);
+        self
+    }
+
+    /// Redacts the field `name`, see `Redaction::field`.
+    pub fn redact_field<T: Into<String>>(mut self, name: T) -> Self {
+        self.redaction = self.redaction.field(name);
+        self
+    }
+
+    pub fn max_body(&self) -> usize {
+        self.max_body
+    }
+
+    pub(crate) fn wants(&self, req: &ServiceRequest) -> bool {
+        if self.paths.is_match(routed_path(req.request())) {
+            return true;
+        }
+        let asked = match self
+            .header
+            .as_ref()
+            .and_then(|name| req.headers().get(name))
+        {
+            Some(value) => !matches!(value.to_str(), Ok("0") | Ok("false")),
+            None => false,
+        };
+        asked
+            && match self.proxies.client_addr(req.request()) {
+                Some(addr) => self.header_networks.iter().any(|net| net.contains(&addr)),
+                None => false,
+            }
+    }
+
+    pub(crate) fn headers(&self, headers: &HeaderMap) -> Value {
+        let mut out = Map::new();
+        for (name, value) in headers {
+            let value = self
+                .redaction
+                .header_value(name, value.to_str().unwrap_or("<binary>"));
+            out.insert(name.to_string(), Value::from(value));
+        }
+        Value::Object(out)
+    }
+
+    /// A JSON body as a JSON value, anything else as text.
+    pub(crate) fn body(&self, tap: &BodyTap) -> Value {
+        let body = tap.captured.borrow();
+        if body.is_empty() {
+            return Value::Null;
+        }
+
+        if !tap.truncated() {
+            if let Ok(mut value) = serde_json::from_slice::<Value>(&body) {
+                self.redaction.json(&mut value);
+                return value;
+            }
+        }
+
+        let text = String::from_utf8_lossy(&body);
+        Value::from(self.redaction.text(&text).into_owned())
+    }
+}
+
+/// Counts the bytes of a body and keeps the first `limit` of them.
+#[derive(Debug, Default)]
+pub(crate) struct BodyTap {
+    size: Cell<usize>,
+    captured: RefCell<Vec<u8>>,
+    limit: usize,
+}
+
+impl BodyTap {
+    pub(crate) fn new(limit: usize) -> Self {
+        BodyTap {
+            limit,
+            ..BodyTap::default()
+        }
+    }
+
+    pub(crate) fn record(&self, chunk: &[u8]) {
+        self.size.set(self.size.get() + chunk.len());
+
+        let mut captured = self.captured.borrow_mut();
+        let room = self.limit.saturating_sub(captured.len());
+        captured.extend_from_slice(&chunk[..room.min(chunk.len())]);
+    }
+
+    pub(crate) fn captured(&self) -> Vec<u8> {
+        self.captured.borrow().clone()
+    }
+
+    pub(crate) fn size(&self) -> usize {
+        self.size.get()
+    }
+
+    pub(crate) fn truncated(&self) -> bool {
+        self.size.get() > self.captured.borrow().len()
+    }
+}
+
+pin_project! {
+    /// Records the bytes of the request body in a `BodyTap` as the handler
+    /// reads them.
+    pub(crate) struct TapPayload {
+        #[pin]
+        payload: Payload,
+        tap: Rc<BodyTap>,
+    }
+}
+
+impl TapPayload {
+    pub(crate) fn new(payload: Payload, tap: Rc<BodyTap>) -> Self {
+        TapPayload { payload, tap }
+    }
+}
+
+impl futures_core::Stream for TapPayload {
+    type Item = Result<Bytes, PayloadError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.project();
+        let item = ready!(this.payload.poll_next(cx));
+        if let Some(Ok(chunk)) = &item {
+            this.tap.record(chunk);
+        }
+        Poll::Ready(item)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn capture() -> Capture {
+        Capture::new(64)
+            .redact_field("password")
+            .redact_field("Token")
+    }
+
+    fn body(capture: &Capture, body: &str) -> Value {
+        let tap = BodyTap::new(capture.max_body());
+        tap.record(body.as_bytes());
+        capture.body(&tap)
+    }
+
+    #[test]
+    fn default_headers_are_redacted() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, "Bearer abc".parse().unwrap());
+        headers.insert(X_API_KEY, "abc".parse().unwrap());
+        headers.insert(header::ACCEPT, "*/*".parse().unwrap());
+
+        assert_eq!(
+            capture().headers(&headers),
+            json!({"authorization": REDACTED, "x-api-key": REDACTED, "accept": "*/*"})
+        );
+    }
+
+    #[test]
+    fn json_fields_are_redacted_at_any_depth() {
+        assert_eq!(
+            body(
+                &capture(),
+                r#"{"user":"a","password":"p","auth":[{"TOKEN":1}]}"#
+            ),
+            json!({"user": "a", "password": REDACTED, "auth": [{"TOKEN": REDACTED}]})
+        );
+    }
+
+    #[test]
+    fn truncated_json_fields_are_redacted() {
+        let body = body(
+            &capture(),
+            r#"{"user":"a","token": 42,"password":"a long password cut by max_body"}"#,
+        );
+        assert_eq!(
+            body,
+            json!(r#"{"user":"a","token": "[REDACTED]","password":"[REDACTED]""#)
+        );
+    }
+
+    #[test]
+    fn form_fields_are_redacted() {
+        assert_eq!(
+            body(&capture(), "user=a&password=p&token=t&keep=k"),
+            json!("user=a&password=[REDACTED]&token=[REDACTED]&keep=k")
+        );
+        assert_eq!(
+            body(&capture(), "password=p&keep=k"),
+            json!("password=[REDACTED]&keep=k")
+        );
+    }
+
+    #[test]
+    fn text_without_fields_is_kept() {
+        assert_eq!(
+            body(&capture(), "not json, password: p"),
+            json!("not json, password: p")
+        );
+        assert_eq!(body(&Capture::new(8), "password=p"), json!("password"));
+    }
+
+    #[test]
+    fn redacted_bodies_stay_text() {
+        let redaction = Redaction::default().field("password");
+        assert_eq!(
+            redaction.body(String::from(r#"{ "user": "a", "password": "p" }"#), false),
+            r#"{"user":"a","password":"[REDACTED]"}"#
+        );
+        assert_eq!(
+            redaction.body(String::from(r#"{"password":"p"#), true),
+            r#"{"password":"[REDACTED]""#
+        );
+        assert_eq!(
+            redaction.body(String::from("user=a&password=p"), false),
+            "user=a&password=[REDACTED]"
+        );
+
+        let body = String::from(r#"{ "password": "p" }"#);
+        assert_eq!(Redaction::default().body(body.clone(), false), body);
+    }
+
+    #[test]
+    fn the_debug_header_is_only_accepted_from_the_networks() {
+        let capture = Capture::new(64)
+            .header(HeaderName::from_static("x-debug-capture"))
+            .header_network("10.0.0.0/8".parse().unwrap());
+
+        for (peer, value, wanted) in [
+            ("10.0.0.1:4000", "1", true),
+            ("10.0.0.1:4000", "0", false),
+            ("203.0.113.7:4000", "1", false),
+        ] {
+            let req = actix_web::test::TestRequest::get()
+                .insert_header(("x-debug-capture", value))
+                .peer_addr(peer.parse().unwrap())
+                .to_srv_request();
+            assert_eq!(capture.wants(&req), wanted, "{} {}", peer, value);
+        }
+    }
+}
@@ -0,0 +1,117 @@
+use std::{rc::Rc, sync::Arc};
+
+use actix_service::{boxed, ServiceExt};
+use futures::future::LocalBoxFuture;
+
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::{
+    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    Error,
+};
+
+/// A service with its request, response and body types erased.
+pub type BoxedService = boxed::BoxService<ServiceRequest, ServiceResponse<BoxBody>, Error>;
+
+type WrapFn = Rc<dyn Fn(BoxedService) -> LocalBoxFuture<'static, Result<BoxedService, ()>>>;
+
+/// Builds a middleware for each worker.
+///
+/// Most middlewares hold `Rc`s and can't be shared between workers, so the
+/// server keeps a thread safe factory and instantiates the middleware once the
+/// worker builds its `App`.
+#[derive(Clone)]
+pub struct MiddlewareFactory {
+    name: &'static str,
+    factory: Arc<dyn Fn() -> WrapFn + Send + Sync>,
+}
+
+impl MiddlewareFactory {
+    pub fn new<F, M, B>(factory: F) -> Self
+    where
+        F: Fn() -> M + Send + Sync + 'static,
+        M: Transform<
+                BoxedService,
+                ServiceRequest,
+                Response = ServiceResponse<B>,
+                Error = Error,
+                InitError = (),
+            > + 'static,
+        M::Transform: 'static,
+        M::Future: 'static,
+        B: MessageBody + 'static,
+    {
+        let factory = Arc::new(move || {
+            let middleware = Rc::new(factory());
+            Rc::new(move |service: BoxedService| {
+                let fut = middleware.new_transform(service);
+                Box::pin(async move {
+                    let transform = fut.await?;
+                    Ok(boxed::service(
+                        transform.map(|res: ServiceResponse<B>| res.map_into_boxed_body()),
+                    ))
+                }) as LocalBoxFuture<'static, Result<BoxedService, ()>>
+            }) as WrapFn
+        });
+
+        MiddlewareFactory {
+            name: short_type_name(std::any::type_name::<M>()),
+            factory,
+        }
+    }
+
+    /// The type name of the middleware without its path and generics, e.g. `RateLimiter`.
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+}
+
+fn short_type_name(name: &'static str) -> &'static str {
+    let name = name.split('<').next().unwrap_or(name);
+    name.rsplit("::").next().unwrap_or(name)
+}
+
+/// Applies a list of middlewares as one, so that a dynamic number of them can
+/// be wrapped around an `App`, `Scope` or `Resource`.
+///
+/// Like repeated `.wrap()` calls, the middleware registered last is the
+/// outermost one and sees the request first.
+#[derive(Clone, Default)]
+pub struct Chain(Vec<WrapFn>);
+
+impl Chain {
+    pub fn new(factories: &[MiddlewareFactory]) -> Self {
+        Chain(factories.iter().map(|f| (f.factory)()).collect())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for Chain
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = Error;
+    type Transform = BoxedService;
+    type InitError = ();
+    type Future = LocalBoxFuture<'static, Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        let wraps = self.0.clone();
+
+        Box::pin(async move {
+            let mut service: BoxedService =
+                boxed::service(service.map(|res: ServiceResponse<B>| res.map_into_boxed_body()));
+
+            for wrap in wraps {
+                service = wrap(service).await?;
+            }
+
+            Ok(service)
+        })
+    }
+}
@@ -0,0 +1,261 @@
+use std::rc::Rc;
+
+use actix_utils::future::{ready, Either, Ready};
+use actix_web::body::EitherBody;
+use actix_web::{
+    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    http::{
+        header::{self, HeaderValue},
+        StatusCode,
+    },
+    Error, HttpResponse,
+};
+use futures::future::LocalBoxFuture;
+
+use crate::core::proxy::TrustedProxies;
+use crate::core::settings::{PlainHttp, TlsSettings};
+
+/// Redirects plaintext requests to the HTTPS origin and adds the
+/// `Strict-Transport-Security` header to TLS responses.
+///
+/// A request is plaintext when it arrived on a listener bound without TLS,
+/// unless a trusted reverse proxy (e.g. nginx over a Unix socket) says
+/// otherwise with `Forwarded` or `X-Forwarded-Proto`. The redirects keep the
+/// `Host` of the request, or the forwarded host behind a trusted proxy.
+#[derive(Debug, Clone, Default)]
+pub struct Https(Rc<Inner>);
+
+#[derive(Debug, Default)]
+struct Inner {
+    /// Port and status of the redirects, `None` serves plaintext requests.
+    redirect: Option<(u16, StatusCode)>,
+    hsts: Option<HeaderValue>,
+    proxies: TrustedProxies,
+}
+
+impl Https {
+    pub fn new(settings: &TlsSettings) -> Https {
+        if !settings.enable {
+            return Https::default();
+        }
+
+        let redirect = if settings.http == PlainHttp::Redirect {
+            let status = StatusCode::from_u16(settings.redirect_status)
+                .unwrap_or(StatusCode::PERMANENT_REDIRECT);
+            Some((settings.port, status))
+        } else {
+            None
+        };
+
+        let hsts = if settings.hsts.enable {
+            HeaderValue::from_str(&settings.hsts.header_value()).ok()
+        } else {
+            None
+        };
+
+        Https(Rc::new(Inner {
+            redirect,
+            hsts,
+            proxies: TrustedProxies::default(),
+        }))
+    }
+
+    /// Believes the scheme and host forwarded by these proxies.
+    pub fn trusted_proxies(mut self, proxies: TrustedProxies) -> Https {
+        Rc::get_mut(&mut self.0).unwrap().proxies = proxies;
+        self
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for Https
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = HttpsMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(HttpsMiddleware {
+            service: Rc::new(service),
+            inner: self.0.clone(),
+        }))
+    }
+}
+
+pub struct HttpsMiddleware<S> {
+    service: Rc<S>,
+    inner: Rc<Inner>,
+}
+
+impl<S, B> Service<ServiceRequest> for HttpsMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = Either<
+        Ready<Result<Self::Response, Self::Error>>,
+        LocalBoxFuture<'static, Result<Self::Response, Self::Error>>,
+    >;
+
+    actix_service::forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let secure = match self.inner.proxies.forwarded_proto(req.request()) {
+            Some(proto) => proto.eq_ignore_ascii_case("https"),
+            None => req.app_config().secure(),
+        };
+
+        if !secure {
+            if let Some((port, status)) = self.inner.redirect {
+                let location = https_location(&req, &self.inner.proxies, port);
+                let res = HttpResponse::build(status)
+                    .insert_header((header::LOCATION, location))
+                    .finish();
+                return Either::left(ready(Ok(req.into_response(res).map_into_right_body())));
+            }
+        }
+
+        let hsts = if secure {
+            self.inner.hsts.clone()
+        } else {
+            None
+        };
+        let fut = self.service.call(req);
+
+        Either::right(Box::pin(async move {
+            let mut res = fut.await?;
+            if let Some(hsts) = hsts {
+                res.headers_mut()
+                    .insert(header::STRICT_TRANSPORT_SECURITY, hsts);
+            }
+            Ok(res.map_into_left_body())
+        }))
+    }
+}
+
+/// The same URL on the HTTPS origin, keeping the path and query.
+fn https_location(req: &ServiceRequest, proxies: &TrustedProxies, port: u16) -> String {
+    let host = proxies.host(req.request());
+    // strip the plaintext port, bracketed IPv6 hosts contain colons
+    let host = match host.rfind(':') {
+        Some(idx) if !host[idx..].contains(']') => &host[..idx],
+        _ => host,
+    };
+
+    let path_and_query = req
+        .uri()
+        .path_and_query()
+        .map(|pq| pq.as_str())
+        .unwrap_or("/");
+
+    if port == 443 {
+        format!("https://{}{}", host, path_and_query)
+    } else {
+        format!("https://{}:{}{}", host, port, path_and_query)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        test::{call_service, init_service, TestRequest},
+        web, App,
+    };
+
+    use super::*;
+    use crate::core::settings::HstsSettings;
+
+    const PROXY: &str = "10.0.0.2:40000";
+    const CLIENT: &str = "203.0.113.7:40000";
+    const HSTS: &str = "max-age=31536000";
+
+    fn https() -> Https {
+        let settings = TlsSettings {
+            enable: true,
+            http: PlainHttp::Redirect,
+            hsts: HstsSettings {
+                enable: true,
+                ..HstsSettings::default()
+            },
+            ..TlsSettings::default()
+        };
+        Https::new(&settings)
+            .trusted_proxies(TrustedProxies::new(vec!["10.0.0.2/32".parse().unwrap()]))
+    }
+
+    /// Status, `Location` and `Strict-Transport-Security` of the response.
+    async fn serve(req: TestRequest) -> (StatusCode, Option<String>, Option<String>) {
+        let app = init_service(
+            App::new()
+                .wrap(https())
+                .route("/{tail:.*}", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let res = call_service(&app, req.to_request()).await;
+        let header = |name| {
+            res.headers()
+                .get(name)
+                .map(|v: &HeaderValue| v.to_str().unwrap().to_owned())
+        };
+        (
+            res.status(),
+            header(header::LOCATION),
+            header(header::STRICT_TRANSPORT_SECURITY),
+        )
+    }
+
+    fn request(peer: &str) -> TestRequest {
+        TestRequest::get()
+            .uri("/users/42?verbose=1")
+            .insert_header((header::HOST, "example.com:8080"))
+            .peer_addr(peer.parse().unwrap())
+    }
+
+    #[actix_web::test]
+    async fn plaintext_is_redirected() {
+        let (status, location, hsts) = serve(request(CLIENT)).await;
+        assert_eq!(status, StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(
+            location.as_deref(),
+            Some("https://example.com:8443/users/42?verbose=1")
+        );
+        assert_eq!(hsts, None);
+    }
+
+    #[actix_web::test]
+    async fn forwarded_proto_is_believed_from_trusted_proxies() {
+        let req = request(PROXY).insert_header(("x-forwarded-proto", "https"));
+        let (status, location, hsts) = serve(req).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(location, None);
+        assert_eq!(hsts.as_deref(), Some(HSTS));
+
+        let req = request(CLIENT).insert_header(("x-forwarded-proto", "https"));
+        let (status, _, hsts) = serve(req).await;
+        assert_eq!(status, StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(hsts, None);
+    }
+
+    #[actix_web::test]
+    async fn forwarded_host_is_believed_from_trusted_proxies() {
+        let req = request(PROXY).insert_header(("x-forwarded-host", "public.example.com"));
+        let (_, location, _) = serve(req).await;
+        assert_eq!(
+            location.as_deref(),
+            Some("https://public.example.com:8443/users/42?verbose=1")
+        );
+
+        let req = request(CLIENT).insert_header(("x-forwarded-host", "evil.example.com"));
+        let (_, location, _) = serve(req).await;
+        assert_eq!(
+            location.as_deref(),
+            Some("https://example.com:8443/users/42?verbose=1")
+        );
+    }
+}
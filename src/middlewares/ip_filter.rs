@@ -0,0 +1,232 @@
+use std::{net::IpAddr, rc::Rc, sync::Arc};
+
+use actix_utils::future::{ready, Either, Ready};
+use actix_web::{
+    body::EitherBody,
+    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    Error, HttpResponse,
+};
+use futures::future::LocalBoxFuture;
+use ipnet::IpNet;
+
+use crate::core::{proxy::TrustedProxies, routes::routed_path};
+
+/// Allows or denies the requests by the address of the client, per path
+/// prefix, e.g. to keep a scope to the internal networks:
+///
+/// ```ignore
+/// IpFilter::new()
+///     .trusted_proxies(settings.server.trusted_proxies())
+///     .scope("/developer", IpRules::default().allow("10.0.0.0/8".parse().unwrap()))
+/// ```
+///
+/// The rules of the longest prefix matching the decoded path apply, `/` matching every
+/// path, and the requests matching no prefix aren't filtered. The denied
+/// requests get a `403`.
+///
+/// Wrap it outside of the other middlewares, the rate limit in particular, so
+/// that they don't see the denied requests. The client address is taken from
+/// the forwarded headers only behind a trusted proxy, see `TrustedProxies`.
+#[derive(Debug, Clone, Default)]
+pub struct IpFilter(Arc<Inner>);
+
+#[derive(Debug, Default)]
+struct Inner {
+    /// Longest prefix first.
+    scopes: Vec<(String, IpRules)>,
+    proxies: TrustedProxies,
+}
+
+/// The networks allowed and denied. An address in both lists is denied, and
+/// an empty allow list allows every address that isn't denied.
+#[derive(Debug, Clone, Default)]
+pub struct IpRules {
+    allow: Vec<IpNet>,
+    deny: Vec<IpNet>,
+}
+
+impl IpRules {
+    pub fn allow(mut self, network: IpNet) -> Self {
+        self.allow.push(network);
+        self
+    }
+
+    pub fn deny(mut self, network: IpNet) -> Self {
+        self.deny.push(network);
+        self
+    }
+
+    fn is_allowed(&self, addr: Option<IpAddr>) -> bool {
+        match addr {
+            Some(ip) => {
+                !self.deny.iter().any(|net| net.contains(&ip))
+                    && (self.allow.is_empty() || self.allow.iter().any(|net| net.contains(&ip)))
+            }
+            None => self.allow.is_empty(),
+        }
+    }
+}
+
+impl IpFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Filters the paths under `prefix` with `rules`.
+    pub fn scope(mut self, prefix: &str, rules: IpRules) -> Self {
+        let inner = Arc::get_mut(&mut self.0).unwrap();
+        inner
+            .scopes
+            .push((prefix.trim_end_matches('/').to_string(), rules));
+        inner
+            .scopes
+            .sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+        self
+    }
+
+    /// Takes the client address from the forwarded headers of these proxies.
+    pub fn trusted_proxies(mut self, proxies: TrustedProxies) -> Self {
+        Arc::get_mut(&mut self.0).unwrap().proxies = proxies;
+        self
+    }
+}
+
+impl Inner {
+    fn rules_for(&self, path: &str) -> Option<&IpRules> {
+        self.scopes
+            .iter()
+            .find(|(prefix, _)| match path.strip_prefix(prefix.as_str()) {
+                Some(rest) => rest.is_empty() || rest.starts_with('/'),
+                None => false,
+            })
+            .map(|(_, rules)| rules)
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for IpFilter
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = IpFilterMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(IpFilterMiddleware {
+            service: Rc::new(service),
+            inner: self.0.clone(),
+        }))
+    }
+}
+
+pub struct IpFilterMiddleware<S> {
+    service: Rc<S>,
+    inner: Arc<Inner>,
+}
+
+impl<S, B> Service<ServiceRequest> for IpFilterMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = Either<
+        Ready<Result<Self::Response, Self::Error>>,
+        LocalBoxFuture<'static, Result<Self::Response, Self::Error>>,
+    >;
+
+    actix_service::forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let denied = match self.inner.rules_for(routed_path(req.request())) {
+            Some(rules) => {
+                let addr = self.inner.proxies.client_addr(req.request());
+                (!rules.is_allowed(addr)).then_some(addr)
+            }
+            None => None,
+        };
+        if let Some(addr) = denied {
+            let address = addr.map(|ip| ip.to_string());
+            let res = HttpResponse::Forbidden().json(serde_json::json!({
+                "status": 403,
+                "error": "Forbidden",
+                "message": format!(
+                    "Access from {} is not allowed",
+                    address.as_deref().unwrap_or("an unknown address")
+                ),
+                "address": address,
+            }));
+            return Either::left(ready(Ok(req.into_response(res).map_into_right_body())));
+        }
+
+        let fut = self.service.call(req);
+        Either::right(Box::pin(async move { Ok(fut.await?.map_into_left_body()) }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{middleware, test, web, App, HttpResponse};
+
+    use super::*;
+
+    #[actix_web::test]
+    async fn encoded_paths_are_filtered() {
+        let filter = IpFilter::new().scope(
+            "/developer",
+            IpRules::default().allow("10.0.0.0/8".parse().unwrap()),
+        );
+        let app = test::init_service(
+            App::new()
+                .wrap(filter)
+                .wrap(middleware::NormalizePath::trim())
+                .service(
+                    web::scope("/developer").route("/routes", web::get().to(HttpResponse::Ok)),
+                ),
+        )
+        .await;
+
+        for (peer, path, allowed) in [
+            ("10.0.0.1:4000", "/%64eveloper/routes", true),
+            ("203.0.113.7:4000", "/developer/routes", false),
+            ("203.0.113.7:4000", "/%64eveloper/routes", false),
+            ("203.0.113.7:4000", "/developer/%72outes/", false),
+        ] {
+            let req = test::TestRequest::get()
+                .uri(path)
+                .peer_addr(peer.parse().unwrap())
+                .to_request();
+            let res = test::call_service(&app, req).await;
+            assert_eq!(res.status().is_success(), allowed, "{} {}", peer, path);
+        }
+    }
+
+    #[actix_web::test]
+    async fn metrics_are_internal_by_default() {
+        let filter =
+            crate::core::settings::IpFilterSettings::default().ip_filter(TrustedProxies::default());
+        let app = test::init_service(
+            App::new()
+                .wrap(filter)
+                .route("/metrics", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        for (peer, path, allowed) in [
+            ("127.0.0.1:4000", "/metrics", true),
+            ("203.0.113.7:4000", "/metrics", false),
+            ("203.0.113.7:4000", "/%6detrics", false),
+        ] {
+            let req = test::TestRequest::get()
+                .uri(path)
+                .peer_addr(peer.parse().unwrap())
+                .to_request();
+            let res = test::call_service(&app, req).await;
+            assert_eq!(res.status().is_success(), allowed, "{} {}", peer, path);
+        }
+    }
+}
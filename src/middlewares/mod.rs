@@ -1 +1,8 @@
 pub mod access_filter;
+pub mod capture;
+pub mod chain;
+pub mod https;
+pub mod ip_filter;
+pub mod rate_limit;
+pub mod recorder;
+pub mod request_id;
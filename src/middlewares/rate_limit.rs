@@ -0,0 +1,992 @@
+use std::{
+    collections::{HashMap, HashSet},
+    convert::Infallible,
+    fmt,
+    net::{IpAddr, Ipv6Addr},
+    rc::Rc,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use actix_extensible_rate_limit::backend::Backend;
+use actix_utils::future::{ready, Ready};
+use actix_web::{
+    body::EitherBody,
+    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    http::{
+        header::{self, HeaderMap, HeaderName, HeaderValue},
+        Method,
+    },
+    rt::time::Instant,
+    Error, HttpResponse,
+};
+use async_trait::async_trait;
+use futures::future::LocalBoxFuture;
+use ipnet::IpNet;
+use openssl::sha::sha256;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::core::proxy::TrustedProxies;
+use crate::core::routes::routed_path;
+use crate::core::tls::PeerIdentity;
+use crate::repository::rate_limit_repo::MongoBackend;
+use crate::utils::parse::canonical_ip;
+
+pub const RATELIMIT_LIMIT: HeaderName = HeaderName::from_static("ratelimit-limit");
+pub const RATELIMIT_REMAINING: HeaderName = HeaderName::from_static("ratelimit-remaining");
+pub const RATELIMIT_RESET: HeaderName = HeaderName::from_static("ratelimit-reset");
+pub const RATELIMIT_POLICY: HeaderName = HeaderName::from_static("ratelimit-policy");
+pub const X_API_KEY: HeaderName = HeaderName::from_static("x-api-key");
+
+/// Time between two sweeps of the expired windows of `MemoryBackend`.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// What the requests are counted by.
+///
+/// The strategies identifying the client by something else than its address
+/// count the requests without it by address, like `Ip`. `ApiKey` and `Header`
+/// take values the client chooses, so they count the values that aren't in
+/// the `keys` of the policy by address too: rotating them doesn't give a
+/// client a fresh quota.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyStrategy {
+    /// The client address, from `Forwarded` or `X-Forwarded-For` behind a
+    /// trusted proxy. IPv6 clients are counted by /64.
+    Ip,
+    /// The address of the connection, for clients connecting directly.
+    Peer,
+    /// One counter for every client.
+    Global,
+    /// The `X-Api-Key` header, or the token of an `Authorization: Bearer`
+    /// header. Only a digest of the key is kept.
+    ApiKey,
+    /// The common name of the client certificate, its subject when it has
+    /// none.
+    Principal,
+    /// The value of a header, e.g. `{ header: x-tenant-id }`.
+    Header(String),
+    /// A named group of the path regex of the rule, e.g. `{ param: id }` with
+    /// `^/user/(?P<id>[^/]+)$`.
+    Param(String),
+}
+
+/// How the requests of a key are counted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Algorithm {
+    /// A counter restarting at the end of each window, which lets twice the
+    /// limit through around the start of a window.
+    #[default]
+    FixedWindow,
+    /// The count of the current window plus the count of the previous one,
+    /// weighted by the part of it still within a window from now.
+    SlidingWindow,
+    /// A bucket of `burst` tokens refilled at `limit` per window, each request
+    /// taking one.
+    TokenBucket,
+}
+
+/// `limit` requests per `window` for each key.
+#[derive(Debug, Clone)]
+pub struct Policy {
+    /// Counters are shared by the rules using the same policy.
+    pub name: String,
+    pub window: Duration,
+    pub limit: u64,
+    pub algorithm: Algorithm,
+    /// Tokens of a full bucket, for `Algorithm::TokenBucket`.
+    pub burst: u64,
+    pub key: KeyStrategy,
+    /// The API keys or header values counted on their own for
+    /// `KeyStrategy::ApiKey` and `KeyStrategy::Header`.
+    pub keys: HashSet<String>,
+    /// Adds the `RateLimit-*` headers to the responses.
+    pub headers: bool,
+}
+
+/// A request to count, the input of the backends.
+#[derive(Debug, Clone)]
+pub struct LimitInput {
+    pub key: String,
+    pub algorithm: Algorithm,
+    pub window: Duration,
+    pub limit: u64,
+    pub burst: u64,
+}
+
+impl LimitInput {
+    /// Tokens added to the bucket per second.
+    pub fn rate(&self) -> f64 {
+        self.limit as f64 / self.window.as_secs_f64()
+    }
+
+    /// The requests a client can make right away with a full quota.
+    pub fn quota(&self) -> u64 {
+        match self.algorithm {
+            Algorithm::TokenBucket => self.burst,
+            _ => self.limit,
+        }
+    }
+
+    /// Time from the start of the current sliding window until `count`
+    /// requests in it and `previous` in the previous one leave room for one
+    /// more.
+    pub fn sliding_wait(&self, previous: u64, count: u64) -> Duration {
+        let window = self.window.as_secs_f64();
+        let room = self.limit as f64 - 1.0;
+        let secs = if room < 0.0 {
+            // never, until both windows are over
+            2.0 * window
+        } else if (count as f64) <= room {
+            // the previous window has to weigh less
+            window * (1.0 - (room - count as f64) / previous.max(1) as f64)
+        } else {
+            // the current window becomes the previous one
+            window + window * (1.0 - room / count as f64)
+        };
+        Duration::from_secs_f64(secs.max(0.0))
+    }
+}
+
+/// The state of the quota of a key after a request.
+#[derive(Debug, Clone)]
+pub struct LimitOutput {
+    /// Requests allowed with a full quota.
+    pub limit: u64,
+    pub remaining: u64,
+    /// When the quota is full again.
+    pub reset: Instant,
+    /// When the next request is allowed.
+    pub retry: Instant,
+}
+
+/// The client of a request, as seen by a policy.
+struct Client {
+    /// The connection address for `KeyStrategy::Peer`, the client address
+    /// otherwise.
+    addr: Option<IpAddr>,
+    /// The key, API key or principal identifying the client, if any.
+    id: Option<String>,
+}
+
+impl Policy {
+    fn client(&self, req: &ServiceRequest, path: &Regex, proxies: &TrustedProxies) -> Client {
+        let addr = match self.key {
+            KeyStrategy::Peer => req.peer_addr().map(|addr| canonical_ip(addr.ip())),
+            _ => proxies.client_addr(req.request()),
+        };
+        let id = match &self.key {
+            KeyStrategy::Ip | KeyStrategy::Peer | KeyStrategy::Global => None,
+            KeyStrategy::ApiKey => api_key(req.headers()).map(str::to_string),
+            KeyStrategy::Principal => req.conn_data::<PeerIdentity>().map(|peer| {
+                peer.common_name
+                    .clone()
+                    .unwrap_or_else(|| peer.subject.clone())
+            }),
+            KeyStrategy::Header(name) => req
+                .headers()
+                .get(name.as_str())
+                .and_then(|value| value.to_str().ok())
+                .filter(|value| !value.is_empty())
+                .map(str::to_string),
+            KeyStrategy::Param(name) => path
+                .captures(routed_path(req.request()))
+                .and_then(|captures| captures.name(name))
+                .map(|param| param.as_str().to_string()),
+        };
+        Client { addr, id }
+    }
+
+    /// Whether the requests of `id` are counted on their own.
+    fn counts(&self, id: &str) -> bool {
+        match self.key {
+            KeyStrategy::ApiKey | KeyStrategy::Header(_) => self.keys.contains(id),
+            _ => true,
+        }
+    }
+
+    /// The key the client is counted by, prefixed with the policy name.
+    fn key(&self, client: &Client) -> String {
+        let id = client.id.as_ref().filter(|id| self.counts(id));
+        match (&self.key, id) {
+            (KeyStrategy::Global, _) => format!("{}:", self.name),
+            (KeyStrategy::ApiKey, Some(id)) => format!("{}:key:{}", self.name, digest(id)),
+            (_, Some(id)) => format!("{}:id:{}", self.name, id),
+            (_, None) => match client.addr {
+                Some(addr) => format!("{}:{}", self.name, ip_key(addr)),
+                None => format!("{}:-", self.name),
+            },
+        }
+    }
+
+    /// The 429 response, with a JSON body telling when to retry.
+    fn denied(&self, output: &LimitOutput) -> HttpResponse {
+        let retry_after = seconds_until(output.retry).max(1);
+        let mut res = HttpResponse::TooManyRequests().json(serde_json::json!({
+            "status": 429,
+            "error": "Too Many Requests",
+            "message": format!(
+                "Rate limit of {} requests per {}s exceeded, retry in {}s",
+                self.limit,
+                self.window.as_secs(),
+                retry_after
+            ),
+            "policy": self.name,
+            "algorithm": self.algorithm,
+            "limit": self.limit,
+            "window": self.window.as_secs(),
+            "retry_after": retry_after,
+        }));
+        let headers = res.headers_mut();
+        headers.insert(header::RETRY_AFTER, HeaderValue::from(retry_after));
+        if self.headers {
+            self.insert_headers(headers, output);
+        }
+        res
+    }
+
+    /// The `RateLimit-*` headers of draft-ietf-httpapi-ratelimit-headers.
+    fn insert_headers(&self, headers: &mut HeaderMap, output: &LimitOutput) {
+        headers.insert(RATELIMIT_LIMIT, HeaderValue::from(output.limit));
+        headers.insert(RATELIMIT_REMAINING, HeaderValue::from(output.remaining));
+        headers.insert(
+            RATELIMIT_RESET,
+            HeaderValue::from(seconds_until(output.reset)),
+        );
+        let policy = match self.algorithm {
+            Algorithm::TokenBucket => format!(
+                "{};w={};burst={}",
+                self.limit,
+                self.window.as_secs(),
+                self.burst
+            ),
+            _ => format!("{};w={}", self.limit, self.window.as_secs()),
+        };
+        if let Ok(policy) = HeaderValue::try_from(policy) {
+            headers.insert(RATELIMIT_POLICY, policy);
+        }
+    }
+}
+
+/// Limits the requests matching a rule with its policy, the first matching
+/// rule wins and the other requests aren't limited.
+///
+/// ```ignore
+/// RateLimit::new(backend.clone())
+///     .rule(writes, &[Method::POST], "^/user$")
+///     .rule(developer, &[], "^/developer(/|$)")
+///     .bypass_network("10.0.0.0/8".parse().unwrap())
+/// ```
+///
+/// The counters are kept by the backend, so that workers sharing a clone of
+/// it share the limits.
+pub struct RateLimit<B = MemoryBackend>(Rc<Inner<B>>);
+
+struct Inner<B> {
+    backend: B,
+    rules: Vec<LimitRule>,
+    /// Clients never limited, by address or by key.
+    bypass_networks: Vec<IpNet>,
+    bypass_keys: HashSet<String>,
+    proxies: TrustedProxies,
+    /// Takes back the requests answered with a server error.
+    rollback_server_errors: bool,
+}
+
+struct LimitRule {
+    /// Any method when empty.
+    methods: Vec<Method>,
+    path: Regex,
+    policy: Rc<Policy>,
+}
+
+impl<B> RateLimit<B> {
+    pub fn new(backend: B) -> Self {
+        RateLimit(Rc::new(Inner {
+            backend,
+            rules: vec![],
+            bypass_networks: vec![],
+            bypass_keys: HashSet::new(),
+            proxies: TrustedProxies::default(),
+            rollback_server_errors: false,
+        }))
+    }
+
+    /// Applies `policy` to the requests with one of `methods`, any when
+    /// empty, on a path matching the `path` regex.
+    pub fn rule(mut self, policy: Policy, methods: &[Method], path: &str) -> Self {
+        Rc::get_mut(&mut self.0).unwrap().rules.push(LimitRule {
+            methods: methods.to_vec(),
+            path: Regex::new(path).unwrap(),
+            policy: Rc::new(policy),
+        });
+        self
+    }
+
+    /// Takes the client address from the forwarded headers of these proxies.
+    pub fn trusted_proxies(mut self, proxies: TrustedProxies) -> Self {
+        Rc::get_mut(&mut self.0).unwrap().proxies = proxies;
+        self
+    }
+
+    /// Doesn't count the requests answered with a 5xx against the quota of
+    /// the client, so that it can retry them.
+    pub fn rollback_server_errors(mut self, enable: bool) -> Self {
+        Rc::get_mut(&mut self.0).unwrap().rollback_server_errors = enable;
+        self
+    }
+
+    /// Never limits the clients in `network`, by the address their policy
+    /// sees.
+    pub fn bypass_network(mut self, network: IpNet) -> Self {
+        Rc::get_mut(&mut self.0)
+            .unwrap()
+            .bypass_networks
+            .push(network);
+        self
+    }
+
+    /// Never limits the clients identified by `key`: the API key, principal,
+    /// header or path parameter their policy counts them by.
+    pub fn bypass_key(mut self, key: &str) -> Self {
+        Rc::get_mut(&mut self.0)
+            .unwrap()
+            .bypass_keys
+            .insert(key.to_string());
+        self
+    }
+}
+
+impl<B> Inner<B> {
+    fn rule_for(&self, req: &ServiceRequest) -> Option<&LimitRule> {
+        self.rules.iter().find(|rule| {
+            (rule.methods.is_empty() || rule.methods.contains(req.method()))
+                && rule.path.is_match(routed_path(req.request()))
+        })
+    }
+
+    fn bypassed(&self, client: &Client) -> bool {
+        if let Some(addr) = client.addr {
+            if self.bypass_networks.iter().any(|net| net.contains(&addr)) {
+                return true;
+            }
+        }
+        match &client.id {
+            Some(id) => self.bypass_keys.contains(id),
+            None => false,
+        }
+    }
+}
+
+impl<S, B, BA> Transform<S, ServiceRequest> for RateLimit<BA>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+    BA: Backend<LimitInput, Output = LimitOutput> + 'static,
+    BA::Error: fmt::Display,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = RateLimitMiddleware<S, BA>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimitMiddleware {
+            service: Rc::new(service),
+            inner: self.0.clone(),
+        }))
+    }
+}
+
+pub struct RateLimitMiddleware<S, BA> {
+    service: Rc<S>,
+    inner: Rc<Inner<BA>>,
+}
+
+impl<S, B, BA> Service<ServiceRequest> for RateLimitMiddleware<S, BA>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+    BA: Backend<LimitInput, Output = LimitOutput> + 'static,
+    BA::Error: fmt::Display,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    actix_service::forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let (policy, client) = match self.inner.rule_for(&req) {
+            Some(rule) => (
+                rule.policy.clone(),
+                rule.policy.client(&req, &rule.path, &self.inner.proxies),
+            ),
+            None => return self.pass(req),
+        };
+        if self.inner.bypassed(&client) {
+            return self.pass(req);
+        }
+
+        let input = LimitInput {
+            key: policy.key(&client),
+            algorithm: policy.algorithm,
+            window: policy.window,
+            limit: policy.limit,
+            burst: policy.burst,
+        };
+        let backend = self.inner.backend.clone();
+        let service = self.service.clone();
+        let rollback = self.inner.rollback_server_errors;
+
+        Box::pin(async move {
+            let (output, token) = match backend.request(input).await {
+                Ok((true, output, token)) => (Some(output), Some(token)),
+                Ok((false, output, _)) => {
+                    let res = policy.denied(&output);
+                    return Ok(req.into_response(res).map_into_right_body());
+                }
+                Err(err) => {
+                    // better to serve the request than to deny everything
+                    log::warn!("Rate limit {} unavailable: {}", policy.name, err);
+                    (None, None)
+                }
+            };
+
+            let mut res = service.call(req).await?;
+            if let (true, Some(token)) = (rollback && res.status().is_server_error(), token) {
+                if let Err(err) = backend.rollback(token).await {
+                    log::warn!("Couldn't roll back the rate limit {}: {}", policy.name, err);
+                }
+            }
+            if let (true, Some(output)) = (policy.headers, output) {
+                policy.insert_headers(res.headers_mut(), &output);
+            }
+            Ok(res.map_into_left_body())
+        })
+    }
+}
+
+impl<S, B, BA> RateLimitMiddleware<S, BA>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    /// Serves the request without counting it.
+    fn pass(
+        &self,
+        req: ServiceRequest,
+    ) -> LocalBoxFuture<'static, Result<ServiceResponse<EitherBody<B>>, Error>> {
+        let fut = self.service.call(req);
+        Box::pin(async move { Ok(fut.await?.map_into_left_body()) })
+    }
+}
+
+/// Seconds left until `reset`, rounded up.
+fn seconds_until(reset: Instant) -> u64 {
+    let left = reset.saturating_duration_since(Instant::now());
+    left.as_secs() + u64::from(left.subsec_nanos() > 0)
+}
+
+/// IPv6 addresses grouped by /64 since a client usually gets a whole prefix.
+fn ip_key(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V6(v6) => {
+            let s = v6.segments();
+            format!("{}/64", Ipv6Addr::new(s[0], s[1], s[2], s[3], 0, 0, 0, 0))
+        }
+        ip => ip.to_string(),
+    }
+}
+
+/// The `X-Api-Key` header, or the token of an `Authorization: Bearer` header.
+fn api_key(headers: &HeaderMap) -> Option<&str> {
+    if let Some(key) = headers.get(X_API_KEY).and_then(|v| v.to_str().ok()) {
+        return Some(key).filter(|key| !key.is_empty());
+    }
+    let authorization = headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = authorization.split_once(' ')?;
+    Some(token.trim()).filter(|token| scheme.eq_ignore_ascii_case("bearer") && !token.is_empty())
+}
+
+/// Hex of the first bytes of the SHA-256 of `key`, so that the counters don't
+/// keep the secret.
+fn digest(key: &str) -> String {
+    sha256(key.as_bytes())[..12]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Counters kept in memory, shared by the clones of the backend.
+#[derive(Clone, Default)]
+pub struct MemoryBackend(Arc<Mutex<Counters>>);
+
+#[derive(Default)]
+struct Counters {
+    counters: HashMap<String, Counter>,
+    next_sweep: Option<Instant>,
+}
+
+struct Counter {
+    state: State,
+    /// When the counter is back to its initial state and can be dropped.
+    expires: Instant,
+}
+
+enum State {
+    Window {
+        start: Instant,
+        count: u64,
+        /// Count of the previous window, for `Algorithm::SlidingWindow`.
+        previous: u64,
+    },
+    Bucket {
+        tokens: f64,
+        updated: Instant,
+    },
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Counter {
+    fn new(input: &LimitInput, now: Instant) -> Counter {
+        let state = match input.algorithm {
+            Algorithm::TokenBucket => State::Bucket {
+                tokens: input.burst as f64,
+                updated: now,
+            },
+            _ => State::Window {
+                start: now,
+                count: 0,
+                previous: 0,
+            },
+        };
+        Counter {
+            state,
+            expires: now,
+        }
+    }
+
+    /// Counts a request at `now`, unless it is denied.
+    fn request(&mut self, input: &LimitInput, now: Instant) -> (bool, LimitOutput) {
+        let window = input.window;
+        let (allow, remaining, reset, retry) = match &mut self.state {
+            State::Window {
+                start,
+                count,
+                previous,
+            } if input.algorithm == Algorithm::SlidingWindow => {
+                let elapsed = now.duration_since(*start);
+                if elapsed >= window * 2 {
+                    *start = now;
+                    *previous = 0;
+                    *count = 0;
+                } else if elapsed >= window {
+                    *start += window;
+                    *previous = *count;
+                    *count = 0;
+                }
+                let weight = 1.0 - now.duration_since(*start).as_secs_f64() / window.as_secs_f64();
+                let mut used = *previous as f64 * weight + *count as f64;
+                let allow = used + 1.0 <= input.limit as f64;
+                if allow {
+                    *count += 1;
+                    used += 1.0;
+                }
+                let retry = if allow {
+                    now
+                } else {
+                    *start + input.sliding_wait(*previous, *count)
+                };
+                let reset = if *count > 0 {
+                    *start + window * 2
+                } else {
+                    *start + window
+                };
+                self.expires = *start + window * 2;
+                let remaining = (input.limit as f64 - used).max(0.0) as u64;
+                (allow, remaining, reset, retry)
+            }
+            State::Window { start, count, .. } => {
+                if now.duration_since(*start) >= window {
+                    *start = now;
+                    *count = 0;
+                }
+                let allow = *count < input.limit;
+                if allow {
+                    *count += 1;
+                }
+                let reset = *start + window;
+                self.expires = reset;
+                let retry = if allow { now } else { reset };
+                (allow, input.limit.saturating_sub(*count), reset, retry)
+            }
+            State::Bucket { tokens, updated } => {
+                let rate = input.rate();
+                let burst = input.burst as f64;
+                *tokens = (*tokens + now.duration_since(*updated).as_secs_f64() * rate).min(burst);
+                *updated = now;
+                let allow = *tokens >= 1.0;
+                if allow {
+                    *tokens -= 1.0;
+                }
+                let reset = now + Duration::from_secs_f64((burst - *tokens).max(0.0) / rate);
+                self.expires = reset;
+                let retry = if allow {
+                    now
+                } else {
+                    now + Duration::from_secs_f64((1.0 - *tokens) / rate)
+                };
+                (allow, *tokens as u64, reset, retry)
+            }
+        };
+        let output = LimitOutput {
+            limit: input.quota(),
+            remaining,
+            reset,
+            retry,
+        };
+        (allow, output)
+    }
+
+    fn rollback(&mut self) {
+        match &mut self.state {
+            State::Window { count, .. } => *count = count.saturating_sub(1),
+            State::Bucket { tokens, .. } => *tokens += 1.0,
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl Backend<LimitInput> for MemoryBackend {
+    type Output = LimitOutput;
+    type RollbackToken = String;
+    type Error = Infallible;
+
+    async fn request(
+        &self,
+        input: LimitInput,
+    ) -> Result<(bool, Self::Output, Self::RollbackToken), Self::Error> {
+        let now = Instant::now();
+        let mut counters = self.0.lock().unwrap();
+
+        if !matches!(counters.next_sweep, Some(sweep) if sweep > now) {
+            counters.counters.retain(|_, counter| counter.expires > now);
+            counters.next_sweep = Some(now + SWEEP_INTERVAL);
+        }
+
+        let (allow, output) = counters
+            .counters
+            .entry(input.key.clone())
+            .or_insert_with(|| Counter::new(&input, now))
+            .request(&input, now);
+        Ok((allow, output, input.key))
+    }
+
+    async fn rollback(&self, key: Self::RollbackToken) -> Result<(), Self::Error> {
+        if let Some(counter) = self.0.lock().unwrap().counters.get_mut(&key) {
+            counter.rollback();
+        }
+        Ok(())
+    }
+}
+
+/// Where a request was counted, to roll it back.
+#[derive(Debug, Clone)]
+pub enum Counted {
+    Memory(String),
+    /// The document of the window or bucket.
+    Mongo(String, Algorithm),
+}
+
+/// The backend chosen by the settings.
+#[derive(Clone)]
+pub enum Store {
+    /// Counters of this instance only.
+    Memory(MemoryBackend),
+    /// Counters shared by the instances using the same database.
+    Mongo(MongoBackend),
+}
+
+#[async_trait(?Send)]
+impl Backend<LimitInput> for Store {
+    type Output = LimitOutput;
+    type RollbackToken = Counted;
+    type Error = Infallible;
+
+    async fn request(
+        &self,
+        input: LimitInput,
+    ) -> Result<(bool, Self::Output, Self::RollbackToken), Self::Error> {
+        match self {
+            Store::Memory(backend) => {
+                let (allow, output, key) = backend.request(input).await?;
+                Ok((allow, output, Counted::Memory(key)))
+            }
+            Store::Mongo(backend) => backend.request(input).await,
+        }
+    }
+
+    async fn rollback(&self, token: Self::RollbackToken) -> Result<(), Self::Error> {
+        match (self, token) {
+            (Store::Memory(backend), Counted::Memory(key)) => backend.rollback(key).await,
+            (Store::Mongo(backend), token) => backend.rollback(token).await,
+            (Store::Memory(_), Counted::Mongo(..)) => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        test::{call_service, init_service, TestRequest},
+        web, App,
+    };
+
+    use super::*;
+
+    fn policy(algorithm: Algorithm, limit: u64, burst: u64) -> Policy {
+        Policy {
+            name: String::from("test"),
+            window: Duration::from_secs(10),
+            limit,
+            algorithm,
+            burst,
+            key: KeyStrategy::Ip,
+            keys: HashSet::new(),
+            headers: true,
+        }
+    }
+
+    fn input(algorithm: Algorithm, limit: u64, burst: u64) -> LimitInput {
+        LimitInput {
+            key: String::from("test:203.0.113.7"),
+            algorithm,
+            window: Duration::from_secs(10),
+            limit,
+            burst,
+        }
+    }
+
+    fn secs(secs: f64) -> Duration {
+        Duration::from_secs_f64(secs)
+    }
+
+    fn assert_near(actual: Duration, expected: Duration) {
+        let diff = actual.max(expected) - actual.min(expected);
+        assert!(
+            diff < Duration::from_millis(1),
+            "{:?} != {:?}",
+            actual,
+            expected
+        );
+    }
+
+    /// Counts a request at `t0 + at` seconds, returning whether it is allowed,
+    /// the remaining quota and the seconds until the reset and the retry.
+    fn request(
+        counter: &mut Counter,
+        input: &LimitInput,
+        t0: Instant,
+        at: f64,
+    ) -> (bool, u64, Duration, Duration) {
+        let now = t0 + secs(at);
+        let (allow, output) = counter.request(input, now);
+        (
+            allow,
+            output.remaining,
+            output.reset - now,
+            output.retry - now,
+        )
+    }
+
+    #[test]
+    fn fixed_window_does_not_count_denied_requests() {
+        let input = input(Algorithm::FixedWindow, 2, 0);
+        let t0 = Instant::now();
+        let mut counter = Counter::new(&input, t0);
+
+        let (allow, remaining, reset, _) = request(&mut counter, &input, t0, 0.0);
+        assert!(allow);
+        assert_eq!(remaining, 1);
+        assert_near(reset, secs(10.0));
+        assert!(request(&mut counter, &input, t0, 1.0).0);
+        for at in [2.0, 3.0, 9.0] {
+            let (allow, remaining, reset, retry) = request(&mut counter, &input, t0, at);
+            assert!(!allow);
+            assert_eq!(remaining, 0);
+            assert_near(reset, secs(10.0 - at));
+            assert_near(retry, secs(10.0 - at));
+        }
+        assert!(matches!(counter.state, State::Window { count: 2, .. }));
+
+        let (allow, remaining, reset, _) = request(&mut counter, &input, t0, 10.0);
+        assert!(allow);
+        assert_eq!(remaining, 1);
+        assert_near(reset, secs(10.0));
+    }
+
+    #[test]
+    fn sliding_window_weighs_the_previous_window() {
+        let input = input(Algorithm::SlidingWindow, 4, 0);
+        let t0 = Instant::now();
+        let mut counter = Counter::new(&input, t0);
+
+        for remaining in (0..4).rev() {
+            assert_eq!(request(&mut counter, &input, t0, 0.0).1, remaining);
+        }
+        // 4 requests in the window, room for one more once it weighs 3
+        let (allow, remaining, _, retry) = request(&mut counter, &input, t0, 1.0);
+        assert!(!allow);
+        assert_eq!(remaining, 0);
+        assert_near(retry, secs(11.5));
+        assert!(matches!(counter.state, State::Window { count: 4, .. }));
+
+        assert!(!request(&mut counter, &input, t0, 10.0).0);
+        let (allow, remaining, reset, _) = request(&mut counter, &input, t0, 12.5);
+        assert!(allow);
+        assert_eq!(remaining, 0);
+        assert_near(reset, secs(17.5));
+
+        // both windows over
+        let (allow, remaining, _, _) = request(&mut counter, &input, t0, 30.0);
+        assert!(allow);
+        assert_eq!(remaining, 3);
+    }
+
+    #[test]
+    fn sliding_wait() {
+        let input = input(Algorithm::SlidingWindow, 5, 0);
+        // the previous window has to weigh 2 of its 10 requests, at 8s
+        assert_near(input.sliding_wait(10, 2), secs(8.0));
+        // room right away
+        assert_near(input.sliding_wait(0, 2), secs(0.0));
+        // 6 requests in the current window have to weigh 4 once previous
+        assert_near(input.sliding_wait(3, 6), secs(10.0 + 10.0 / 3.0));
+
+        let never = LimitInput { limit: 0, ..input };
+        assert_near(never.sliding_wait(0, 0), secs(20.0));
+    }
+
+    #[test]
+    fn token_bucket_refills_at_the_rate() {
+        // 2 tokens per 10s, 3 at most
+        let input = input(Algorithm::TokenBucket, 2, 3);
+        let t0 = Instant::now();
+        let mut counter = Counter::new(&input, t0);
+
+        let (allow, remaining, reset, _) = request(&mut counter, &input, t0, 0.0);
+        assert!(allow);
+        assert_eq!(remaining, 2);
+        assert_near(reset, secs(5.0));
+        assert!(request(&mut counter, &input, t0, 0.0).0);
+        assert!(request(&mut counter, &input, t0, 0.0).0);
+
+        let (allow, remaining, reset, retry) = request(&mut counter, &input, t0, 0.0);
+        assert!(!allow);
+        assert_eq!(remaining, 0);
+        assert_near(reset, secs(15.0));
+        assert_near(retry, secs(5.0));
+
+        // half a token back
+        let (allow, _, _, retry) = request(&mut counter, &input, t0, 2.5);
+        assert!(!allow);
+        assert_near(retry, secs(2.5));
+        assert!(request(&mut counter, &input, t0, 5.0).0);
+        assert!(!request(&mut counter, &input, t0, 5.0).0);
+
+        // full again, never above the burst
+        let (allow, remaining, _, _) = request(&mut counter, &input, t0, 60.0);
+        assert!(allow);
+        assert_eq!(remaining, 2);
+    }
+
+    #[actix_web::test]
+    async fn encoded_paths_are_limited() {
+        let limit = RateLimit::new(MemoryBackend::new()).rule(
+            policy(Algorithm::FixedWindow, 1, 0),
+            &[],
+            "^/mandelbrot$",
+        );
+        let app = init_service(
+            App::new()
+                .wrap(limit)
+                .route("/mandelbrot", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        for (path, allowed) in [("/mandelbrot", true), ("/mandelbro%74", false)] {
+            let req = TestRequest::get()
+                .uri(path)
+                .peer_addr("203.0.113.7:4000".parse().unwrap())
+                .to_request();
+            let res = call_service(&app, req).await;
+            assert_eq!(res.status().is_success(), allowed, "{}", path);
+        }
+    }
+
+    #[actix_web::test]
+    async fn unknown_api_keys_are_limited_by_address() {
+        let limit = RateLimit::new(MemoryBackend::new()).rule(
+            Policy {
+                key: KeyStrategy::ApiKey,
+                keys: HashSet::from([String::from("known")]),
+                ..policy(Algorithm::FixedWindow, 1, 0)
+            },
+            &[],
+            "^/developer$",
+        );
+        let app = init_service(
+            App::new()
+                .wrap(limit)
+                .route("/developer", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        for (key, allowed) in [
+            ("rotated-1", true),
+            ("rotated-2", false),
+            ("known", true),
+            ("known", false),
+        ] {
+            let req = TestRequest::get()
+                .uri("/developer")
+                .insert_header((X_API_KEY, key))
+                .peer_addr("203.0.113.7:4000".parse().unwrap())
+                .to_request();
+            let res = call_service(&app, req).await;
+            assert_eq!(res.status().is_success(), allowed, "{}", key);
+        }
+    }
+
+    #[actix_web::test]
+    async fn server_errors_are_rolled_back() {
+        let limit = RateLimit::new(MemoryBackend::new())
+            .rule(policy(Algorithm::FixedWindow, 1, 0), &[], "^/")
+            .rollback_server_errors(true);
+        let app = init_service(
+            App::new()
+                .wrap(limit)
+                .route("/error", web::get().to(HttpResponse::InternalServerError))
+                .route("/ok", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        for (path, status) in [("/error", 500), ("/error", 500), ("/ok", 200), ("/ok", 429)] {
+            let req = TestRequest::get()
+                .uri(path)
+                .peer_addr("203.0.113.7:4000".parse().unwrap())
+                .to_request();
+            let res = call_service(&app, req).await;
+            assert_eq!(res.status().as_u16(), status, "{}", path);
+        }
+    }
+}
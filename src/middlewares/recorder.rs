@@ -0,0 +1,463 @@
+use std::{
+    fs::{self, OpenOptions},
+    future::Future,
+    io::{self, LineWriter, Write},
+    marker::PhantomData,
+    path::Path,
+    pin::Pin,
+    rc::Rc,
+    sync::mpsc::{self, SyncSender, TrySendError},
+    task::{Context, Poll},
+    thread,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use actix_utils::future::{ready, Ready};
+use actix_web::{
+    body::{BodySize, MessageBody},
+    dev::{Payload, Service, ServiceRequest, ServiceResponse, Transform},
+    http::header,
+    Error, HttpMessage,
+};
+use bytes::Bytes;
+use futures_core::ready;
+use pin_project_lite::pin_project;
+use regex::RegexSet;
+use serde::{Deserialize, Serialize};
+
+use crate::core::routes::routed_path;
+use crate::middlewares::capture::{BodyTap, Redaction, TapPayload};
+
+/// Headers of the connection, the target or the framing of the body rather
+/// than of the request, left out of recordings: the replay sets its own.
+const CONNECTION_HEADERS: [header::HeaderName; 7] = [
+    header::CONNECTION,
+    header::HOST,
+    header::CONTENT_LENGTH,
+    header::TRANSFER_ENCODING,
+    header::UPGRADE,
+    header::TE,
+    header::TRAILER,
+];
+
+/// Recordings waiting for the writer thread, the next ones are dropped while
+/// it is this far behind.
+const QUEUE: usize = 1024;
+
+/// A request recorded by `Recorder`, one JSON object per line of the file,
+/// as read by the `replay` binary.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Recording {
+    /// Milliseconds since the epoch when the request was received.
+    pub ts: u64,
+    pub method: String,
+    /// Path and query string.
+    pub uri: String,
+    pub headers: Vec<(String, String)>,
+    /// Body as read by the handler, base64 encoded if it isn't UTF-8.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub body: String,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub base64: bool,
+    /// Whether the body was cut at `max_body` bytes.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub truncated: bool,
+    pub status: u16,
+    /// Milliseconds until the response body was sent, like `replay` measures
+    /// the requests it sends.
+    pub duration_ms: f64,
+}
+
+impl Recording {
+    pub fn body_bytes(&self) -> Result<Vec<u8>, base64::DecodeError> {
+        if self.base64 {
+            base64::decode(&self.body)
+        } else {
+            Ok(self.body.clone().into_bytes())
+        }
+    }
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
+}
+
+/// Records the requests received to a JSONL file, for the `replay` binary to
+/// send them again to another instance.
+///
+/// The file holds the requests as sent, but for the headers and fields of the
+/// `redaction`, and is created readable by its owner only. A JSON body with
+/// redacted fields is recorded compacted, its keys in their order but without
+/// the whitespace sent. WebSocket upgrades aren't recorded.
+///
+/// The file is written by a thread of its own, the workers only queue the
+/// recordings, dropping them when the file can't keep up.
+#[derive(Clone)]
+pub struct Recorder {
+    sink: Option<SyncSender<Recording>>,
+    paths: RegexSet,
+    exclude: RegexSet,
+    max_body: usize,
+    redaction: Redaction,
+}
+
+impl Recorder {
+    /// Appends the requests to the file at `path`.
+    pub fn open(path: &str) -> io::Result<Self> {
+        if let Some(dir) = Path::new(path).parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut options = OpenOptions::new();
+        options.create(true).append(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = LineWriter::new(options.open(path)?);
+
+        // stops once every worker dropped its recorder
+        let (sink, recordings) = mpsc::sync_channel::<Recording>(QUEUE);
+        thread::Builder::new()
+            .name(String::from("recorder"))
+            .spawn(move || {
+                for recording in recordings {
+                    let result = serde_json::to_vec(&recording)
+                        .map_err(io::Error::from)
+                        .and_then(|mut line| {
+                            line.push(b'\n');
+                            file.write_all(&line)
+                        });
+                    if let Err(err) = result {
+                        log::warn!(
+                            "Couldn't record {} {}: {}",
+                            recording.method,
+                            recording.uri,
+                            err
+                        );
+                    }
+                }
+            })?;
+
+        Ok(Recorder {
+            sink: Some(sink),
+            ..Recorder::disabled()
+        })
+    }
+
+    /// A recorder letting every request through unrecorded.
+    pub fn disabled() -> Self {
+        Recorder {
+            sink: None,
+            paths: RegexSet::empty(),
+            exclude: RegexSet::empty(),
+            max_body: 64 * 1024,
+            redaction: Redaction::default(),
+        }
+    }
+
+    /// Only records the paths matching one of the `pattern` regexes, every
+    /// path by default.
+    pub fn path<T: Into<String>>(mut self, pattern: T) -> Self {
+        let mut patterns = self.paths.patterns().to_vec();
+        patterns.push(pattern.into());
+        self.paths = RegexSet::new(patterns).unwrap();
+        self
+    }
+
+    pub fn exclude<T: Into<String>>(mut self, pattern: T) -> Self {
+        let mut patterns = self.exclude.patterns().to_vec();
+        patterns.push(pattern.into());
+        self.exclude = RegexSet::new(patterns).unwrap();
+        self
+    }
+
+    /// Bytes of each body kept, 64 KiB by default.
+    pub fn max_body(mut self, max_body: usize) -> Self {
+        self.max_body = max_body;
+        self
+    }
+
+    /// Replaces the values of these headers and fields with `[REDACTED]`,
+    /// nothing by default.
+    pub fn redaction(mut self, redaction: Redaction) -> Self {
+        self.redaction = redaction;
+        self
+    }
+
+    fn wants(&self, req: &ServiceRequest) -> bool {
+        let path = routed_path(req.request());
+        self.sink.is_some()
+            && (self.paths.is_empty() || self.paths.is_match(path))
+            && !self.exclude.is_match(path)
+            && !req.headers().contains_key(header::UPGRADE)
+    }
+
+    fn write(&self, recording: Recording) {
+        let sink = match &self.sink {
+            Some(sink) => sink,
+            None => return,
+        };
+        match sink.try_send(recording) {
+            Ok(()) => {}
+            Err(TrySendError::Full(recording)) => log::warn!(
+                "Couldn't record {} {}: the recorder is behind",
+                recording.method,
+                recording.uri
+            ),
+            Err(TrySendError::Disconnected(recording)) => log::warn!(
+                "Couldn't record {} {}: the recorder stopped",
+                recording.method,
+                recording.uri
+            ),
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for Recorder
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    B: MessageBody,
+{
+    type Response = ServiceResponse<RecordedBody<B>>;
+    type Error = Error;
+    type Transform = RecorderMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RecorderMiddleware {
+            service,
+            recorder: Rc::new(self.clone()),
+        }))
+    }
+}
+
+pub struct RecorderMiddleware<S> {
+    service: S,
+    recorder: Rc<Recorder>,
+}
+
+impl<S, B> Service<ServiceRequest> for RecorderMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    B: MessageBody,
+{
+    type Response = ServiceResponse<RecordedBody<B>>;
+    type Error = Error;
+    type Future = RecorderResponse<S, B>;
+
+    actix_service::forward_ready!(service);
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        if !self.recorder.wants(&req) {
+            return RecorderResponse {
+                fut: self.service.call(req),
+                pending: None,
+                _phantom: PhantomData,
+            };
+        }
+
+        let tap = Rc::new(BodyTap::new(self.recorder.max_body));
+        let payload = TapPayload::new(req.take_payload(), tap.clone());
+        req.set_payload(Payload::Stream {
+            payload: Box::pin(payload),
+        });
+
+        let headers = req
+            .headers()
+            .iter()
+            .filter(|(name, _)| !CONNECTION_HEADERS.contains(name))
+            .filter_map(|(name, value)| {
+                let value = self
+                    .recorder
+                    .redaction
+                    .header_value(name, value.to_str().ok()?);
+                Some((name.to_string(), value.to_string()))
+            })
+            .collect();
+        let pending = Pending {
+            recorder: self.recorder.clone(),
+            ts: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |elapsed| elapsed.as_millis() as u64),
+            start: Instant::now(),
+            method: req.method().to_string(),
+            uri: req
+                .uri()
+                .path_and_query()
+                .map_or_else(|| req.path().to_string(), |pq| pq.to_string()),
+            headers,
+            tap,
+        };
+
+        RecorderResponse {
+            fut: self.service.call(req),
+            pending: Some(pending),
+            _phantom: PhantomData,
+        }
+    }
+}
+
+/// What is known of a recorded request until its response is sent.
+struct Pending {
+    recorder: Rc<Recorder>,
+    ts: u64,
+    start: Instant,
+    method: String,
+    uri: String,
+    headers: Vec<(String, String)>,
+    tap: Rc<BodyTap>,
+}
+
+impl Pending {
+    fn finish(self, status: u16) {
+        let body = self.tap.captured();
+        let truncated = self.tap.truncated();
+        let (body, base64) = match String::from_utf8(body) {
+            Ok(body) => (self.recorder.redaction.body(body, truncated), false),
+            Err(err) => (base64::encode(err.as_bytes()), true),
+        };
+
+        self.recorder.write(Recording {
+            ts: self.ts,
+            method: self.method,
+            uri: self.uri,
+            headers: self.headers,
+            body,
+            base64,
+            truncated,
+            status,
+            duration_ms: self.start.elapsed().as_secs_f64() * 1000.0,
+        });
+    }
+}
+
+pin_project! {
+    pub struct RecorderResponse<S, B>
+    where
+        S: Service<ServiceRequest>,
+    {
+        #[pin]
+        fut: S::Future,
+        pending: Option<Pending>,
+        _phantom: PhantomData<B>,
+    }
+}
+
+impl<S, B> Future for RecorderResponse<S, B>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    B: MessageBody,
+{
+    type Output = Result<ServiceResponse<RecordedBody<B>>, Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let res = match ready!(this.fut.poll(cx)) {
+            Ok(res) => res,
+            Err(err) => {
+                if let Some(pending) = this.pending.take() {
+                    pending.finish(err.as_response_error().status_code().as_u16());
+                }
+                return Poll::Ready(Err(err));
+            }
+        };
+
+        let status = res.status().as_u16();
+        let pending = this.pending.take();
+        Poll::Ready(Ok(res.map_body(move |_, body| RecordedBody {
+            body,
+            pending,
+            status,
+        })))
+    }
+}
+
+pin_project! {
+    /// The body of a response, recording the request once it is sent.
+    pub struct RecordedBody<B> {
+        #[pin]
+        body: B,
+        pending: Option<Pending>,
+        status: u16,
+    }
+
+    impl<B> PinnedDrop for RecordedBody<B> {
+        fn drop(this: Pin<&mut Self>) {
+            let this = this.project();
+            if let Some(pending) = this.pending.take() {
+                pending.finish(*this.status);
+            }
+        }
+    }
+}
+
+impl<B: MessageBody> MessageBody for RecordedBody<B> {
+    type Error = B::Error;
+
+    #[inline]
+    fn size(&self) -> BodySize {
+        self.body.size()
+    }
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        self.project().body.poll_next(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use actix_web::{
+        test::{call_service, init_service, read_body, TestRequest},
+        web, App, HttpResponse,
+    };
+
+    use super::*;
+
+    #[actix_web::test]
+    async fn credentials_are_redacted() {
+        let path =
+            std::env::temp_dir().join(format!("rs-starter-{}-traffic.jsonl", std::process::id()));
+        let recorder = Recorder::open(path.to_str().unwrap()).unwrap().redaction(
+            Redaction::default()
+                .header(header::AUTHORIZATION)
+                .field("password"),
+        );
+        let app = init_service(App::new().wrap(recorder).route(
+            "/login",
+            web::post().to(|body: String| async { HttpResponse::Ok().body(body) }),
+        ))
+        .await;
+
+        let req = TestRequest::post()
+            .uri("/login")
+            .insert_header((header::AUTHORIZATION, "Bearer abc"))
+            .insert_header((header::ACCEPT, "*/*"))
+            .set_payload(r#"{"user":"a","password":"p"}"#)
+            .to_request();
+        read_body(call_service(&app, req).await).await;
+
+        // written by the thread of the recorder
+        let mut content = String::new();
+        for _ in 0..100 {
+            content = fs::read_to_string(&path).unwrap_or_default();
+            if !content.is_empty() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        fs::remove_file(&path).ok();
+
+        let recording: Recording = serde_json::from_str(content.trim()).unwrap();
+        assert!(recording
+            .headers
+            .contains(&(String::from("authorization"), String::from("[REDACTED]"))));
+        assert!(recording
+            .headers
+            .contains(&(String::from("accept"), String::from("*/*"))));
+        assert_eq!(recording.body, r#"{"user":"a","password":"[REDACTED]"}"#);
+    }
+}
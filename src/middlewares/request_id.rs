@@ -0,0 +1,255 @@
+use std::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    rc::Rc,
+    task::{Context, Poll},
+};
+
+use actix_utils::future::{ready, Ready};
+use actix_web::{
+    dev::{Payload, Service, ServiceRequest, ServiceResponse, Transform},
+    error,
+    http::header::{HeaderName, HeaderValue},
+    Error, FromRequest, HttpMessage, HttpRequest,
+};
+use pin_project_lite::pin_project;
+use uuid::Uuid;
+
+/// Key of the request id in the log4rs MDC, e.g. `{X(request_id)(-)}` in a pattern.
+pub const MDC_KEY: &str = "request_id";
+
+/// Longest incoming id that is kept, longer ones are replaced.
+const MAX_LEN: usize = 128;
+
+/// The id of the request being served.
+///
+/// Taken from the `X-Request-Id` header when the client (or a proxy) sent a
+/// usable one, a UUID v4 otherwise. Extract it in a handler to pass it on to
+/// other services.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestId(Rc<str>);
+
+impl RequestId {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl FromRequest for RequestId {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(
+            req.extensions()
+                .get::<RequestId>()
+                .cloned()
+                .ok_or_else(|| error::ErrorInternalServerError("request id middleware missing")),
+        )
+    }
+}
+
+/// Assigns a `RequestId` to every request.
+///
+/// The id is echoed in the response header and set in the log4rs MDC while
+/// the inner services run, so that every record logged for the request
+/// carries it. Wrap it outside of `access_filter::Logger` for `%L` to see it.
+#[derive(Debug, Clone)]
+pub struct AssignRequestId {
+    header: HeaderName,
+}
+
+impl Default for AssignRequestId {
+    fn default() -> Self {
+        Self {
+            header: HeaderName::from_static("x-request-id"),
+        }
+    }
+}
+
+impl AssignRequestId {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reads and writes the id in `header` instead of `X-Request-Id`.
+    pub fn header(mut self, header: HeaderName) -> Self {
+        self.header = header;
+        self
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for AssignRequestId
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = AssignRequestIdMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(AssignRequestIdMiddleware {
+            service,
+            header: Rc::new(self.header.clone()),
+        }))
+    }
+}
+
+pub struct AssignRequestIdMiddleware<S> {
+    service: S,
+    header: Rc<HeaderName>,
+}
+
+impl<S, B> Service<ServiceRequest> for AssignRequestIdMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = RequestIdResponse<S>;
+
+    actix_service::forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let id = req
+            .headers()
+            .get(self.header.as_ref())
+            .and_then(|value| value.to_str().ok())
+            .filter(|value| valid(value))
+            .map(Rc::from)
+            .unwrap_or_else(|| Rc::from(Uuid::new_v4().to_string()));
+        let id = RequestId(id);
+        req.extensions_mut().insert(id.clone());
+
+        let fut = with_mdc(&id, || self.service.call(req));
+        RequestIdResponse {
+            fut,
+            id,
+            header: self.header.clone(),
+        }
+    }
+}
+
+pin_project! {
+    pub struct RequestIdResponse<S>
+    where
+        S: Service<ServiceRequest>,
+    {
+        #[pin]
+        fut: S::Future,
+        id: RequestId,
+        header: Rc<HeaderName>,
+    }
+}
+
+impl<S, B> Future for RequestIdResponse<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+{
+    type Output = Result<ServiceResponse<B>, Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let id = this.id;
+        let poll = with_mdc(id, || this.fut.poll(cx));
+
+        poll.map(|res| {
+            res.map(|mut res| {
+                if let Ok(value) = HeaderValue::from_str(id.as_str()) {
+                    res.headers_mut()
+                        .insert(this.header.as_ref().clone(), value);
+                }
+                res
+            })
+        })
+    }
+}
+
+/// Runs `f` with the id in the MDC. Requests are interleaved on a worker
+/// thread, so the id is only set for the duration of a call or poll.
+fn with_mdc<T>(id: &RequestId, f: impl FnOnce() -> T) -> T {
+    log_mdc::insert(MDC_KEY, id.as_str());
+    let out = f();
+    log_mdc::remove(MDC_KEY);
+    out
+}
+
+/// Ids are echoed and logged, only short printable ones are accepted.
+fn valid(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_LEN && id.bytes().all(|b| b.is_ascii_graphic())
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        test::{call_service, init_service, read_body, TestRequest},
+        web, App,
+    };
+
+    use super::*;
+
+    /// Sends `id` in `X-Request-Id`, returns the response header and the id
+    /// the handler extracted.
+    async fn assigned(id: Option<&str>) -> (String, String) {
+        let app = init_service(App::new().wrap(AssignRequestId::new()).route(
+            "/",
+            web::get().to(|id: RequestId| async move { id.to_string() }),
+        ))
+        .await;
+
+        let mut req = TestRequest::get().uri("/");
+        if let Some(id) = id {
+            req = req.insert_header(("x-request-id", id));
+        }
+        let res = call_service(&app, req.to_request()).await;
+        let header = res.headers().get("x-request-id").unwrap();
+        let header = header.to_str().unwrap().to_string();
+        let body = read_body(res).await;
+        (header, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    fn assert_uuid_v4(id: &str) {
+        let uuid = Uuid::parse_str(id).unwrap();
+        assert_eq!(uuid.get_version_num(), 4, "{}", id);
+    }
+
+    #[actix_web::test]
+    async fn valid_ids_are_echoed() {
+        let (header, _) = assigned(Some("req-42.abc")).await;
+        assert_eq!(header, "req-42.abc");
+    }
+
+    #[actix_web::test]
+    async fn invalid_ids_are_replaced() {
+        for id in ["a".repeat(MAX_LEN + 1).as_str(), "two words", "tab\there"] {
+            let (header, _) = assigned(Some(id)).await;
+            assert_ne!(header, id);
+            assert_uuid_v4(&header);
+        }
+        let (header, _) = assigned(Some(&"a".repeat(MAX_LEN))).await;
+        assert_eq!(header, "a".repeat(MAX_LEN));
+    }
+
+    #[actix_web::test]
+    async fn missing_ids_are_generated() {
+        let (header, _) = assigned(None).await;
+        assert_uuid_v4(&header);
+    }
+
+    #[actix_web::test]
+    async fn the_extractor_sees_the_response_id() {
+        for id in [Some("req-42"), Some("two words"), None] {
+            let (header, extracted) = assigned(id).await;
+            assert_eq!(header, extracted);
+        }
+    }
+}
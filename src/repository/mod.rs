@@ -1 +1,2 @@
 pub mod mongodb_repo;
+pub mod rate_limit_repo;
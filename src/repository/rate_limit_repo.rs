@@ -0,0 +1,393 @@
+use std::{
+    convert::Infallible,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use actix_extensible_rate_limit::backend::Backend;
+use actix_web::rt::time::{timeout, Instant};
+use async_trait::async_trait;
+use mongodb::{
+    bson::{doc, DateTime, Document},
+    error::{Error, ErrorKind},
+    options::{FindOneAndUpdateOptions, IndexOptions, ReturnDocument, UpdateModifications},
+    Collection, Database, IndexModel,
+};
+
+use crate::middlewares::rate_limit::{Algorithm, Counted, LimitInput, LimitOutput, MemoryBackend};
+
+/// Whether the request is allowed, the quota left and how to take it back.
+type Counting = (bool, LimitOutput, Counted);
+
+/// Code of the duplicate key errors, raised when two upserts of a new window
+/// race.
+const DUPLICATE_KEY: i32 = 11000;
+
+/// Rate limit counters kept in a MongoDB collection, shared by every instance
+/// using the same database.
+///
+/// The windows are documents `{_id: "<key>@<start>", count, expires}` and the
+/// buckets `{_id: "<key>@bucket", tokens, updated, allowed, expires}`, updated
+/// by atomic upserts and removed by a TTL index once they don't count anymore.
+///
+/// When MongoDB fails or doesn't answer within `timeout`, the requests are
+/// counted by the `MemoryBackend` of the instance until `retry` has elapsed.
+/// Until MongoDB answered once, the requests timing out are counted in memory
+/// without waiting for `retry`: the connection is still being set up.
+///
+/// The buckets are updated by pipelines, which need MongoDB 4.2. `init` finds
+/// out whether the server runs them, the buckets are counted by the
+/// `MemoryBackend` otherwise.
+#[derive(Clone)]
+pub struct MongoBackend(Arc<Inner>);
+
+struct Inner {
+    db: Database,
+    collection: Collection<Document>,
+    fallback: MemoryBackend,
+    timeout: Duration,
+    retry: Duration,
+    /// Until when the fallback counts the requests, `None` while MongoDB is
+    /// available.
+    degraded: Mutex<Option<Instant>>,
+    /// Whether MongoDB answered once.
+    connected: AtomicBool,
+    /// Whether the server runs the pipeline updates of the buckets.
+    buckets: AtomicBool,
+}
+
+impl MongoBackend {
+    pub fn new(db: &Database, collection: &str, timeout: Duration, retry: Duration) -> Self {
+        MongoBackend(Arc::new(Inner {
+            db: db.clone(),
+            collection: db.collection(collection),
+            fallback: MemoryBackend::new(),
+            timeout,
+            retry,
+            degraded: Mutex::new(None),
+            connected: AtomicBool::new(false),
+            buckets: AtomicBool::new(true),
+        }))
+    }
+
+    /// Connects to MongoDB, checks that it can update the buckets and creates
+    /// the TTL index removing the windows once over.
+    pub async fn init(&self) -> Result<(), Error> {
+        let info = self.0.db.run_command(doc! {"buildInfo": 1}, None).await?;
+        self.0.connected.store(true, Ordering::Relaxed);
+        let version = info.get_str("version").unwrap_or_default();
+        if !supports_pipelines(version) {
+            log::warn!(
+                "MongoDB {} can't update the rate limit buckets, which needs 4.2: they are counted in memory",
+                version
+            );
+            self.0.buckets.store(false, Ordering::Relaxed);
+        }
+
+        let index = IndexModel::builder()
+            .keys(doc! {"expires": 1})
+            .options(
+                IndexOptions::builder()
+                    .name(String::from("expires_ttl"))
+                    .expire_after(Duration::ZERO)
+                    .build(),
+            )
+            .build();
+        self.0
+            .collection
+            .create_index(index, None)
+            .await
+            .map(|_| ())
+    }
+
+    /// Upserts the document `id` and returns it once updated.
+    async fn upsert(
+        &self,
+        id: &str,
+        update: impl Into<UpdateModifications> + Clone,
+    ) -> Result<Document, Error> {
+        let options = FindOneAndUpdateOptions::builder()
+            .upsert(true)
+            .return_document(ReturnDocument::After)
+            .build();
+        let mut retried = false;
+        loop {
+            let res = self
+                .0
+                .collection
+                .find_one_and_update(doc! {"_id": id}, update.clone(), options.clone())
+                .await;
+            match res {
+                Ok(doc) => return Ok(doc.unwrap_or_default()),
+                // the other upsert created the document, update it
+                Err(err) if !retried && is_duplicate_key(&err) => retried = true,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    async fn count(&self, input: &LimitInput, now: i64) -> Result<Counting, Error> {
+        match input.algorithm {
+            Algorithm::FixedWindow => self.fixed_window(input, now).await,
+            Algorithm::SlidingWindow => self.sliding_window(input, now).await,
+            Algorithm::TokenBucket => self.token_bucket(input, now).await,
+        }
+    }
+
+    /// Takes back the request counted in the window `id`.
+    async fn uncount(&self, id: &str) -> Result<(), Error> {
+        self.0
+            .collection
+            .update_one(doc! {"_id": id}, doc! {"$inc": {"count": -1_i64}}, None)
+            .await
+            .map(|_| ())
+    }
+
+    /// Counts the request in the current window, taking it back when denied
+    /// so that a client retrying doesn't push its count further.
+    async fn fixed_window(&self, input: &LimitInput, now: i64) -> Result<Counting, Error> {
+        let (start, end) = window_of(input, now);
+        let id = format!("{}@{}", input.key, start);
+        let doc = self
+            .upsert(
+                &id,
+                doc! {
+                    "$inc": {"count": 1_i64},
+                    "$setOnInsert": {"expires": DateTime::from_millis(end)},
+                },
+            )
+            .await?;
+        let mut count = count_of(&doc);
+
+        let allow = count <= input.limit;
+        if !allow {
+            self.uncount(&id).await?;
+            count -= 1;
+        }
+        let output = LimitOutput {
+            limit: input.limit,
+            remaining: input.limit.saturating_sub(count),
+            reset: instant_at(now, end),
+            retry: instant_at(now, if allow { now } else { end }),
+        };
+        Ok((allow, output, Counted::Mongo(id, input.algorithm)))
+    }
+
+    /// Counts the request in the current window and reads the previous one,
+    /// taking it back when denied like `fixed_window`.
+    async fn sliding_window(&self, input: &LimitInput, now: i64) -> Result<Counting, Error> {
+        let (start, end) = window_of(input, now);
+        let window = end - start;
+        let id = format!("{}@{}", input.key, start);
+        let (current, previous) = futures::join!(
+            self.upsert(
+                &id,
+                doc! {
+                    "$inc": {"count": 1_i64},
+                    // still weighs during the next window
+                    "$setOnInsert": {"expires": DateTime::from_millis(end + window)},
+                },
+            ),
+            self.0.collection.find_one(
+                doc! {"_id": format!("{}@{}", input.key, start - window)},
+                None
+            )
+        );
+        let mut count = count_of(&current?);
+        let previous = previous?.as_ref().map_or(0, count_of);
+
+        let weight = 1.0 - (now - start) as f64 / window as f64;
+        let used = previous as f64 * weight + count as f64;
+        let allow = used <= input.limit as f64;
+        let retry = if allow {
+            now
+        } else {
+            self.uncount(&id).await?;
+            count -= 1;
+            start + input.sliding_wait(previous, count).as_millis() as i64
+        };
+        let output = LimitOutput {
+            limit: input.limit,
+            remaining: (input.limit as f64 - used).max(0.0) as u64,
+            reset: instant_at(now, if count > 0 { end + window } else { end }),
+            retry: instant_at(now, retry),
+        };
+        Ok((allow, output, Counted::Mongo(id, input.algorithm)))
+    }
+
+    /// Refills the bucket and takes a token in a single pipeline update.
+    async fn token_bucket(&self, input: &LimitInput, now: i64) -> Result<Counting, Error> {
+        let id = format!("{}@bucket", input.key);
+        let rate = input.rate() / 1000.0;
+        let burst = input.burst as f64;
+        let full = (burst / rate).ceil() as i64;
+        let update = vec![
+            doc! {"$set": {
+                "tokens": {"$min": [burst, {"$add": [
+                    {"$ifNull": ["$tokens", burst]},
+                    {"$multiply": [
+                        {"$max": [0_i64, {"$subtract": [now, {"$ifNull": ["$updated", now]}]}]},
+                        rate,
+                    ]},
+                ]}]},
+                // a late clock doesn't take the tokens back
+                "updated": {"$max": ["$updated", now]},
+            }},
+            doc! {"$set": {"allowed": {"$gte": ["$tokens", 1.0]}}},
+            doc! {"$set": {
+                "tokens": {"$cond": ["$allowed", {"$subtract": ["$tokens", 1.0]}, "$tokens"]},
+                "expires": DateTime::from_millis(now + full),
+            }},
+        ];
+        let doc = self.upsert(&id, update).await?;
+        let tokens = doc.get_f64("tokens").unwrap_or(0.0);
+        let allow = doc.get_bool("allowed").unwrap_or(true);
+
+        let output = LimitOutput {
+            limit: input.burst,
+            remaining: tokens as u64,
+            reset: instant_at(now, now + ((burst - tokens).max(0.0) / rate) as i64),
+            retry: instant_at(
+                now,
+                if allow {
+                    now
+                } else {
+                    now + ((1.0 - tokens) / rate).ceil() as i64
+                },
+            ),
+        };
+        Ok((allow, output, Counted::Mongo(id, input.algorithm)))
+    }
+
+    /// Whether MongoDB is skipped for now, after a failure.
+    fn is_degraded(&self) -> bool {
+        let mut degraded = self.0.degraded.lock().unwrap();
+        match *degraded {
+            Some(until) if until > Instant::now() => true,
+            Some(_) => {
+                *degraded = None;
+                log::info!("Rate limit store retrying MongoDB");
+                false
+            }
+            None => false,
+        }
+    }
+
+    fn degrade(&self, reason: &dyn std::fmt::Display) {
+        let mut degraded = self.0.degraded.lock().unwrap();
+        if degraded.is_none() {
+            log::warn!(
+                "Rate limit store falling back to memory for {}s: {}",
+                self.0.retry.as_secs(),
+                reason
+            );
+        }
+        *degraded = Some(Instant::now() + self.0.retry);
+    }
+}
+
+#[async_trait(?Send)]
+impl Backend<LimitInput> for MongoBackend {
+    type Output = LimitOutput;
+    type RollbackToken = Counted;
+    type Error = Infallible;
+
+    async fn request(&self, input: LimitInput) -> Result<Counting, Self::Error> {
+        let buckets = self.0.buckets.load(Ordering::Relaxed);
+        if (buckets || input.algorithm != Algorithm::TokenBucket) && !self.is_degraded() {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as i64;
+            let counting = timeout(self.0.timeout, self.count(&input, now));
+            match counting.await {
+                Ok(Ok(counting)) => {
+                    self.0.connected.store(true, Ordering::Relaxed);
+                    return Ok(counting);
+                }
+                Ok(Err(err)) => self.degrade(&err),
+                // the driver keeps on connecting for the next requests
+                Err(_) if !self.0.connected.load(Ordering::Relaxed) => {}
+                Err(_) => self.degrade(&"timed out"),
+            }
+        }
+
+        let (allow, output, key) = self.0.fallback.request(input).await?;
+        Ok((allow, output, Counted::Memory(key)))
+    }
+
+    async fn rollback(&self, token: Self::RollbackToken) -> Result<(), Self::Error> {
+        match token {
+            Counted::Mongo(id, algorithm) => {
+                let update = match algorithm {
+                    Algorithm::TokenBucket => doc! {"$inc": {"tokens": 1.0}},
+                    _ => doc! {"$inc": {"count": -1_i64}},
+                };
+                let update = self
+                    .0
+                    .collection
+                    .update_one(doc! {"_id": &id}, update, None);
+                if let Ok(Err(err)) = timeout(self.0.timeout, update).await {
+                    log::warn!("Couldn't roll back the rate limit of {}: {}", id, err);
+                }
+                Ok(())
+            }
+            Counted::Memory(key) => self.0.fallback.rollback(key).await,
+        }
+    }
+}
+
+/// The start and end of the window of `now`, in milliseconds since the epoch.
+/// The windows are aligned on the epoch so that the instances agree on them.
+fn window_of(input: &LimitInput, now: i64) -> (i64, i64) {
+    let window = (input.window.as_millis() as i64).max(1);
+    let start = now - now % window;
+    (start, start + window)
+}
+
+fn count_of(doc: &Document) -> u64 {
+    doc.get_i64("count").unwrap_or(0).max(0) as u64
+}
+
+/// The instant of `at`, in milliseconds since the epoch like `now`.
+fn instant_at(now: i64, at: i64) -> Instant {
+    Instant::now() + Duration::from_millis((at - now).max(0) as u64)
+}
+
+/// Whether the server `version` runs the pipeline updates, added in 4.2.
+fn supports_pipelines(version: &str) -> bool {
+    let mut parts = version
+        .split('.')
+        .map(|part| part.parse::<u32>().unwrap_or(0));
+    let major = parts.next().unwrap_or(0);
+    let minor = parts.next().unwrap_or(0);
+    (major, minor) >= (4, 2)
+}
+
+fn is_duplicate_key(err: &Error) -> bool {
+    match err.kind.as_ref() {
+        ErrorKind::Command(err) => err.code == DUPLICATE_KEY,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pipelines_need_4_2() {
+        for (version, supported) in [
+            ("4.0.28", false),
+            ("4.2.0", true),
+            ("4.10.1", true),
+            ("6.0.3-rc0", true),
+            ("", false),
+        ] {
+            assert_eq!(supports_pipelines(version), supported, "{}", version);
+        }
+    }
+}
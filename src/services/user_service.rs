@@ -1,13 +1,16 @@
+use crate::middlewares::request_id::RequestId;
 use crate::{models::user_model::User, repository::mongodb_repo::MongoRepo};
 use actix_web::{
-    delete, get, post, put,
     web::{Data, Json, Path},
     HttpResponse,
 };
 use mongodb::bson::oid::ObjectId;
 
-#[post("/user")]
-pub async fn create_user(db: Data<MongoRepo>, new_user: Json<User>) -> HttpResponse {
+pub async fn create_user(
+    db: Data<MongoRepo>,
+    new_user: Json<User>,
+    request_id: RequestId,
+) -> HttpResponse {
     let data = User {
         id: None,
         name: new_user.name.to_owned(),
@@ -19,12 +22,15 @@ pub async fn create_user(db: Data<MongoRepo>, new_user: Json<User>) -> HttpRespo
 
     match user_detail {
         Ok(user) => HttpResponse::Ok().json(user),
-        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+        Err(err) => internal_error(&request_id, err),
     }
 }
 
-#[get("/user/{id}")]
-pub async fn get_user(db: Data<MongoRepo>, path: Path<String>) -> HttpResponse {
+pub async fn get_user(
+    db: Data<MongoRepo>,
+    path: Path<String>,
+    request_id: RequestId,
+) -> HttpResponse {
     let id = path.into_inner();
     if id.is_empty() {
         return HttpResponse::BadRequest().body("invalid ID");
@@ -33,15 +39,15 @@ pub async fn get_user(db: Data<MongoRepo>, path: Path<String>) -> HttpResponse {
 
     match user_detail {
         Ok(user) => HttpResponse::Ok().json(user),
-        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+        Err(err) => internal_error(&request_id, err),
     }
 }
 
-#[put("/user/{id}")]
 pub async fn update_user(
     db: Data<MongoRepo>,
     path: Path<String>,
     new_user: Json<User>,
+    request_id: RequestId,
 ) -> HttpResponse {
     let id = path.into_inner();
     if id.is_empty() {
@@ -63,18 +69,21 @@ pub async fn update_user(
 
                 return match updated_user_info {
                     Ok(user) => HttpResponse::Ok().json(user),
-                    Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+                    Err(err) => internal_error(&request_id, err),
                 };
             } else {
                 HttpResponse::NotFound().body("No user found with specified ID")
             }
         }
-        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+        Err(err) => internal_error(&request_id, err),
     }
 }
 
-#[delete("/user/{id}")]
-pub async fn delete_user(db: Data<MongoRepo>, path: Path<String>) -> HttpResponse {
+pub async fn delete_user(
+    db: Data<MongoRepo>,
+    path: Path<String>,
+    request_id: RequestId,
+) -> HttpResponse {
     let id = path.into_inner();
     if id.is_empty() {
         return HttpResponse::BadRequest().body("invalid ID");
@@ -89,16 +98,22 @@ pub async fn delete_user(db: Data<MongoRepo>, path: Path<String>) -> HttpRespons
                 HttpResponse::NotFound().json("User with specified ID not found!")
             }
         }
-        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+        Err(err) => internal_error(&request_id, err),
     }
 }
 
-#[get("/users")]
-pub async fn get_all_users(db: Data<MongoRepo>) -> HttpResponse {
+pub async fn get_all_users(db: Data<MongoRepo>, request_id: RequestId) -> HttpResponse {
     let users = db.get_all_users().await;
 
     match users {
         Ok(users) => HttpResponse::Ok().json(users),
-        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+        Err(err) => internal_error(&request_id, err),
     }
 }
+
+/// Logs the error and returns it with the request id, so that clients can
+/// report which request failed.
+fn internal_error(request_id: &RequestId, err: impl std::fmt::Display) -> HttpResponse {
+    log::error!("{}", err);
+    HttpResponse::InternalServerError().body(format!("{} (request id {})", err, request_id))
+}
@@ -1,8 +1,39 @@
+use std::net::{IpAddr, SocketAddr};
 use std::str::FromStr;
 
+use ipnet::IpNet;
+
 pub fn parse_number<T: FromStr>(s: &str) -> Option<T> {
     match T::from_str(s) {
         Ok(l) => Some(l),
         _ => None,
     }
 }
+
+/// A network in CIDR notation, a bare address being a network of its own.
+pub fn parse_network(s: &str) -> Option<IpNet> {
+    s.parse::<IpNet>()
+        .or_else(|_| s.parse::<IpAddr>().map(IpNet::from))
+        .ok()
+        .map(|net| net.trunc())
+}
+
+/// An address with or without its port, quoted and bracketed like in the
+/// `Forwarded` header or not, IPv4-mapped IPv6 addresses as IPv4.
+pub fn parse_ip(s: &str) -> Option<IpAddr> {
+    let s = s.trim().trim_matches('"');
+    let ip = s
+        .parse::<IpAddr>()
+        .or_else(|_| s.parse::<SocketAddr>().map(|addr| addr.ip()))
+        .or_else(|_| s.trim_start_matches('[').trim_end_matches(']').parse())
+        .ok()?;
+    Some(canonical_ip(ip))
+}
+
+/// IPv4-mapped IPv6 addresses as IPv4, so that they match IPv4 networks.
+pub fn canonical_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        ip => ip,
+    }
+}
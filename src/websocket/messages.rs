@@ -27,3 +27,8 @@ pub struct ClientActorMessage {
     pub msg: String,
     pub room_id: Uuid,
 }
+
+/// Asks a session to send a close frame and stop, the server is going away.
+#[derive(Message, Clone)]
+#[rtype(result = "()")]
+pub struct Shutdown;
@@ -1,4 +1,5 @@
 pub mod lobby;
 pub mod messages;
 pub mod server;
+pub mod sessions;
 pub mod ws;
@@ -6,11 +6,16 @@ use std::time::{Duration, Instant};
 use actix::prelude::*;
 use actix_web::web::Bytes;
 use actix_web_actors::ws;
+use uuid::Uuid;
+
+use crate::websocket::messages::Shutdown;
+use crate::websocket::sessions;
 
 const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
 const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
 
 pub struct MyWebSocket {
+    id: Uuid,
     hb: Instant,
 }
 
@@ -22,7 +27,10 @@ impl Default for MyWebSocket {
 
 impl MyWebSocket {
     pub fn new() -> Self {
-        Self { hb: Instant::now() }
+        Self {
+            id: Uuid::new_v4(),
+            hb: Instant::now(),
+        }
     }
 
     fn hb(&self, ctx: &mut <Self as Actor>::Context) {
@@ -42,6 +50,23 @@ impl Actor for MyWebSocket {
 
     fn started(&mut self, ctx: &mut Self::Context) {
         self.hb(ctx);
+        sessions::register(self.id, ctx.address().recipient());
+    }
+
+    fn stopped(&mut self, _: &mut Self::Context) {
+        sessions::unregister(&self.id);
+    }
+}
+
+impl Handler<Shutdown> for MyWebSocket {
+    type Result = ();
+
+    fn handle(&mut self, _: Shutdown, ctx: &mut Self::Context) {
+        ctx.close(Some(ws::CloseReason {
+            code: ws::CloseCode::Away,
+            description: Some(String::from("server shutting down")),
+        }));
+        ctx.stop();
     }
 }
 
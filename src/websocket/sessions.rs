@@ -0,0 +1,34 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use actix::prelude::Recipient;
+use lazy_static::lazy_static;
+use uuid::Uuid;
+
+use crate::websocket::messages::Shutdown;
+
+lazy_static! {
+    /// Every open WebSocket session of the process, across all workers.
+    static ref SESSIONS: Mutex<HashMap<Uuid, Recipient<Shutdown>>> = Mutex::new(HashMap::new());
+}
+
+pub fn register(id: Uuid, addr: Recipient<Shutdown>) {
+    SESSIONS.lock().unwrap().insert(id, addr);
+}
+
+pub fn unregister(id: &Uuid) {
+    SESSIONS.lock().unwrap().remove(id);
+}
+
+pub fn count() -> usize {
+    SESSIONS.lock().unwrap().len()
+}
+
+/// Asks every open session to close, returns how many were notified.
+pub fn close_all() -> usize {
+    let sessions = SESSIONS.lock().unwrap();
+    for addr in sessions.values() {
+        addr.do_send(Shutdown);
+    }
+    sessions.len()
+}
@@ -1,5 +1,6 @@
 use crate::websocket::lobby::Lobby; // as well as this
-use crate::websocket::messages::{ClientActorMessage, Connect, Disconnect, WsMessage}; //We'll be writing this later
+use crate::websocket::messages::{ClientActorMessage, Connect, Disconnect, Shutdown, WsMessage}; //We'll be writing this later
+use crate::websocket::sessions;
 use actix::{fut, ActorContext, ActorFutureExt, ContextFutureSpawner, WrapFuture};
 use actix::{Actor, Addr, Running, StreamHandler};
 use actix::{AsyncContext, Handler};
@@ -36,6 +37,7 @@ impl Actor for WsConn {
         self.hb(ctx);
 
         let addr = ctx.address();
+        sessions::register(self.id, addr.clone().recipient());
         self.lobby_addr
             .send(Connect {
                 addr: addr.recipient(),
@@ -60,6 +62,10 @@ impl Actor for WsConn {
         });
         Running::Stop
     }
+
+    fn stopped(&mut self, _: &mut Self::Context) {
+        sessions::unregister(&self.id);
+    }
 }
 
 impl WsConn {
@@ -117,3 +123,15 @@ impl Handler<WsMessage> for WsConn {
         ctx.text(msg.0);
     }
 }
+
+impl Handler<Shutdown> for WsConn {
+    type Result = ();
+
+    fn handle(&mut self, _: Shutdown, ctx: &mut Self::Context) {
+        ctx.close(Some(ws::CloseReason {
+            code: ws::CloseCode::Away,
+            description: Some(String::from("server shutting down")),
+        }));
+        ctx.stop();
+    }
+}
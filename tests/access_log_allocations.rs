@@ -0,0 +1,120 @@
+//! The access log of the combined format doesn't allocate: the buffers of a
+//! request are reused by the next ones and the record is formatted straight
+//! into the logger.
+//!
+//! The allocations are counted on the thread of the test only, the harness
+//! running on others.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::io::{self, Write};
+
+use actix_web::{
+    test::{call_service, init_service, read_body, TestRequest},
+    web, App, HttpResponse,
+};
+use log::{LevelFilter, Log, Metadata, Record};
+
+use rs_starter::middlewares::access_filter::Logger;
+
+const REQUESTS: usize = 1_000;
+
+const COMBINED: &str = r#"%a "%r" %s %b "%{Referer}i" "%{User-Agent}i" %T"#;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Counts the allocations of each thread.
+struct Counting;
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.with(|count| count.set(count.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.with(|count| count.set(count.get() + 1));
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+/// Formats every record like an appender would, without keeping it.
+struct Sink;
+
+impl Log for Sink {
+    fn enabled(&self, _: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        write!(io::sink(), "{}", record.args()).unwrap();
+    }
+
+    fn flush(&self) {}
+}
+
+static SINK: Sink = Sink;
+
+#[test]
+fn combined_format_adds_no_allocation() {
+    log::set_logger(&SINK).unwrap();
+    log::set_max_level(LevelFilter::Info);
+
+    actix_web::rt::System::new().block_on(async {
+        let baseline = allocations(None).await;
+        let combined = allocations(Some(Logger::new(COMBINED))).await;
+        assert_eq!(
+            combined, baseline,
+            "allocations of {} requests with the combined access log",
+            REQUESTS
+        );
+    });
+}
+
+/// Allocations of `REQUESTS` requests served, once the buffers are warmed up.
+async fn allocations(logger: Option<Logger>) -> usize {
+    let app = App::new().route(
+        "/user/{id}",
+        web::get().to(|| async { HttpResponse::Ok().body("hello") }),
+    );
+
+    macro_rules! measure {
+        ($app:expr) => {{
+            let app = init_service($app).await;
+            let serve = || async {
+                let res = call_service(&app, request().to_request()).await;
+                read_body(res).await;
+            };
+            for _ in 0..REQUESTS / 10 {
+                serve().await;
+            }
+
+            let start = ALLOCATIONS.with(Cell::get);
+            for _ in 0..REQUESTS {
+                serve().await;
+            }
+            ALLOCATIONS.with(Cell::get) - start
+        }};
+    }
+
+    match logger {
+        Some(logger) => measure!(app.wrap(logger)),
+        None => measure!(app),
+    }
+}
+
+fn request() -> TestRequest {
+    TestRequest::get()
+        .uri("/user/42?verbose=1")
+        .insert_header(("user-agent", "test/1.0"))
+        .insert_header(("referer", "http://localhost/"))
+}